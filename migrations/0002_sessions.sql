@@ -0,0 +1,33 @@
+CREATE TABLE sessions (
+    id UUID PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL UNIQUE,
+    user_agent TEXT,
+    ip TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+
+-- Every refresh-token family now belongs to a session. Each family that still has a
+-- live token becomes a session of its own, keyed by the family id, so signed-in users
+-- stay signed in. Spent families have nothing left to refresh and are dropped.
+-- Access tokens issued before this migration carry no session id and are rejected;
+-- clients get a new one from their refresh token.
+INSERT INTO sessions (id, user_id, token_hash, created_at, last_seen_at, expires_at)
+SELECT DISTINCT ON (family_id) family_id, user_id, token_hash, created_at, created_at, expires_at
+FROM refresh_tokens
+WHERE used_at IS NULL AND revoked_at IS NULL AND expires_at > now()
+ORDER BY family_id, created_at DESC;
+
+DELETE FROM refresh_tokens WHERE family_id NOT IN (SELECT id FROM sessions);
+ALTER TABLE refresh_tokens RENAME COLUMN family_id TO session_id;
+ALTER TABLE refresh_tokens
+    ADD CONSTRAINT refresh_tokens_session_id_fkey
+    FOREIGN KEY (session_id) REFERENCES sessions (id) ON DELETE CASCADE;
+ALTER INDEX refresh_tokens_family_id_idx RENAME TO refresh_tokens_session_id_idx;
+
+ALTER TABLE users DROP COLUMN token;
@@ -0,0 +1,5 @@
+CREATE TYPE user_role AS ENUM ('admin', 'member', 'read_only');
+
+ALTER TABLE users
+    ADD COLUMN role user_role NOT NULL DEFAULT 'member',
+    ADD COLUMN disabled_at TIMESTAMPTZ;
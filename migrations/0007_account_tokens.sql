@@ -0,0 +1,15 @@
+ALTER TABLE users ADD COLUMN email_verified_at TIMESTAMPTZ;
+
+CREATE TYPE account_token_purpose AS ENUM ('password_reset', 'email_verification');
+
+-- Single-use tokens sent by email. The token itself is a signed JWT whose `jti` is the id here.
+CREATE TABLE account_tokens (
+    id UUID PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    purpose account_token_purpose NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX account_tokens_user_id_idx ON account_tokens (user_id);
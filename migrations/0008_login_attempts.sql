@@ -0,0 +1,8 @@
+-- Failed sign-in counters, keyed by account (`account:<username>`) or source (`ip:<address>`).
+-- Keys for unknown usernames are tracked too, so lockout doesn't reveal which accounts exist.
+CREATE TABLE login_attempts (
+    key TEXT PRIMARY KEY,
+    failures INTEGER NOT NULL DEFAULT 0,
+    window_started_at TIMESTAMPTZ NOT NULL,
+    locked_until TIMESTAMPTZ
+);
@@ -0,0 +1,28 @@
+-- Opaque handle authenticators store with a passkey; identifies the user in usernameless sign-in.
+ALTER TABLE users ADD COLUMN webauthn_user_id UUID NOT NULL UNIQUE DEFAULT gen_random_uuid();
+
+CREATE TABLE passkeys (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    credential_id BYTEA NOT NULL UNIQUE,
+    -- The serialized credential, including its COSE public key.
+    public_key JSONB NOT NULL,
+    sign_count BIGINT NOT NULL DEFAULT 0,
+    transports TEXT[] NOT NULL DEFAULT '{}',
+    name TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMPTZ
+);
+
+CREATE INDEX passkeys_user_id_idx ON passkeys (user_id);
+
+CREATE TYPE webauthn_ceremony AS ENUM ('registration', 'authentication');
+
+-- In-flight ceremonies, consumed when the client answers the challenge.
+CREATE TABLE webauthn_challenges (
+    id UUID PRIMARY KEY,
+    ceremony webauthn_ceremony NOT NULL,
+    user_id INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    state JSONB NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
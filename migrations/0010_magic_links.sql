@@ -0,0 +1,5 @@
+ALTER TYPE account_token_purpose ADD VALUE 'magic_link';
+
+-- Hash of a nonce kept in the requesting browser's cookie; set for tokens that must be
+-- redeemed from the same browser.
+ALTER TABLE account_tokens ADD COLUMN nonce_hash TEXT;
@@ -0,0 +1,8 @@
+ALTER TABLE users
+    ADD COLUMN display_name TEXT,
+    -- IANA time zone name, e.g. 'Europe/Berlin'.
+    ADD COLUMN timezone TEXT NOT NULL DEFAULT 'UTC',
+    -- BCP 47 language tag, e.g. 'en-GB'.
+    ADD COLUMN locale TEXT NOT NULL DEFAULT 'en',
+    -- Set while the user has an avatar; doubles as its version in download URLs.
+    ADD COLUMN avatar_updated_at TIMESTAMPTZ;
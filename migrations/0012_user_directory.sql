@@ -0,0 +1,3 @@
+-- Prefix search in the user directory matches on lower(...) LIKE 'term%'.
+CREATE INDEX users_username_prefix_idx ON users (lower(username) text_pattern_ops);
+CREATE INDEX users_display_name_prefix_idx ON users (lower(display_name) text_pattern_ops);
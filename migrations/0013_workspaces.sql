@@ -0,0 +1,64 @@
+CREATE TYPE workspace_role AS ENUM ('owner', 'admin', 'member', 'guest');
+
+CREATE TABLE workspaces (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE workspace_memberships (
+    workspace_id INTEGER NOT NULL REFERENCES workspaces (id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    role workspace_role NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (workspace_id, user_id)
+);
+
+CREATE INDEX workspace_memberships_user_id_idx ON workspace_memberships (user_id);
+
+-- Every existing user gets a personal workspace, and their tasks move into it.
+ALTER TABLE workspaces ADD COLUMN seed_user_id INTEGER;
+
+INSERT INTO workspaces (name, seed_user_id)
+SELECT 'Personal', id FROM users;
+
+INSERT INTO workspace_memberships (workspace_id, user_id, role)
+SELECT id, seed_user_id, 'owner' FROM workspaces;
+
+ALTER TABLE tasks ADD COLUMN workspace_id INTEGER REFERENCES workspaces (id) ON DELETE CASCADE;
+
+UPDATE tasks
+SET workspace_id = workspaces.id
+FROM workspaces
+WHERE workspaces.seed_user_id = tasks.user_id;
+
+-- Tasks that never had an owner go to a shared workspace owned by the admins. Without an
+-- admin to own it the migration stops rather than lose them.
+DO $$
+DECLARE
+    unassigned_id INTEGER;
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM tasks WHERE workspace_id IS NULL) THEN
+        RETURN;
+    END IF;
+    IF NOT EXISTS (SELECT 1 FROM users WHERE role = 'admin' AND deleted_at IS NULL) THEN
+        RAISE EXCEPTION '% tasks have no owner and there is no admin to own them',
+            (SELECT count(*) FROM tasks WHERE workspace_id IS NULL)
+            USING HINT = 'Make a user an admin, or set tasks.user_id, before migrating.';
+    END IF;
+    INSERT INTO workspaces (name) VALUES ('Unassigned tasks') RETURNING id INTO unassigned_id;
+    INSERT INTO workspace_memberships (workspace_id, user_id, role)
+    SELECT unassigned_id, id, 'owner' FROM users WHERE role = 'admin' AND deleted_at IS NULL;
+    UPDATE tasks SET workspace_id = unassigned_id WHERE workspace_id IS NULL;
+END
+$$;
+
+ALTER TABLE workspaces DROP COLUMN seed_user_id;
+ALTER TABLE tasks ALTER COLUMN workspace_id SET NOT NULL;
+
+CREATE INDEX tasks_workspace_id_idx ON tasks (workspace_id);
+
+-- `user_id` now records who created a task; shared tasks outlive their creator's account.
+ALTER TABLE tasks
+    DROP CONSTRAINT tasks_user_id_fkey,
+    ADD CONSTRAINT tasks_user_id_fkey FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE SET NULL;
@@ -0,0 +1,20 @@
+-- Invitations to join a workspace. The link sent out is a signed JWT whose `jti` is the id here.
+CREATE TABLE workspace_invites (
+    id UUID PRIMARY KEY,
+    workspace_id INTEGER NOT NULL REFERENCES workspaces (id) ON DELETE CASCADE,
+    email TEXT NOT NULL,
+    role workspace_role NOT NULL,
+    invited_by INTEGER REFERENCES users (id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    sent_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    accepted_at TIMESTAMPTZ,
+    accepted_by INTEGER REFERENCES users (id) ON DELETE SET NULL,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX workspace_invites_workspace_id_idx ON workspace_invites (workspace_id);
+
+-- At most one open invite per address and workspace.
+CREATE UNIQUE INDEX workspace_invites_open_idx ON workspace_invites (workspace_id, lower(email))
+    WHERE accepted_at IS NULL AND revoked_at IS NULL;
@@ -0,0 +1,13 @@
+-- Full-text search over tasks. Titles weigh more than descriptions. Angle brackets are blanked
+-- out first, as the parser would otherwise take them for markup and skip the text after them.
+-- The column is generated, so it's left out of the entity and only read in search queries.
+ALTER TABLE tasks ADD COLUMN search tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', translate(title, '<>', '  ')), 'A') ||
+    setweight(to_tsvector('english', translate(coalesce(description, ''), '<>', '  ')), 'B')
+) STORED;
+
+CREATE INDEX tasks_search_idx ON tasks USING GIN (search);
+
+-- Trigram matching on titles catches misspelled search terms.
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+CREATE INDEX tasks_title_trgm_idx ON tasks USING GIN (title gin_trgm_ops);
@@ -0,0 +1,56 @@
+-- Each workspace orders its own priority levels; a higher level is more urgent. Tasks store
+-- the level, so sorting by priority follows the workspace's order.
+CREATE TABLE task_priorities (
+    workspace_id INTEGER NOT NULL REFERENCES workspaces (id) ON DELETE CASCADE,
+    level SMALLINT NOT NULL,
+    key TEXT NOT NULL,
+    label TEXT NOT NULL,
+    color TEXT NOT NULL CHECK (color ~ '^#[0-9a-f]{6}$'),
+    PRIMARY KEY (workspace_id, level),
+    UNIQUE (workspace_id, key)
+);
+
+INSERT INTO task_priorities (workspace_id, level, key, label, color)
+SELECT workspaces.id, defaults.level, defaults.key, defaults.label, defaults.color
+FROM workspaces
+CROSS JOIN (VALUES
+    (1, 'low', 'Low', '#6b7280'),
+    (2, 'medium', 'Medium', '#2563eb'),
+    (3, 'high', 'High', '#f59e0b'),
+    (4, 'urgent', 'Urgent', '#dc2626')
+) AS defaults (level, key, label, color);
+
+-- Free-text priorities are mapped onto the default levels. Anything unrecognised keeps its
+-- original text in `legacy_priority` so it can be reviewed and assigned by hand.
+ALTER TABLE tasks RENAME COLUMN priority TO legacy_priority;
+ALTER TABLE tasks ADD COLUMN priority SMALLINT;
+
+UPDATE tasks
+SET priority = CASE
+    WHEN lower(trim(legacy_priority)) IN ('low', 'lo', 'l', 'p3', 'p4', 'minor', 'trivial') THEN 1
+    WHEN lower(trim(legacy_priority)) IN ('medium', 'med', 'mid', 'm', 'p2', 'normal') THEN 2
+    WHEN lower(trim(legacy_priority)) IN ('high', 'hi', 'h', 'p1', 'major', 'important') THEN 3
+    WHEN lower(trim(legacy_priority)) IN ('urgent', 'urg', 'u', 'p0', 'critical', 'crit', 'asap', 'blocker') THEN 4
+END;
+
+UPDATE tasks SET legacy_priority = NULL WHERE priority IS NOT NULL OR trim(legacy_priority) = '';
+
+DO $$
+DECLARE
+    unmapped BIGINT;
+BEGIN
+    SELECT count(*) INTO unmapped FROM tasks WHERE legacy_priority IS NOT NULL;
+    IF unmapped > 0 THEN
+        RAISE NOTICE '% tasks have a priority that matches no level; see tasks.legacy_priority',
+            unmapped;
+    END IF;
+END
+$$;
+
+-- Renumbering a workspace's levels carries its tasks along; deleting a level clears it.
+-- Clearing only `priority` on delete needs PostgreSQL 15 or later.
+ALTER TABLE tasks ADD CONSTRAINT tasks_priority_fkey
+    FOREIGN KEY (workspace_id, priority) REFERENCES task_priorities (workspace_id, level)
+    ON UPDATE CASCADE ON DELETE SET NULL (priority);
+
+CREATE INDEX tasks_workspace_id_priority_idx ON tasks (workspace_id, priority);
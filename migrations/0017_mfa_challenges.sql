@@ -0,0 +1,3 @@
+-- Second-factor challenges are recorded like emailed tokens, so each one finishes a single
+-- sign-in.
+ALTER TYPE account_token_purpose ADD VALUE 'mfa_challenge';
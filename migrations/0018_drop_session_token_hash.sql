@@ -0,0 +1,3 @@
+-- A session's refresh tokens live in `refresh_tokens`; the copy of the latest one's hash
+-- kept here was written on every refresh but never read.
+ALTER TABLE sessions DROP COLUMN token_hash;
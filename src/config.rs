@@ -0,0 +1,280 @@
+use std::{
+    env,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum_extra::extract::cookie::SameSite;
+use chrono::Duration;
+
+use crate::utils::{
+    avatar::AvatarConfig,
+    jwt::JwtConfig,
+    keyring::Keyring,
+    lockout::{AttemptStoreKind, LockoutConfig},
+    mailer::{MailTransport, MailerConfig},
+    oidc::OidcProviderConfig,
+    passkey::PasskeyConfig,
+    password::PasswordHasherConfig,
+    password_policy::{BreachedPasswords, PasswordPolicy},
+    storage::StorageBackend,
+    totp::TotpConfig,
+};
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub database_url: String,
+    pub jwt: JwtConfig,
+    pub oidc: Vec<OidcProviderConfig>,
+    pub totp: TotpConfig,
+    pub mailer: MailerConfig,
+    pub account: AccountConfig,
+    pub lockout: LockoutConfig,
+    pub password_policy: PasswordPolicy,
+    pub password_hasher: PasswordHasherConfig,
+    pub auth: AuthConfig,
+    pub passkey: PasskeyConfig,
+    pub storage: StorageBackend,
+    pub avatar: AvatarConfig,
+}
+
+/// Which credentials the guard accepts, and how browser session cookies are set.
+#[derive(Clone, Debug)]
+pub struct AuthConfig {
+    /// `Authorization: Bearer` with tokens returned in response bodies.
+    pub bearer: bool,
+    /// HttpOnly session cookies, with CSRF protection on state-changing requests.
+    pub cookie: bool,
+    pub cookie_secure: bool,
+    pub cookie_same_site: SameSite,
+    /// Reverse proxies whose `Forwarded` and `X-Forwarded-For` headers name the client.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// Settings for the email-driven account flows.
+#[derive(Clone, Debug)]
+pub struct AccountConfig {
+    /// Base URL of the frontend; links in emails point below it.
+    pub app_url: String,
+    pub password_reset_ttl: Duration,
+    pub email_verification_ttl: Duration,
+    pub magic_link_ttl: Duration,
+    pub invite_ttl: Duration,
+    /// How long a deleted account is kept before it's purged.
+    pub deletion_grace_period: Duration,
+    pub purge_interval: Duration,
+    /// How many tasks a user may create before verifying their email address.
+    pub unverified_task_limit: u64,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Config {
+            database_url: env::var("DATABASE_URL").expect("DB connection string not set"),
+            jwt: JwtConfig {
+                keyring: Arc::new(load_keyring()),
+                issuer: env_or("JWT_ISSUER", "axum_db"),
+                audience: env_or("JWT_AUDIENCE", "axum_db"),
+                access_token_ttl: Duration::seconds(env_parse_or("JWT_ACCESS_TTL_SECS", 900)),
+                refresh_token_ttl: Duration::seconds(env_parse_or(
+                    "JWT_REFRESH_TTL_SECS",
+                    60 * 60 * 24 * 30,
+                )),
+            },
+            oidc: load_oidc_providers(),
+            totp: TotpConfig {
+                issuer: env_or("TOTP_ISSUER", "axum_db"),
+                challenge_ttl: Duration::seconds(env_parse_or("TOTP_CHALLENGE_TTL_SECS", 300)),
+            },
+            mailer: MailerConfig {
+                from: env_or("MAIL_FROM", "axum_db <no-reply@localhost>"),
+                transport: match env::var("SMTP_URL") {
+                    Ok(url) => MailTransport::Smtp(url),
+                    Err(_) => MailTransport::File(PathBuf::from(env_or("MAIL_DIR", "mail"))),
+                },
+            },
+            account: AccountConfig {
+                app_url: env_or("APP_URL", "http://localhost:3000"),
+                password_reset_ttl: Duration::seconds(env_parse_or(
+                    "PASSWORD_RESET_TTL_SECS",
+                    60 * 60,
+                )),
+                email_verification_ttl: Duration::seconds(env_parse_or(
+                    "EMAIL_VERIFICATION_TTL_SECS",
+                    60 * 60 * 48,
+                )),
+                magic_link_ttl: Duration::seconds(env_parse_or("MAGIC_LINK_TTL_SECS", 15 * 60)),
+                invite_ttl: Duration::seconds(env_parse_or("INVITE_TTL_SECS", 60 * 60 * 24 * 7)),
+                deletion_grace_period: Duration::days(env_parse_or(
+                    "ACCOUNT_DELETION_GRACE_DAYS",
+                    30,
+                )),
+                purge_interval: Duration::seconds(env_parse_or(
+                    "ACCOUNT_PURGE_INTERVAL_SECS",
+                    60 * 60,
+                )),
+                unverified_task_limit: env_parse_or("UNVERIFIED_TASK_LIMIT", 10),
+            },
+            lockout: LockoutConfig {
+                store: match env_or("LOGIN_ATTEMPT_STORE", "postgres").as_str() {
+                    "memory" => AttemptStoreKind::Memory,
+                    "postgres" => AttemptStoreKind::Postgres,
+                    other => panic!("Unknown LOGIN_ATTEMPT_STORE {other}"),
+                },
+                max_failures: env_parse_or("LOGIN_MAX_FAILURES", 5),
+                max_failures_per_ip: env_parse_or("LOGIN_MAX_FAILURES_PER_IP", 50),
+                window: Duration::seconds(env_parse_or("LOGIN_FAILURE_WINDOW_SECS", 15 * 60)),
+                lockout: Duration::seconds(env_parse_or("LOGIN_LOCKOUT_SECS", 15 * 60)),
+                base_delay: Duration::milliseconds(env_parse_or("LOGIN_BASE_DELAY_MS", 250)),
+            },
+            password_policy: PasswordPolicy {
+                min_length: env_parse_or("PASSWORD_MIN_LENGTH", 8),
+                max_length: env_parse_or("PASSWORD_MAX_LENGTH", 128),
+                require_lowercase: env_parse_or("PASSWORD_REQUIRE_LOWERCASE", false),
+                require_uppercase: env_parse_or("PASSWORD_REQUIRE_UPPERCASE", true),
+                require_digit: env_parse_or("PASSWORD_REQUIRE_DIGIT", true),
+                require_special: env_parse_or("PASSWORD_REQUIRE_SPECIAL", true),
+                min_entropy_bits: env_parse_or("PASSWORD_MIN_ENTROPY_BITS", 36.0),
+                reject_username: env_parse_or("PASSWORD_REJECT_USERNAME", true),
+                breached: load_breached_passwords(),
+            },
+            password_hasher: PasswordHasherConfig {
+                memory_kib: env_parse_or("ARGON2_MEMORY_KIB", 19 * 1024),
+                iterations: env_parse_or("ARGON2_ITERATIONS", 2),
+                parallelism: env_parse_or("ARGON2_PARALLELISM", 1),
+                workers: env_parse_or("PASSWORD_HASH_WORKERS", 4),
+                legacy_bcrypt_cost: load_legacy_bcrypt_cost(),
+            },
+            auth: load_auth_config(),
+            passkey: PasskeyConfig {
+                rp_id: env_or("WEBAUTHN_RP_ID", "localhost"),
+                rp_name: env_or("WEBAUTHN_RP_NAME", "axum_db"),
+                rp_origin: env_or(
+                    "WEBAUTHN_RP_ORIGIN",
+                    &env_or("APP_URL", "http://localhost:3000"),
+                ),
+                challenge_ttl: Duration::seconds(env_parse_or("WEBAUTHN_CHALLENGE_TTL_SECS", 300)),
+            },
+            storage: match env_or("STORAGE_BACKEND", "filesystem").as_str() {
+                "filesystem" => {
+                    StorageBackend::Filesystem(PathBuf::from(env_or("STORAGE_DIR", "storage")))
+                }
+                "memory" => StorageBackend::Memory,
+                other => panic!("Unknown STORAGE_BACKEND {other}"),
+            },
+            avatar: AvatarConfig {
+                max_upload_bytes: env_parse_or("AVATAR_MAX_UPLOAD_BYTES", 5 * 1024 * 1024),
+                max_dimension: env_parse_or("AVATAR_MAX_DIMENSION", 4096),
+            },
+        }
+    }
+}
+
+/// Asymmetric keys are used when `JWT_KEYS_DIR` is set, otherwise the HS256 `JWT_SECRET`.
+fn load_keyring() -> Keyring {
+    match env::var("JWT_KEYS_DIR") {
+        Ok(dir) => {
+            let active_kid = env::var("JWT_ACTIVE_KID").expect("JWT active key id not set");
+            Keyring::load_dir(Path::new(&dir), &active_kid)
+                .unwrap_or_else(|error| panic!("Could not load JWT keys: {error}"))
+        }
+        Err(_) => {
+            let secret = env::var("JWT_SECRET").expect("JWT secret not set");
+            Keyring::from_secret(&env_or("JWT_SECRET_KID", "default"), secret.as_bytes())
+        }
+    }
+}
+
+/// `AUTH_MODES` is a comma-separated subset of `bearer` and `cookie`; `TRUSTED_PROXIES` is a
+/// comma-separated list of proxy addresses.
+fn load_auth_config() -> AuthConfig {
+    let modes = env_or("AUTH_MODES", "bearer");
+    let modes: Vec<&str> = modes.split(',').map(str::trim).collect();
+    if let Some(unknown) = modes
+        .iter()
+        .find(|mode| !["bearer", "cookie"].contains(mode))
+    {
+        panic!("Unknown AUTH_MODES entry {unknown}");
+    }
+    AuthConfig {
+        bearer: modes.contains(&"bearer"),
+        cookie: modes.contains(&"cookie"),
+        cookie_secure: env_parse_or("COOKIE_SECURE", true),
+        cookie_same_site: match env_or("COOKIE_SAME_SITE", "lax").to_lowercase().as_str() {
+            "strict" => SameSite::Strict,
+            "lax" => SameSite::Lax,
+            other => panic!("Unsupported COOKIE_SAME_SITE {other}"),
+        },
+        trusted_proxies: env_or("TRUSTED_PROXIES", "")
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| {
+                proxy
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Invalid TRUSTED_PROXIES address {proxy}"))
+            })
+            .collect(),
+    }
+}
+
+/// `PASSWORD_BREACHED_DIR` optionally points at a directory of breached password hashes,
+/// split into one file per hash prefix as served by the Pwned Passwords range API.
+fn load_breached_passwords() -> Option<Arc<BreachedPasswords>> {
+    let dir = env::var("PASSWORD_BREACHED_DIR").ok()?;
+    let breached = BreachedPasswords::open(Path::new(&dir))
+        .unwrap_or_else(|error| panic!("Could not open breached password list: {error}"));
+    Some(Arc::new(breached))
+}
+
+/// `OIDC_PROVIDERS` is a comma-separated list of names; each name `NAME` is configured
+/// through `OIDC_NAME_ISSUER`, `OIDC_NAME_CLIENT_ID`, `OIDC_NAME_REDIRECT_URI` and the
+/// optional `OIDC_NAME_CLIENT_SECRET` and `OIDC_NAME_SCOPES`.
+fn load_oidc_providers() -> Vec<OidcProviderConfig> {
+    let Ok(names) = env::var("OIDC_PROVIDERS") else {
+        return Vec::new();
+    };
+    names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let prefix = format!("OIDC_{}", name.to_uppercase());
+            let required = |key: &str| {
+                env::var(format!("{prefix}_{key}"))
+                    .unwrap_or_else(|_| panic!("{prefix}_{key} not set"))
+            };
+            OidcProviderConfig {
+                name: name.to_owned(),
+                issuer: required("ISSUER"),
+                client_id: required("CLIENT_ID"),
+                client_secret: env::var(format!("{prefix}_CLIENT_SECRET")).ok(),
+                redirect_uri: required("REDIRECT_URI"),
+                scopes: env_or(&format!("{prefix}_SCOPES"), "openid email"),
+            }
+        })
+        .collect()
+}
+
+/// `BCRYPT_LEGACY_COST` is the cost the old bcrypt hashes were made with, 12 unless changed.
+/// Set it to `off` once every account has signed in since the move to Argon2id.
+fn load_legacy_bcrypt_cost() -> Option<u32> {
+    match env::var("BCRYPT_LEGACY_COST").as_deref() {
+        Ok("off") => None,
+        _ => Some(env_parse_or("BCRYPT_LEGACY_COST", bcrypt::DEFAULT_COST)),
+    }
+}
+
+fn env_or(key: &str, default: &str) -> String {
+    env::var(key).unwrap_or_else(|_| default.to_owned())
+}
+
+fn env_parse_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{key} is not a valid value")),
+        Err(_) => default,
+    }
+}
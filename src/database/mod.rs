@@ -2,5 +2,20 @@
 
 pub mod prelude;
 
+pub mod account_tokens;
+pub mod login_attempts;
+pub mod oidc_auth_requests;
+pub mod passkeys;
+pub mod personal_access_tokens;
+pub mod recovery_codes;
+pub mod refresh_tokens;
+pub mod sea_orm_active_enums;
+pub mod sessions;
+pub mod task_priorities;
 pub mod tasks;
+pub mod user_identities;
 pub mod users;
+pub mod webauthn_challenges;
+pub mod workspace_invites;
+pub mod workspace_memberships;
+pub mod workspaces;
@@ -1,4 +1,18 @@
 //! `SeaORM` Entity, @generated by sea-orm-codegen 1.0.0
 
+pub use super::account_tokens::Entity as AccountTokens;
+pub use super::login_attempts::Entity as LoginAttempts;
+pub use super::oidc_auth_requests::Entity as OidcAuthRequests;
+pub use super::passkeys::Entity as Passkeys;
+pub use super::personal_access_tokens::Entity as PersonalAccessTokens;
+pub use super::recovery_codes::Entity as RecoveryCodes;
+pub use super::refresh_tokens::Entity as RefreshTokens;
+pub use super::sessions::Entity as Sessions;
+pub use super::task_priorities::Entity as TaskPriorities;
 pub use super::tasks::Entity as Tasks;
+pub use super::user_identities::Entity as UserIdentities;
 pub use super::users::Entity as Users;
+pub use super::webauthn_challenges::Entity as WebauthnChallenges;
+pub use super::workspace_invites::Entity as WorkspaceInvites;
+pub use super::workspace_memberships::Entity as WorkspaceMemberships;
+pub use super::workspaces::Entity as Workspaces;
@@ -7,7 +7,7 @@ use sea_orm::entity::prelude::*;
 pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i32,
-    pub priority: Option<String>,
+    pub priority: Option<i16>,
     pub title: String,
     pub completed_at: Option<DateTimeWithTimeZone>,
     #[sea_orm(column_type = "Text", nullable)]
@@ -15,18 +15,41 @@ pub struct Model {
     pub deleted_at: Option<DateTimeWithTimeZone>,
     pub user_id: Option<i32>,
     pub is_default: Option<bool>,
+    pub workspace_id: i32,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::task_priorities::Entity",
+        from = "(Column::WorkspaceId, Column::Priority)",
+        to = "(super::task_priorities::Column::WorkspaceId, super::task_priorities::Column::Level)",
+        on_update = "Cascade",
+        on_delete = "SetNull"
+    )]
+    TaskPriorities,
     #[sea_orm(
         belongs_to = "super::users::Entity",
         from = "Column::UserId",
         to = "super::users::Column::Id",
         on_update = "NoAction",
-        on_delete = "NoAction"
+        on_delete = "SetNull"
     )]
     Users,
+    #[sea_orm(
+        belongs_to = "super::workspaces::Entity",
+        from = "Column::WorkspaceId",
+        to = "super::workspaces::Column::Id",
+        on_update = "NoAction",
+        on_delete = "Cascade"
+    )]
+    Workspaces,
+}
+
+impl Related<super::task_priorities::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::TaskPriorities.def()
+    }
 }
 
 impl Related<super::users::Entity> for Entity {
@@ -35,4 +58,10 @@ impl Related<super::users::Entity> for Entity {
     }
 }
 
+impl Related<super::workspaces::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Workspaces.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
@@ -1,5 +1,6 @@
 //! `SeaORM` Entity, @generated by sea-orm-codegen 1.0.0
 
+use super::sea_orm_active_enums::UserRole;
 use sea_orm::entity::prelude::*;
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
@@ -9,16 +10,92 @@ pub struct Model {
     pub id: i32,
     #[sea_orm(unique)]
     pub username: String,
-    pub password: String,
+    pub password: Option<String>,
     pub deleted_at: Option<DateTimeWithTimeZone>,
+    pub role: UserRole,
+    pub disabled_at: Option<DateTimeWithTimeZone>,
     #[sea_orm(column_type = "Text", nullable)]
-    pub token: Option<String>,
+    pub totp_secret: Option<String>,
+    pub totp_enabled_at: Option<DateTimeWithTimeZone>,
+    pub totp_last_step: Option<i64>,
+    pub email_verified_at: Option<DateTimeWithTimeZone>,
+    #[sea_orm(unique)]
+    pub webauthn_user_id: Uuid,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub display_name: Option<String>,
+    #[sea_orm(column_type = "Text")]
+    pub timezone: String,
+    #[sea_orm(column_type = "Text")]
+    pub locale: String,
+    pub avatar_updated_at: Option<DateTimeWithTimeZone>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
+    #[sea_orm(has_many = "super::account_tokens::Entity")]
+    AccountTokens,
+    #[sea_orm(has_many = "super::oidc_auth_requests::Entity")]
+    OidcAuthRequests,
+    #[sea_orm(has_many = "super::passkeys::Entity")]
+    Passkeys,
+    #[sea_orm(has_many = "super::personal_access_tokens::Entity")]
+    PersonalAccessTokens,
+    #[sea_orm(has_many = "super::recovery_codes::Entity")]
+    RecoveryCodes,
+    #[sea_orm(has_many = "super::refresh_tokens::Entity")]
+    RefreshTokens,
+    #[sea_orm(has_many = "super::sessions::Entity")]
+    Sessions,
     #[sea_orm(has_many = "super::tasks::Entity")]
     Tasks,
+    #[sea_orm(has_many = "super::user_identities::Entity")]
+    UserIdentities,
+    #[sea_orm(has_many = "super::webauthn_challenges::Entity")]
+    WebauthnChallenges,
+    #[sea_orm(has_many = "super::workspace_memberships::Entity")]
+    WorkspaceMemberships,
+}
+
+impl Related<super::account_tokens::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::AccountTokens.def()
+    }
+}
+
+impl Related<super::oidc_auth_requests::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::OidcAuthRequests.def()
+    }
+}
+
+impl Related<super::passkeys::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Passkeys.def()
+    }
+}
+
+impl Related<super::personal_access_tokens::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::PersonalAccessTokens.def()
+    }
+}
+
+impl Related<super::recovery_codes::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::RecoveryCodes.def()
+    }
+}
+
+impl Related<super::refresh_tokens::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::RefreshTokens.def()
+    }
+}
+
+impl Related<super::sessions::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Sessions.def()
+    }
 }
 
 impl Related<super::tasks::Entity> for Entity {
@@ -27,4 +104,31 @@ impl Related<super::tasks::Entity> for Entity {
     }
 }
 
+impl Related<super::user_identities::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::UserIdentities.def()
+    }
+}
+
+impl Related<super::webauthn_challenges::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::WebauthnChallenges.def()
+    }
+}
+
+impl Related<super::workspace_memberships::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::WorkspaceMemberships.def()
+    }
+}
+
+impl Related<super::workspaces::Entity> for Entity {
+    fn to() -> RelationDef {
+        super::workspace_memberships::Relation::Workspaces.def()
+    }
+    fn via() -> Option<RelationDef> {
+        Some(super::workspace_memberships::Relation::Users.def().rev())
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
@@ -1,11 +1,15 @@
+mod config;
 mod database;
 mod routes;
+#[cfg(test)]
+mod test_support;
 mod utils;
 
 use axum_db::connect_to_db;
+use config::Config;
 use routes::create_routes;
-use std::{env, fmt};
-use tracing_subscriber;
+use std::{env, fmt, net::SocketAddr};
+use utils::purge::spawn_account_purge;
 
 #[derive(PartialEq)]
 enum AppEnv {
@@ -25,22 +29,31 @@ impl fmt::Display for AppEnv {
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
-    let db_conn_str = get_db_conn_str();
+    let config = load_config();
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    let connection = match connect_to_db(&db_conn_str[..]).await {
+    let connection = match connect_to_db(&config.database_url[..]).await {
         Ok(c) => c,
         Err(e) => {
             eprintln!("Failed to connect to database: {e}");
             return;
         }
     };
-    axum::serve(listener, create_routes(connection).await)
-        .await
-        .unwrap();
+    spawn_account_purge(
+        connection.clone(),
+        config.account.deletion_grace_period,
+        config.account.purge_interval,
+    );
+    let app = create_routes(connection, config).await;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
 
-fn get_db_conn_str() -> String {
+fn load_config() -> Config {
     let app_env = match env::var("APP_ENV") {
         Ok(v) if v == "prod" => AppEnv::Prod,
         _ => AppEnv::Dev,
@@ -54,5 +67,5 @@ fn get_db_conn_str() -> String {
             Err(e) => println!("Could not load .env file: {e}"),
         };
     }
-    env::var("DATABASE_URL").expect("DB connection string not set")
+    Config::from_env()
 }
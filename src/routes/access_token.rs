@@ -0,0 +1,158 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{Duration, Utc};
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait,
+    IntoActiveModel, QueryFilter, QueryOrder, Set,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use super::guard::{AuthUser, SessionClaims};
+use crate::database::{personal_access_tokens, prelude::PersonalAccessTokens};
+use crate::utils::app_error::AppError;
+use crate::utils::permission::Scope;
+use crate::utils::token::{generate_opaque_token, hash_token};
+
+/// Lets the guard tell access tokens apart from JWTs without trying to decode them.
+pub const ACCESS_TOKEN_PREFIX: &str = "adb_pat_";
+
+#[derive(Debug, Deserialize)]
+pub struct AccessTokenRequest {
+    name: String,
+    scopes: Vec<Scope>,
+    expires_in_days: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct AccessTokenResponse {
+    id: i32,
+    name: String,
+    scopes: Vec<String>,
+    created_at: DateTimeWithTimeZone,
+    expires_at: Option<DateTimeWithTimeZone>,
+    last_used_at: Option<DateTimeWithTimeZone>,
+    /// Only present in the response to creation; it can't be recovered afterwards.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+impl From<personal_access_tokens::Model> for AccessTokenResponse {
+    fn from(access_token: personal_access_tokens::Model) -> Self {
+        AccessTokenResponse {
+            id: access_token.id,
+            name: access_token.name,
+            scopes: access_token.scopes,
+            created_at: access_token.created_at,
+            expires_at: access_token.expires_at,
+            last_used_at: access_token.last_used_at,
+            token: None,
+        }
+    }
+}
+
+#[instrument(skip(database, user, _session))]
+pub async fn create_access_token(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+    Json(req): Json<AccessTokenRequest>,
+) -> Result<(StatusCode, Json<AccessTokenResponse>), AppError> {
+    let name = req.name.trim();
+    if name.is_empty() {
+        return Err(AppError::new(StatusCode::BAD_REQUEST, "Name is required."));
+    }
+    if req.scopes.is_empty() {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "At least one scope is required.",
+        ));
+    }
+    let expires_at = match req.expires_in_days {
+        Some(days) if days <= 0 => {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                "Expiry must be at least one day.",
+            ))
+        }
+        Some(days) => Some((Utc::now() + Duration::days(days)).into()),
+        None => None,
+    };
+
+    let mut scopes: Vec<String> = req
+        .scopes
+        .iter()
+        .map(|scope| scope.as_str().to_owned())
+        .collect();
+    scopes.sort();
+    scopes.dedup();
+    let token = format!("{ACCESS_TOKEN_PREFIX}{}", generate_opaque_token());
+    let access_token = personal_access_tokens::ActiveModel {
+        user_id: Set(user.id),
+        name: Set(name.to_owned()),
+        token_hash: Set(hash_token(&token)),
+        scopes: Set(scopes),
+        created_at: Set(Utc::now().into()),
+        expires_at: Set(expires_at),
+        ..Default::default()
+    }
+    .insert(&database)
+    .await
+    .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(
+        user_id = user.id,
+        token_id = access_token.id,
+        "access token created"
+    );
+
+    let mut response = AccessTokenResponse::from(access_token);
+    response.token = Some(token);
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+#[instrument(skip(database, user))]
+pub async fn get_my_access_tokens(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+) -> Result<Json<Vec<AccessTokenResponse>>, AppError> {
+    let access_tokens = PersonalAccessTokens::find()
+        .filter(personal_access_tokens::Column::UserId.eq(user.id))
+        .filter(personal_access_tokens::Column::RevokedAt.is_null())
+        .order_by_desc(personal_access_tokens::Column::CreatedAt)
+        .all(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .into_iter()
+        .map(AccessTokenResponse::from)
+        .collect();
+    Ok(Json(access_tokens))
+}
+
+#[instrument(skip(database, user, _session))]
+pub async fn revoke_access_token(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+    Path(token_id): Path<i32>,
+) -> Result<(), AppError> {
+    let Some(access_token) = PersonalAccessTokens::find_by_id(token_id)
+        .filter(personal_access_tokens::Column::UserId.eq(user.id))
+        .filter(personal_access_tokens::Column::RevokedAt.is_null())
+        .one(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+    else {
+        return Err(AppError::new(StatusCode::NOT_FOUND, "Token not found."));
+    };
+    let mut access_token = access_token.into_active_model();
+    access_token.revoked_at = Set(Some(Utc::now().into()));
+    access_token
+        .update(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(user_id = user.id, token_id, "access token revoked");
+    Ok(())
+}
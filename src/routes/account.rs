@@ -0,0 +1,551 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use axum_extra::extract::CookieJar;
+use chrono::{Duration, Utc};
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, sea_query::Expr, ActiveEnum, ActiveModelTrait, ColumnTrait,
+    ConnectionTrait, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter, Set,
+    TransactionTrait,
+};
+use serde::Deserialize;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use super::cookie::{set_magic_link_nonce, take_magic_link_nonce};
+use super::guard::{AuthUser, SessionClaims};
+use super::mfa::{complete_sign_in, LoginResponse};
+use super::session::{revoke_user_sessions, ClientInfo};
+use crate::config::{AccountConfig, AuthConfig};
+use crate::database::{
+    account_tokens,
+    prelude::{AccountTokens, Users},
+    sea_orm_active_enums::AccountTokenPurpose,
+    users,
+};
+use crate::utils::app_error::AppError;
+use crate::utils::jwt::{create_challenge_token, decode_challenge_token, JwtConfig};
+use crate::utils::lockout::LoginThrottle;
+use crate::utils::mailer::{Email, Mailer};
+use crate::utils::password::PasswordHasher;
+use crate::utils::password_policy::PasswordPolicy;
+use crate::utils::token::{generate_opaque_token, hash_token};
+use crate::utils::totp::TotpConfig;
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordRequest {
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    token: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailParams {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkRequest {
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkLoginRequest {
+    token: String,
+}
+
+/// Always answers 202 so the response doesn't reveal whether the account exists.
+#[instrument(skip_all)]
+pub async fn forgot_password(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    State(mailer): State<Arc<dyn Mailer>>,
+    State(account): State<AccountConfig>,
+    Json(request): Json<ForgotPasswordRequest>,
+) -> Result<StatusCode, AppError> {
+    let user = Users::find()
+        .filter(users::Column::Username.eq(request.username))
+        .filter(users::Column::DisabledAt.is_null())
+        .filter(users::Column::DeletedAt.is_null())
+        .one(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if let Some(user) = user {
+        let token = issue_account_token(
+            &database,
+            &jwt,
+            user.id,
+            AccountTokenPurpose::PasswordReset,
+            account.password_reset_ttl,
+            None,
+        )
+        .await?;
+        let email = Email {
+            to: user.username,
+            subject: "Reset your password".to_owned(),
+            body: format!(
+                "Someone asked to reset the password for this account. If it was you, follow \
+                 this link within {} minutes:\n\n{}/password/reset?token={token}\n\nOtherwise \
+                 you can ignore this email.",
+                account.password_reset_ttl.num_minutes(),
+                account.app_url,
+            ),
+        };
+        send_in_background(mailer, email);
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Sets a new password and signs the account out everywhere.
+#[instrument(skip_all)]
+pub async fn reset_password(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    State(hasher): State<PasswordHasher>,
+    State(password_policy): State<PasswordPolicy>,
+    Json(request): Json<ResetPasswordRequest>,
+) -> Result<(), AppError> {
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let user = consume_account_token(
+        &txn,
+        &jwt,
+        &request.token,
+        AccountTokenPurpose::PasswordReset,
+        None,
+    )
+    .await?;
+    // Checked after the token so the username rule applies; failing rolls the token back.
+    password_policy.check(&request.password, Some(&user.username))?;
+    let user_id = user.id;
+    let verified = user.email_verified_at.is_some();
+    let mut user = user.into_active_model();
+    user.password = Set(Some(hasher.hash(request.password).await?));
+    // Following the emailed link proves the address works.
+    if !verified {
+        user.email_verified_at = Set(Some(Utc::now().into()));
+    }
+    user.update(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    AccountTokens::update_many()
+        .col_expr(
+            account_tokens::Column::UsedAt,
+            Expr::value(DateTimeWithTimeZone::from(Utc::now())),
+        )
+        .filter(account_tokens::Column::UserId.eq(user_id))
+        .filter(account_tokens::Column::Purpose.eq(AccountTokenPurpose::PasswordReset))
+        .filter(account_tokens::Column::UsedAt.is_null())
+        .exec(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    revoke_user_sessions(&txn, user_id, None).await?;
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(user_id, "password reset");
+    Ok(())
+}
+
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn resend_verification_email(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    State(mailer): State<Arc<dyn Mailer>>,
+    State(account): State<AccountConfig>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+) -> Result<StatusCode, AppError> {
+    if user.email_verified_at.is_some() {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            "Email address is already verified.",
+        ));
+    }
+    let email = verification_email(&database, &jwt, &account, &user).await?;
+    mailer.send(email).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Target of the link in the verification email.
+#[instrument(skip_all)]
+pub async fn verify_email(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    Query(params): Query<VerifyEmailParams>,
+) -> Result<(), AppError> {
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let user = consume_account_token(
+        &txn,
+        &jwt,
+        &params.token,
+        AccountTokenPurpose::EmailVerification,
+        None,
+    )
+    .await?;
+    if user.email_verified_at.is_none() {
+        let user_id = user.id;
+        let mut user = user.into_active_model();
+        user.email_verified_at = Set(Some(Utc::now().into()));
+        user.update(&txn)
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        info!(user_id, "email verified");
+    }
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(())
+}
+
+/// Emails a one-time sign-in link and ties it to this browser with a nonce cookie. Like
+/// `forgot_password`, it answers the same way whether or not the account exists. A newer
+/// request replaces the cookie, so only the latest link works.
+#[instrument(skip_all)]
+pub async fn request_magic_link(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    State(mailer): State<Arc<dyn Mailer>>,
+    State(account): State<AccountConfig>,
+    State(auth): State<AuthConfig>,
+    jar: CookieJar,
+    Json(request): Json<MagicLinkRequest>,
+) -> Result<(CookieJar, StatusCode), AppError> {
+    let nonce = generate_opaque_token();
+    let user = Users::find()
+        .filter(users::Column::Username.eq(request.username))
+        .filter(users::Column::DisabledAt.is_null())
+        .filter(users::Column::DeletedAt.is_null())
+        .one(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if let Some(user) = user {
+        let token = issue_account_token(
+            &database,
+            &jwt,
+            user.id,
+            AccountTokenPurpose::MagicLink,
+            account.magic_link_ttl,
+            Some(hash_token(&nonce)),
+        )
+        .await?;
+        let email = Email {
+            to: user.username,
+            subject: "Your sign-in link".to_owned(),
+            body: format!(
+                "Follow this link within {} minutes to sign in:\n\n\
+                 {}/login/magic-link?token={token}\n\nIt only works once, and only in the \
+                 browser you requested it from. If you didn't ask to sign in, you can ignore \
+                 this email.",
+                account.magic_link_ttl.num_minutes(),
+                account.app_url,
+            ),
+        };
+        send_in_background(mailer, email);
+    }
+    let jar = set_magic_link_nonce(jar, &auth, nonce, account.magic_link_ttl);
+    Ok((jar, StatusCode::ACCEPTED))
+}
+
+/// Redeems a sign-in link. Ends up where `login` does, including the second-factor
+/// challenge for users who have one.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all)]
+pub async fn magic_link_login(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    State(totp): State<TotpConfig>,
+    State(auth): State<AuthConfig>,
+    State(throttle): State<LoginThrottle>,
+    client: ClientInfo,
+    jar: CookieJar,
+    Json(request): Json<MagicLinkLoginRequest>,
+) -> Result<(CookieJar, Json<LoginResponse>), AppError> {
+    let (jar, nonce) = take_magic_link_nonce(jar);
+    let Some(nonce) = nonce else {
+        return Err(AppError::new(
+            StatusCode::UNAUTHORIZED,
+            "Open the sign-in link in the browser you requested it from.",
+        ));
+    };
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    // The nonce is checked as part of consuming, so a link opened elsewhere stays usable.
+    let user = consume_account_token(
+        &txn,
+        &jwt,
+        &request.token,
+        AccountTokenPurpose::MagicLink,
+        Some(&nonce),
+    )
+    .await?;
+    if user.disabled_at.is_some() {
+        return Err(AppError::new(StatusCode::FORBIDDEN, "Account disabled."));
+    }
+    throttle.check(&user.username, client.ip.as_deref()).await?;
+    // Following the emailed link proves the address works.
+    let user = if user.email_verified_at.is_none() {
+        let mut user = user.into_active_model();
+        user.email_verified_at = Set(Some(Utc::now().into()));
+        user.update(&txn)
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+    } else {
+        user
+    };
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(user_id = user.id, "signed in with magic link");
+    let (jar, response) =
+        complete_sign_in(&database, &jwt, &totp, &auth, jar, user, client).await?;
+    Ok((jar, Json(response)))
+}
+
+/// Sends the verification email for a newly created account without holding up the response.
+pub async fn send_welcome_verification(
+    database: &DatabaseConnection,
+    jwt: &JwtConfig,
+    mailer: Arc<dyn Mailer>,
+    account: &AccountConfig,
+    user: &users::Model,
+) -> Result<(), AppError> {
+    let email = verification_email(database, jwt, account, user).await?;
+    send_in_background(mailer, email);
+    Ok(())
+}
+
+async fn verification_email(
+    database: &DatabaseConnection,
+    jwt: &JwtConfig,
+    account: &AccountConfig,
+    user: &users::Model,
+) -> Result<Email, AppError> {
+    let token = issue_account_token(
+        database,
+        jwt,
+        user.id,
+        AccountTokenPurpose::EmailVerification,
+        account.email_verification_ttl,
+        None,
+    )
+    .await?;
+    Ok(Email {
+        to: user.username.clone(),
+        subject: "Verify your email address".to_owned(),
+        body: format!(
+            "Confirm this address by following the link below within {} hours:\n\n\
+             {}/email/verify?token={token}",
+            account.email_verification_ttl.num_hours(),
+            account.app_url,
+        ),
+    })
+}
+
+/// Records a single-use token and returns it signed. The row id doubles as the token's `jti`.
+pub async fn issue_account_token(
+    database: &impl ConnectionTrait,
+    jwt: &JwtConfig,
+    user_id: i32,
+    purpose: AccountTokenPurpose,
+    ttl: Duration,
+    nonce_hash: Option<String>,
+) -> Result<String, AppError> {
+    let id = Uuid::new_v4();
+    let now = Utc::now();
+    account_tokens::ActiveModel {
+        id: Set(id),
+        user_id: Set(user_id),
+        purpose: Set(purpose),
+        created_at: Set(now.into()),
+        expires_at: Set((now + ttl).into()),
+        used_at: Set(None),
+        nonce_hash: Set(nonce_hash),
+    }
+    .insert(database)
+    .await
+    .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    create_challenge_token(jwt, user_id, &purpose.to_value(), id, ttl)
+}
+
+/// Checks the signature, marks the token used and returns its user. A second use fails, as
+/// does presenting a `nonce` other than the one the token was issued with.
+pub async fn consume_account_token(
+    database: &impl ConnectionTrait,
+    jwt: &JwtConfig,
+    token: &str,
+    purpose: AccountTokenPurpose,
+    nonce: Option<&str>,
+) -> Result<users::Model, AppError> {
+    let invalid = || AppError::new(StatusCode::UNAUTHORIZED, "Invalid or expired token.");
+    let claims = decode_challenge_token(jwt, token, &purpose.to_value())?;
+    let id: Uuid = claims.jti.parse().map_err(|_| invalid())?;
+    let user_id = claims.user_id()?;
+    let now = DateTimeWithTimeZone::from(Utc::now());
+    let mut update = AccountTokens::update_many()
+        .col_expr(account_tokens::Column::UsedAt, Expr::value(now))
+        .filter(account_tokens::Column::Id.eq(id))
+        .filter(account_tokens::Column::UserId.eq(user_id))
+        .filter(account_tokens::Column::Purpose.eq(purpose))
+        .filter(account_tokens::Column::UsedAt.is_null())
+        .filter(account_tokens::Column::ExpiresAt.gt(now));
+    if let Some(nonce) = nonce {
+        update = update.filter(account_tokens::Column::NonceHash.eq(hash_token(nonce)));
+    }
+    let result = update
+        .exec(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if result.rows_affected == 0 {
+        return Err(invalid());
+    }
+    Users::find_by_id(user_id)
+        .filter(users::Column::DeletedAt.is_null())
+        .one(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or_else(invalid)
+}
+
+pub fn send_in_background(mailer: Arc<dyn Mailer>, email: Email) {
+    tokio::spawn(async move {
+        if let Err(error) = mailer.send(email).await {
+            warn!(?error, "could not send email");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use serde_json::json;
+
+    use super::*;
+    use crate::routes::cookie::MAGIC_LINK_COOKIE;
+    use crate::test_support::{json_request, TestApp, TestResponse};
+
+    const USERNAME: &str = "ada@example.com";
+
+    /// Requests a link for `USERNAME` and returns the token from the email and the nonce
+    /// cookie.
+    async fn request_link(app: &TestApp) -> (String, String) {
+        let response = app
+            .call(json_request(
+                Method::POST,
+                "/login/magic-link",
+                json!({ "username": USERNAME }),
+                &[],
+            ))
+            .await;
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        let nonce = response.cookie(MAGIC_LINK_COOKIE).expect("nonce cookie");
+        let email = app.email(USERNAME, "Your sign-in link").await;
+        let token = email
+            .split("/login/magic-link?token=")
+            .nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .expect("link in the email")
+            .to_owned();
+        (token, nonce)
+    }
+
+    async fn redeem(app: &TestApp, token: &str, nonce: Option<&str>) -> TestResponse {
+        let cookie = nonce.map(|nonce| format!("{MAGIC_LINK_COOKIE}={nonce}"));
+        let headers: Vec<_> = cookie.iter().map(|c| ("cookie", c.as_str())).collect();
+        app.call(json_request(
+            Method::POST,
+            "/login/magic-link/verify",
+            json!({ "token": token }),
+            &headers,
+        ))
+        .await
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn magic_link_signs_in_once() {
+        let app = TestApp::start(|_| {}).await;
+        app.sign_up(USERNAME).await;
+        let (token, nonce) = request_link(&app).await;
+
+        let response = redeem(&app, &token, Some(&nonce)).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        assert_eq!(response.body["username"], USERNAME);
+        assert!(response.body["token"].is_string());
+        assert_eq!(response.cookie(MAGIC_LINK_COOKIE), None);
+
+        let replay = redeem(&app, &token, Some(&nonce)).await;
+        assert_eq!(replay.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(replay.body["message"], "Invalid or expired token.");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn magic_link_expires() {
+        let app = TestApp::start(|_| {}).await;
+        app.sign_up(USERNAME).await;
+        let (token, nonce) = request_link(&app).await;
+        app.database
+            .execute_unprepared(
+                "UPDATE account_tokens SET expires_at = now() - interval '1 second' \
+                 WHERE purpose = 'magic_link'",
+            )
+            .await
+            .unwrap();
+
+        let response = redeem(&app, &token, Some(&nonce)).await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response.body["message"], "Invalid or expired token.");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn magic_link_needs_the_nonce_from_the_requesting_browser() {
+        let app = TestApp::start(|_| {}).await;
+        app.sign_up(USERNAME).await;
+        let (token, nonce) = request_link(&app).await;
+
+        let response = redeem(&app, &token, None).await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.body["message"],
+            "Open the sign-in link in the browser you requested it from."
+        );
+        let response = redeem(&app, &token, Some("another-browser")).await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response.body["message"], "Invalid or expired token.");
+
+        // Neither attempt used the link up.
+        let response = redeem(&app, &token, Some(&nonce)).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn newer_magic_link_replaces_the_nonce() {
+        let app = TestApp::start(|_| {}).await;
+        app.sign_up(USERNAME).await;
+        let (first, _) = request_link(&app).await;
+        let (second, nonce) = request_link(&app).await;
+
+        let response = redeem(&app, &first, Some(&nonce)).await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        let response = redeem(&app, &second, Some(&nonce)).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+    }
+}
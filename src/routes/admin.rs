@@ -0,0 +1,154 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::Utc;
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, ActiveModelTrait, ColumnTrait, ConnectionTrait,
+    DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter, QueryOrder, Set,
+    TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use super::guard::AuthUser;
+use super::session::revoke_user_sessions;
+use crate::database::{prelude::Users, sea_orm_active_enums::UserRole, users};
+use crate::utils::app_error::AppError;
+use crate::utils::lockout::LoginThrottle;
+
+#[derive(Serialize)]
+pub struct AdminUserResponse {
+    id: i32,
+    username: String,
+    role: UserRole,
+    disabled_at: Option<DateTimeWithTimeZone>,
+}
+
+impl From<users::Model> for AdminUserResponse {
+    fn from(user: users::Model) -> Self {
+        AdminUserResponse {
+            id: user.id,
+            username: user.username,
+            role: user.role,
+            disabled_at: user.disabled_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoleRequest {
+    role: UserRole,
+}
+
+#[instrument(skip(database))]
+pub async fn list_users(
+    State(database): State<DatabaseConnection>,
+) -> Result<Json<Vec<AdminUserResponse>>, AppError> {
+    let users = Users::find()
+        .filter(users::Column::DeletedAt.is_null())
+        .order_by_asc(users::Column::Id)
+        .all(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .into_iter()
+        .map(AdminUserResponse::from)
+        .collect();
+    Ok(Json(users))
+}
+
+#[instrument(skip(database, admin))]
+pub async fn disable_user(
+    State(database): State<DatabaseConnection>,
+    AuthUser(admin): AuthUser,
+    Path(user_id): Path<i32>,
+) -> Result<Json<AdminUserResponse>, AppError> {
+    if user_id == admin.id {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "You cannot disable your own account.",
+        ));
+    }
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let mut user = find_user(&txn, user_id).await?.into_active_model();
+    user.disabled_at = Set(Some(Utc::now().into()));
+    let user = user
+        .update(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    revoke_user_sessions(&txn, user_id, None).await?;
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(admin_id = admin.id, user_id, "account disabled");
+    Ok(Json(user.into()))
+}
+
+#[instrument(skip(database, admin))]
+pub async fn enable_user(
+    State(database): State<DatabaseConnection>,
+    AuthUser(admin): AuthUser,
+    Path(user_id): Path<i32>,
+) -> Result<Json<AdminUserResponse>, AppError> {
+    let mut user = find_user(&database, user_id).await?.into_active_model();
+    user.disabled_at = Set(None);
+    let user = user
+        .update(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(admin_id = admin.id, user_id, "account re-enabled");
+    Ok(Json(user.into()))
+}
+
+#[instrument(skip(database, admin))]
+pub async fn set_user_role(
+    State(database): State<DatabaseConnection>,
+    AuthUser(admin): AuthUser,
+    Path(user_id): Path<i32>,
+    Json(req): Json<RoleRequest>,
+) -> Result<Json<AdminUserResponse>, AppError> {
+    if user_id == admin.id && req.role != UserRole::Admin {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "You cannot remove your own admin role.",
+        ));
+    }
+    let mut user = find_user(&database, user_id).await?.into_active_model();
+    user.role = Set(req.role);
+    let user = user
+        .update(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(admin_id = admin.id, user_id, role = ?req.role, "role changed");
+    Ok(Json(user.into()))
+}
+
+/// Lifts a lockout from repeated failed sign-ins.
+#[instrument(skip(database, throttle, admin))]
+pub async fn unlock_user(
+    State(database): State<DatabaseConnection>,
+    State(throttle): State<LoginThrottle>,
+    AuthUser(admin): AuthUser,
+    Path(user_id): Path<i32>,
+) -> Result<Json<AdminUserResponse>, AppError> {
+    let user = find_user(&database, user_id).await?;
+    throttle.unlock(&user.username).await?;
+    info!(admin_id = admin.id, user_id, "account unlocked");
+    Ok(Json(user.into()))
+}
+
+async fn find_user(
+    database: &impl ConnectionTrait,
+    user_id: i32,
+) -> Result<users::Model, AppError> {
+    Users::find_by_id(user_id)
+        .filter(users::Column::DeletedAt.is_null())
+        .one(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, "User not found."))
+}
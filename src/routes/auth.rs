@@ -0,0 +1,170 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::extract::CookieJar;
+use chrono::Utc;
+use jsonwebtoken::jwk::JwkSet;
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, sea_query::Expr, ActiveModelTrait, ColumnTrait, ConnectionTrait,
+    DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter, Set, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{instrument, warn};
+use uuid::Uuid;
+
+use super::cookie::{deliver_tokens, verify_csrf, REFRESH_COOKIE};
+use super::session::revoke_session;
+use crate::config::AuthConfig;
+use crate::database::{
+    prelude::{RefreshTokens, Sessions},
+    refresh_tokens,
+};
+use crate::utils::app_error::AppError;
+use crate::utils::jwt::{create_jwt, JwtConfig};
+use crate::utils::token::{generate_opaque_token, hash_token};
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub token: String,
+    pub refresh_token: String,
+}
+
+pub async fn jwks(State(jwt): State<JwtConfig>) -> Json<JwkSet> {
+    Json(jwt.keyring.jwks())
+}
+
+/// Takes the refresh token from the body, or for browser sessions from its cookie.
+#[instrument(skip_all)]
+pub async fn refresh(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    State(auth): State<AuthConfig>,
+    method: Method,
+    headers: HeaderMap,
+    jar: CookieJar,
+    req: Option<Json<RefreshRequest>>,
+) -> Result<Response, AppError> {
+    let body_token = req
+        .and_then(|Json(req)| req.refresh_token)
+        .filter(|_| auth.bearer);
+    let refresh_token = match body_token {
+        Some(token) => token,
+        None => {
+            let Some(cookie) = jar.get(REFRESH_COOKIE).filter(|_| auth.cookie) else {
+                return Err(invalid_refresh_token());
+            };
+            verify_csrf(&method, &headers, &jar)?;
+            cookie.value().to_owned()
+        }
+    };
+    let txn = database
+        .begin()
+        .await
+        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+
+    let Some((current, Some(session))) = RefreshTokens::find()
+        .filter(refresh_tokens::Column::TokenHash.eq(hash_token(&refresh_token)))
+        .find_also_related(Sessions)
+        .one(&txn)
+        .await
+        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?
+    else {
+        return Err(invalid_refresh_token());
+    };
+    let now = Utc::now();
+    if current.revoked_at.is_some()
+        || current.expires_at < now
+        || session.revoked_at.is_some()
+        || session.expires_at < now
+    {
+        return Err(invalid_refresh_token());
+    }
+
+    // Claim the token atomically so two concurrent refreshes can't both rotate it.
+    let claimed = RefreshTokens::update_many()
+        .col_expr(
+            refresh_tokens::Column::UsedAt,
+            Expr::value(DateTimeWithTimeZone::from(now)),
+        )
+        .filter(refresh_tokens::Column::Id.eq(current.id))
+        .filter(refresh_tokens::Column::UsedAt.is_null())
+        .exec(&txn)
+        .await
+        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+    if claimed.rows_affected == 0 {
+        warn!(
+            user_id = current.user_id,
+            session_id = %current.session_id,
+            "refresh token reuse detected, revoking session"
+        );
+        revoke_session(&txn, session.id).await?;
+        txn.commit()
+            .await
+            .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+        return Err(invalid_refresh_token());
+    }
+
+    let refresh_token = generate_opaque_token();
+    let expires_at: DateTimeWithTimeZone = (now + jwt.refresh_token_ttl).into();
+    record_refresh_token(
+        &txn,
+        session.user_id,
+        session.id,
+        &refresh_token,
+        expires_at,
+    )
+    .await?;
+    let mut session = session.into_active_model();
+    session.last_seen_at = Set(now.into());
+    session.expires_at = Set(expires_at);
+    let session = session
+        .update(&txn)
+        .await
+        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+    txn.commit()
+        .await
+        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+
+    let tokens = TokenResponse {
+        token: create_jwt(&jwt, session.user_id, session.id)?,
+        refresh_token,
+    };
+    Ok(match deliver_tokens(jar, &auth, &jwt, tokens) {
+        (jar, Some(tokens)) => (jar, Json(tokens)).into_response(),
+        (jar, None) => (jar, StatusCode::NO_CONTENT).into_response(),
+    })
+}
+
+/// Stores the hash of a refresh token issued under `session_id`.
+pub async fn record_refresh_token(
+    database: &impl ConnectionTrait,
+    user_id: i32,
+    session_id: Uuid,
+    token: &str,
+    expires_at: DateTimeWithTimeZone,
+) -> Result<(), AppError> {
+    refresh_tokens::ActiveModel {
+        user_id: Set(user_id),
+        session_id: Set(session_id),
+        token_hash: Set(hash_token(token)),
+        created_at: Set(Utc::now().into()),
+        expires_at: Set(expires_at),
+        ..Default::default()
+    }
+    .insert(database)
+    .await
+    .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+    Ok(())
+}
+
+fn invalid_refresh_token() -> AppError {
+    AppError::new(StatusCode::UNAUTHORIZED, "Invalid refresh token.")
+}
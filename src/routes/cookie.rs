@@ -0,0 +1,167 @@
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use sha2::{Digest, Sha256};
+use time::Duration as CookieDuration;
+
+use super::auth::TokenResponse;
+use crate::config::AuthConfig;
+use crate::utils::app_error::AppError;
+use crate::utils::jwt::JwtConfig;
+use crate::utils::token::generate_opaque_token;
+
+pub const SESSION_COOKIE: &str = "session";
+pub const REFRESH_COOKIE: &str = "refresh_token";
+/// Readable by scripts on purpose: the frontend echoes it back in `CSRF_HEADER`.
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// Binds a requested sign-in link to the browser that asked for it.
+pub const MAGIC_LINK_COOKIE: &str = "magic_link_nonce";
+/// Binds an identity provider round trip to the browser that started it.
+pub const OIDC_STATE_COOKIE: &str = "oidc_state";
+/// The refresh cookie is only ever sent to the endpoint that consumes it.
+const REFRESH_COOKIE_PATH: &str = "/auth/refresh";
+const MAGIC_LINK_COOKIE_PATH: &str = "/login/magic-link";
+const OIDC_STATE_COOKIE_PATH: &str = "/auth/oidc";
+
+/// Hands a fresh token pair to the client in every enabled way: as cookies for browser
+/// sessions, and in the response body for bearer clients.
+pub fn deliver_tokens(
+    jar: CookieJar,
+    auth: &AuthConfig,
+    jwt: &JwtConfig,
+    tokens: TokenResponse,
+) -> (CookieJar, Option<TokenResponse>) {
+    if !auth.cookie {
+        return (jar, Some(tokens));
+    }
+    let jar = jar
+        .add(build_cookie(
+            auth,
+            SESSION_COOKIE,
+            tokens.token.clone(),
+            "/",
+            jwt.access_token_ttl,
+            true,
+        ))
+        .add(build_cookie(
+            auth,
+            REFRESH_COOKIE,
+            tokens.refresh_token.clone(),
+            REFRESH_COOKIE_PATH,
+            jwt.refresh_token_ttl,
+            true,
+        ))
+        .add(build_cookie(
+            auth,
+            CSRF_COOKIE,
+            generate_opaque_token(),
+            "/",
+            jwt.refresh_token_ttl,
+            false,
+        ));
+    (jar, auth.bearer.then_some(tokens))
+}
+
+pub fn clear_session_cookies(jar: CookieJar) -> CookieJar {
+    jar.remove(Cookie::build(SESSION_COOKIE).path("/"))
+        .remove(Cookie::build(REFRESH_COOKIE).path(REFRESH_COOKIE_PATH))
+        .remove(Cookie::build(CSRF_COOKIE).path("/"))
+}
+
+/// Set regardless of `AUTH_MODES`: the link is opened in a browser even when the session
+/// it produces ends up as bearer tokens.
+pub fn set_magic_link_nonce(
+    jar: CookieJar,
+    auth: &AuthConfig,
+    nonce: String,
+    ttl: chrono::Duration,
+) -> CookieJar {
+    jar.add(build_cookie(
+        auth,
+        MAGIC_LINK_COOKIE,
+        nonce,
+        MAGIC_LINK_COOKIE_PATH,
+        ttl,
+        true,
+    ))
+}
+
+/// Returns the nonce, if the browser has one, and removes the cookie.
+pub fn take_magic_link_nonce(jar: CookieJar) -> (CookieJar, Option<String>) {
+    let nonce = jar
+        .get(MAGIC_LINK_COOKIE)
+        .map(|cookie| cookie.value().to_owned());
+    let jar = jar.remove(Cookie::build(MAGIC_LINK_COOKIE).path(MAGIC_LINK_COOKIE_PATH));
+    (jar, nonce)
+}
+
+/// Set regardless of `AUTH_MODES`, and always `SameSite=Lax`: the provider sends the browser
+/// back with a cross-site redirect, which would drop a `Strict` cookie.
+pub fn set_oidc_state(
+    jar: CookieJar,
+    auth: &AuthConfig,
+    state: String,
+    ttl: chrono::Duration,
+) -> CookieJar {
+    let mut cookie = build_cookie(
+        auth,
+        OIDC_STATE_COOKIE,
+        state,
+        OIDC_STATE_COOKIE_PATH,
+        ttl,
+        true,
+    );
+    cookie.set_same_site(SameSite::Lax);
+    jar.add(cookie)
+}
+
+/// Removes the state cookie and tells whether it held `state`.
+pub fn take_oidc_state(jar: CookieJar, state: &str) -> (CookieJar, bool) {
+    let matches = jar
+        .get(OIDC_STATE_COOKIE)
+        .is_some_and(|cookie| !cookie.value().is_empty() && digest_eq(cookie.value(), state));
+    let jar = jar.remove(Cookie::build(OIDC_STATE_COOKIE).path(OIDC_STATE_COOKIE_PATH));
+    (jar, matches)
+}
+
+/// Double-submit check for cookie-authenticated requests: anything other than a safe method
+/// must echo the CSRF cookie in `CSRF_HEADER`, which another site can't read or forge.
+pub fn verify_csrf(method: &Method, headers: &HeaderMap, jar: &CookieJar) -> Result<(), AppError> {
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(());
+    }
+    let cookie = jar.get(CSRF_COOKIE).map(Cookie::value);
+    let header = headers
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match (cookie, header) {
+        (Some(cookie), Some(header)) if !cookie.is_empty() && digest_eq(cookie, header) => Ok(()),
+        _ => Err(AppError::new(
+            StatusCode::FORBIDDEN,
+            "Missing or invalid CSRF token.",
+        )),
+    }
+}
+
+fn build_cookie(
+    auth: &AuthConfig,
+    name: &'static str,
+    value: String,
+    path: &'static str,
+    max_age: chrono::Duration,
+    http_only: bool,
+) -> Cookie<'static> {
+    Cookie::build((name, value))
+        .path(path)
+        .http_only(http_only)
+        .secure(auth.cookie_secure)
+        .same_site(auth.cookie_same_site)
+        .max_age(CookieDuration::seconds(max_age.num_seconds()))
+        .build()
+}
+
+/// Compares digests rather than the values themselves so the comparison time doesn't depend
+/// on how much of the token matched.
+fn digest_eq(a: &str, b: &str) -> bool {
+    Sha256::digest(a.as_bytes()) == Sha256::digest(b.as_bytes())
+}
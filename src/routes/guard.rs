@@ -1,39 +1,267 @@
 use crate::{
-    database::users::{self, Entity as Users},
-    utils::{app_error::AppError, jwt::is_valid},
+    config::AuthConfig,
+    database::{
+        personal_access_tokens,
+        prelude::{PersonalAccessTokens, Sessions, Users},
+        sessions, users,
+    },
+    utils::{
+        app_error::AppError,
+        jwt::{decode_claims, Claims, JwtConfig},
+        permission::{Permission, Scope},
+        token::hash_token,
+    },
 };
 use axum::{
-    extract::{Request, State},
-    http::StatusCode,
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{request::Parts, StatusCode},
     middleware::Next,
     response::Response,
 };
 use axum_extra::{
+    extract::CookieJar,
     headers::{authorization::Bearer, Authorization},
     TypedHeader,
 };
-use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
+use chrono::{DateTime, Duration, Utc};
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, sea_query::Expr, ColumnTrait, Condition, DatabaseConnection,
+    EntityTrait, QueryFilter,
+};
+
+use super::access_token::ACCESS_TOKEN_PREFIX;
+use super::cookie::{verify_csrf, SESSION_COOKIE};
+
+/// How stale a `last_seen_at`/`last_used_at` timestamp may get before a request refreshes it.
+const LAST_SEEN_RESOLUTION: Duration = Duration::minutes(1);
+
+/// The user resolved by `check_authentication`. Only usable on routes behind the guard.
+#[derive(Clone, Debug)]
+pub struct AuthUser(pub users::Model);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthUser
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<users::Model>()
+            .cloned()
+            .map(AuthUser)
+            .ok_or_else(|| AppError::new(StatusCode::UNAUTHORIZED, "Not authenticated."))
+    }
+}
+
+/// How the current request authenticated.
+#[derive(Clone, Debug)]
+pub enum Credential {
+    Session(Claims),
+    AccessToken { scopes: Vec<Scope> },
+}
+
+impl Credential {
+    /// Sessions act with the user's full role; access tokens only within their scopes.
+    pub fn allows(&self, scope: Scope) -> bool {
+        match self {
+            Credential::Session(_) => true,
+            Credential::AccessToken { scopes, .. } => scopes.contains(&scope),
+        }
+    }
+}
 
+#[async_trait]
+impl<S> FromRequestParts<S> for Credential
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Credential>()
+            .cloned()
+            .ok_or_else(|| AppError::new(StatusCode::UNAUTHORIZED, "Not authenticated."))
+    }
+}
+
+/// Claims of the interactive session behind the request. Rejects access-token requests,
+/// so account and session management can't be driven by a script's token.
+#[derive(Clone, Debug)]
+pub struct SessionClaims(pub Claims);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for SessionClaims
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match Credential::from_request_parts(parts, state).await? {
+            Credential::Session(claims) => Ok(SessionClaims(claims)),
+            Credential::AccessToken { .. } => Err(AppError::new(
+                StatusCode::FORBIDDEN,
+                "This action requires a signed-in session.",
+            )),
+        }
+    }
+}
+
+/// Accepts a bearer token or a session cookie, whichever `AuthConfig` enables. A bearer
+/// header wins when both are present.
 pub async fn check_authentication(
     State(database): State<DatabaseConnection>,
-    TypedHeader(token): TypedHeader<Authorization<Bearer>>,
+    State(jwt): State<JwtConfig>,
+    State(auth): State<AuthConfig>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    jar: CookieJar,
     mut req: Request,
     next: Next,
 ) -> Result<Response, AppError> {
-    let token = token.token().to_owned();
-    let user = Users::find()
-        .filter(users::Column::Token.eq(Some(&token[..])))
-        .one(&database)
+    let (user, credential) = match bearer.filter(|_| auth.bearer) {
+        Some(TypedHeader(bearer)) => {
+            let token = bearer.token();
+            if token.starts_with(ACCESS_TOKEN_PREFIX) {
+                authenticate_access_token(&database, token).await?
+            } else {
+                authenticate_session(&database, &jwt, token).await?
+            }
+        }
+        None => {
+            let Some(cookie) = jar.get(SESSION_COOKIE).filter(|_| auth.cookie) else {
+                return Err(AppError::new(
+                    StatusCode::UNAUTHORIZED,
+                    "Not authenticated.",
+                ));
+            };
+            verify_csrf(req.method(), req.headers(), &jar)?;
+            authenticate_session(&database, &jwt, cookie.value()).await?
+        }
+    };
+    if user.deleted_at.is_some() {
+        return Err(AppError::new(
+            StatusCode::UNAUTHORIZED,
+            "Not authenticated.",
+        ));
+    }
+    if user.disabled_at.is_some() {
+        return Err(AppError::new(StatusCode::FORBIDDEN, "Account disabled."));
+    }
+    req.extensions_mut().insert(user);
+    req.extensions_mut().insert(credential);
+    Ok(next.run(req).await)
+}
+
+/// Route layer that rejects callers whose role lacks `permission`, or whose access token
+/// lacks the matching scope. Must sit inside `check_authentication`.
+pub async fn require_permission(
+    State(permission): State<Permission>,
+    AuthUser(user): AuthUser,
+    credential: Credential,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    if !permission.granted_to(user.role) {
+        return Err(AppError::new(StatusCode::FORBIDDEN, "Action not allowed."));
+    }
+    let in_scope = match permission.scope() {
+        Some(scope) => credential.allows(scope),
+        None => matches!(credential, Credential::Session(_)),
+    };
+    if !in_scope {
+        return Err(AppError::new(
+            StatusCode::FORBIDDEN,
+            "Token is missing the required scope.",
+        ));
+    }
+    Ok(next.run(req).await)
+}
+
+async fn authenticate_session(
+    database: &DatabaseConnection,
+    jwt: &JwtConfig,
+    token: &str,
+) -> Result<(users::Model, Credential), AppError> {
+    let claims = decode_claims(jwt, token)?;
+    let now = Utc::now();
+    let session = Sessions::find_by_id(claims.sid)
+        .filter(sessions::Column::UserId.eq(claims.user_id()?))
+        .filter(sessions::Column::RevokedAt.is_null())
+        .filter(sessions::Column::ExpiresAt.gt(DateTimeWithTimeZone::from(now)))
+        .find_also_related(Users)
+        .one(database)
         .await
         .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
-    is_valid(&token)?;
 
-    let Some(user) = user else {
+    let Some((session, Some(user))) = session else {
         return Err(AppError::new(
             StatusCode::UNAUTHORIZED,
-            "Something went wrong".to_owned(),
+            "Session expired.".to_owned(),
         ));
     };
-    req.extensions_mut().insert(user);
-    Ok(next.run(req).await)
+    if is_stale(session.last_seen_at, now) {
+        Sessions::update_many()
+            .col_expr(
+                sessions::Column::LastSeenAt,
+                Expr::value(DateTimeWithTimeZone::from(now)),
+            )
+            .filter(sessions::Column::Id.eq(session.id))
+            .exec(database)
+            .await
+            .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+    }
+    Ok((user, Credential::Session(claims)))
+}
+
+async fn authenticate_access_token(
+    database: &DatabaseConnection,
+    token: &str,
+) -> Result<(users::Model, Credential), AppError> {
+    let now = Utc::now();
+    let access_token = PersonalAccessTokens::find()
+        .filter(personal_access_tokens::Column::TokenHash.eq(hash_token(token)))
+        .filter(personal_access_tokens::Column::RevokedAt.is_null())
+        .filter(
+            Condition::any()
+                .add(personal_access_tokens::Column::ExpiresAt.is_null())
+                .add(personal_access_tokens::Column::ExpiresAt.gt(DateTimeWithTimeZone::from(now))),
+        )
+        .find_also_related(Users)
+        .one(database)
+        .await
+        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+
+    let Some((access_token, Some(user))) = access_token else {
+        return Err(AppError::new(StatusCode::UNAUTHORIZED, "Invalid token."));
+    };
+    if access_token
+        .last_used_at
+        .is_none_or(|last_used_at| is_stale(last_used_at, now))
+    {
+        PersonalAccessTokens::update_many()
+            .col_expr(
+                personal_access_tokens::Column::LastUsedAt,
+                Expr::value(DateTimeWithTimeZone::from(now)),
+            )
+            .filter(personal_access_tokens::Column::Id.eq(access_token.id))
+            .exec(database)
+            .await
+            .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+    }
+    let scopes = access_token
+        .scopes
+        .iter()
+        .filter_map(|scope| Scope::parse(scope))
+        .collect();
+    Ok((user, Credential::AccessToken { scopes }))
+}
+
+fn is_stale(timestamp: DateTimeWithTimeZone, now: DateTime<Utc>) -> bool {
+    now - timestamp.to_utc() > LAST_SEEN_RESOLUTION
 }
@@ -0,0 +1,595 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use axum_extra::extract::CookieJar;
+use chrono::{Duration, Utc};
+use sea_orm::{
+    prelude::DateTimeWithTimeZone,
+    sea_query::{Expr, Func},
+    ActiveEnum, ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseConnection, EntityTrait,
+    IntoActiveModel, QueryFilter, QueryOrder, Set, SqlErr, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+use uuid::Uuid;
+use validator::Validate;
+
+use super::account::send_in_background;
+use super::cookie::deliver_tokens;
+use super::guard::{AuthUser, SessionClaims};
+use super::session::{start_session, ClientInfo};
+use super::user::UserResponse;
+use super::workspace::{create_personal_workspace, is_privileged, ActiveWorkspace};
+use crate::config::{AccountConfig, AuthConfig};
+use crate::database::{
+    prelude::{Users, WorkspaceInvites, WorkspaceMemberships, Workspaces},
+    sea_orm_active_enums::WorkspaceRole,
+    users, workspace_invites, workspace_memberships, workspaces,
+};
+use crate::utils::app_error::AppError;
+use crate::utils::jwt::{create_challenge_token, decode_challenge_token, JwtConfig};
+use crate::utils::mailer::{Email, Mailer};
+use crate::utils::password::PasswordHasher;
+use crate::utils::password_policy::PasswordPolicy;
+use crate::utils::permission::WorkspacePermission;
+
+const INVITE_PURPOSE: &str = "workspace_invite";
+
+/// How long to wait before the same invite can be emailed again.
+const RESEND_COOLDOWN: Duration = Duration::minutes(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InviteStatus {
+    Pending,
+    Accepted,
+    Revoked,
+    Expired,
+}
+
+impl InviteStatus {
+    fn of(invite: &workspace_invites::Model) -> Self {
+        match (invite.accepted_at, invite.revoked_at) {
+            (Some(_), _) => InviteStatus::Accepted,
+            (None, Some(_)) => InviteStatus::Revoked,
+            (None, None) if invite.expires_at <= Utc::now() => InviteStatus::Expired,
+            (None, None) => InviteStatus::Pending,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct InviteResponse {
+    id: Uuid,
+    email: String,
+    role: WorkspaceRole,
+    status: InviteStatus,
+    invited_by: Option<i32>,
+    accepted_by: Option<i32>,
+    created_at: DateTimeWithTimeZone,
+    sent_at: DateTimeWithTimeZone,
+    expires_at: DateTimeWithTimeZone,
+    /// The link that was just emailed, for inviters who'd rather share it themselves.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<String>,
+}
+
+impl InviteResponse {
+    fn new(invite: workspace_invites::Model, link: Option<String>) -> Self {
+        InviteResponse {
+            status: InviteStatus::of(&invite),
+            id: invite.id,
+            email: invite.email,
+            role: invite.role,
+            invited_by: invite.invited_by,
+            accepted_by: invite.accepted_by,
+            created_at: invite.created_at,
+            sent_at: invite.sent_at,
+            expires_at: invite.expires_at,
+            link,
+        }
+    }
+}
+
+/// What an invitee sees before deciding to sign in or register.
+#[derive(Serialize)]
+pub struct InvitePreview {
+    workspace_name: String,
+    email: String,
+    role: WorkspaceRole,
+    invited_by: Option<String>,
+    expires_at: DateTimeWithTimeZone,
+    /// Whether to offer signing in rather than registering.
+    account_exists: bool,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct InviteRequest {
+    #[validate(email)]
+    email: String,
+    role: WorkspaceRole,
+}
+
+#[derive(Deserialize)]
+pub struct InvitePath {
+    invite_id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct InviteTokenParams {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct AcceptInviteRequest {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterFromInviteRequest {
+    token: String,
+    password: String,
+}
+
+/// Invites an email address into the workspace and mails it a sign-up link. Only owners can
+/// invite owners and admins.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(workspace_id = active.workspace.id))]
+pub async fn create_invite(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    State(mailer): State<Arc<dyn Mailer>>,
+    State(account): State<AccountConfig>,
+    AuthUser(user): AuthUser,
+    active: ActiveWorkspace,
+    _session: SessionClaims,
+    Json(request): Json<InviteRequest>,
+) -> Result<(StatusCode, Json<InviteResponse>), AppError> {
+    active.require(WorkspacePermission::ManageMembers)?;
+    if let Err(err) = request.validate() {
+        return Err(AppError::new(StatusCode::BAD_REQUEST, format!("{}", err)));
+    }
+    if active.role != WorkspaceRole::Owner && is_privileged(request.role) {
+        return Err(AppError::new(
+            StatusCode::FORBIDDEN,
+            "Only owners can invite owners and admins.",
+        ));
+    }
+    let email = request.email.trim().to_owned();
+    if is_member(&database, active.workspace.id, &email).await? {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            "This address already belongs to a member.",
+        ));
+    }
+    let open_invite = WorkspaceInvites::find()
+        .filter(workspace_invites::Column::WorkspaceId.eq(active.workspace.id))
+        .filter(lower_email_is(&email))
+        .filter(workspace_invites::Column::AcceptedAt.is_null())
+        .filter(workspace_invites::Column::RevokedAt.is_null())
+        .one(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if open_invite.is_some() {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            "This address already has an open invitation; resend or revoke it instead.",
+        ));
+    }
+
+    let now = Utc::now();
+    let invite = workspace_invites::ActiveModel {
+        id: Set(Uuid::new_v4()),
+        workspace_id: Set(active.workspace.id),
+        email: Set(email),
+        role: Set(request.role),
+        invited_by: Set(Some(user.id)),
+        created_at: Set(now.into()),
+        sent_at: Set(now.into()),
+        expires_at: Set((now + account.invite_ttl).into()),
+        accepted_at: Set(None),
+        accepted_by: Set(None),
+        revoked_at: Set(None),
+    }
+    .insert(&database)
+    .await
+    .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let link = send_invite(&jwt, mailer, &account, &invite, &active.workspace, &user)?;
+    info!(invite_id = %invite.id, "invite sent");
+    Ok((
+        StatusCode::CREATED,
+        Json(InviteResponse::new(invite, Some(link))),
+    ))
+}
+
+#[instrument(skip_all, fields(workspace_id = active.workspace.id))]
+pub async fn get_invites(
+    State(database): State<DatabaseConnection>,
+    active: ActiveWorkspace,
+) -> Result<Json<Vec<InviteResponse>>, AppError> {
+    active.require(WorkspacePermission::ManageMembers)?;
+    let invites = WorkspaceInvites::find()
+        .filter(workspace_invites::Column::WorkspaceId.eq(active.workspace.id))
+        .order_by_desc(workspace_invites::Column::CreatedAt)
+        .all(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .into_iter()
+        .map(|invite| InviteResponse::new(invite, None))
+        .collect();
+    Ok(Json(invites))
+}
+
+/// Emails the invite again with a fresh link and expiry. Links sent earlier keep working
+/// until they expire or one of them is used.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(workspace_id = active.workspace.id, invite_id = %path.invite_id))]
+pub async fn resend_invite(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    State(mailer): State<Arc<dyn Mailer>>,
+    State(account): State<AccountConfig>,
+    AuthUser(user): AuthUser,
+    active: ActiveWorkspace,
+    _session: SessionClaims,
+    Path(path): Path<InvitePath>,
+) -> Result<Json<InviteResponse>, AppError> {
+    active.require(WorkspacePermission::ManageMembers)?;
+    let invite = find_invite(&database, active.workspace.id, path.invite_id).await?;
+    ensure_open(&invite)?;
+    if active.role != WorkspaceRole::Owner && is_privileged(invite.role) {
+        return Err(AppError::new(
+            StatusCode::FORBIDDEN,
+            "Only owners can resend invitations for owners and admins.",
+        ));
+    }
+    let now = Utc::now();
+    if invite.sent_at > now - RESEND_COOLDOWN {
+        return Err(AppError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "This invitation was just sent; wait a minute before resending it.",
+        ));
+    }
+    let mut invite = invite.into_active_model();
+    invite.sent_at = Set(now.into());
+    invite.expires_at = Set((now + account.invite_ttl).into());
+    let invite = invite
+        .update(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let link = send_invite(&jwt, mailer, &account, &invite, &active.workspace, &user)?;
+    info!("invite resent");
+    Ok(Json(InviteResponse::new(invite, Some(link))))
+}
+
+/// Withdraws an invite so its links stop working. Accepted invites can't be revoked; remove
+/// the member instead.
+#[instrument(skip_all, fields(workspace_id = active.workspace.id, invite_id = %path.invite_id))]
+pub async fn revoke_invite(
+    State(database): State<DatabaseConnection>,
+    active: ActiveWorkspace,
+    _session: SessionClaims,
+    Path(path): Path<InvitePath>,
+) -> Result<(), AppError> {
+    active.require(WorkspacePermission::ManageMembers)?;
+    let invite = find_invite(&database, active.workspace.id, path.invite_id).await?;
+    if active.role != WorkspaceRole::Owner && is_privileged(invite.role) {
+        return Err(AppError::new(
+            StatusCode::FORBIDDEN,
+            "Only owners can revoke invitations for owners and admins.",
+        ));
+    }
+    match InviteStatus::of(&invite) {
+        InviteStatus::Accepted => {
+            return Err(AppError::new(
+                StatusCode::CONFLICT,
+                "This invitation was already accepted.",
+            ))
+        }
+        InviteStatus::Revoked => return Ok(()),
+        InviteStatus::Pending | InviteStatus::Expired => {}
+    }
+    let mut invite = invite.into_active_model();
+    invite.revoked_at = Set(Some(Utc::now().into()));
+    invite
+        .update(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!("invite revoked");
+    Ok(())
+}
+
+/// Describes the invite behind a link, without consuming it.
+#[instrument(skip_all)]
+pub async fn preview_invite(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    Query(params): Query<InviteTokenParams>,
+) -> Result<Json<InvitePreview>, AppError> {
+    let invite = find_open_invite(&database, &jwt, &params.token).await?;
+    let workspace = Workspaces::find_by_id(invite.workspace_id)
+        .one(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or_else(invalid_invite)?;
+    let invited_by = match invite.invited_by {
+        Some(user_id) => Users::find_by_id(user_id)
+            .filter(users::Column::DeletedAt.is_null())
+            .one(&database)
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+            .map(|user| user.display_name.unwrap_or(user.username)),
+        None => None,
+    };
+    let account_exists = find_user_by_email(&database, &invite.email)
+        .await?
+        .is_some();
+    Ok(Json(InvitePreview {
+        workspace_name: workspace.name,
+        email: invite.email,
+        role: invite.role,
+        invited_by,
+        expires_at: invite.expires_at,
+        account_exists,
+    }))
+}
+
+/// Joins the workspace with the signed-in account, which must be the one the invite was
+/// sent to.
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn accept_invite(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+    Json(request): Json<AcceptInviteRequest>,
+) -> Result<StatusCode, AppError> {
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let invite = find_open_invite(&txn, &jwt, &request.token).await?;
+    if !invite.email.eq_ignore_ascii_case(&user.username) {
+        return Err(AppError::new(
+            StatusCode::FORBIDDEN,
+            "This invitation was sent to a different email address.",
+        ));
+    }
+    let invite = claim_invite(&txn, invite, user.id).await?;
+    // Following the emailed link proves the address works.
+    if user.email_verified_at.is_none() {
+        let mut user = user.into_active_model();
+        user.email_verified_at = Set(Some(Utc::now().into()));
+        user.update(&txn)
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    }
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(workspace_id = invite.workspace_id, invite_id = %invite.id, "invite accepted");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Creates an account for the invited address and joins the workspace in one go, then signs
+/// the new account in.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all)]
+pub async fn register_from_invite(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    State(auth): State<AuthConfig>,
+    State(hasher): State<PasswordHasher>,
+    State(password_policy): State<PasswordPolicy>,
+    client: ClientInfo,
+    jar: CookieJar,
+    Json(request): Json<RegisterFromInviteRequest>,
+) -> Result<(CookieJar, Json<UserResponse>), AppError> {
+    let invite = find_open_invite(&database, &jwt, &request.token).await?;
+    password_policy.check(&request.password, Some(&invite.email))?;
+    let password = hasher.hash(request.password).await?;
+
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if find_user_by_email(&txn, &invite.email).await?.is_some() {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            "An account with this address already exists; sign in to accept the invitation.",
+        ));
+    }
+    let user = users::ActiveModel {
+        username: Set(invite.email.clone()),
+        password: Set(Some(password)),
+        email_verified_at: Set(Some(Utc::now().into())),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await
+    .map_err(|err| match err.sql_err() {
+        // Deleted accounts keep their address until they are purged.
+        Some(SqlErr::UniqueConstraintViolation(_)) => AppError::new(
+            StatusCode::CONFLICT,
+            "This address belongs to an account that was recently deleted or is being \
+             registered; try again later.",
+        ),
+        _ => AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    })?;
+    create_personal_workspace(&txn, user.id).await?;
+    let invite = claim_invite(&txn, invite, user.id).await?;
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(
+        user_id = user.id,
+        workspace_id = invite.workspace_id,
+        "registered from invite"
+    );
+    let tokens = start_session(&database, &jwt, user.id, client).await?;
+    let (jar, tokens) = deliver_tokens(jar, &auth, &jwt, tokens);
+    Ok((jar, Json(UserResponse::signed_in(user, tokens))))
+}
+
+/// Signs a link for the invite and emails it in the background. Returns the link.
+fn send_invite(
+    jwt: &JwtConfig,
+    mailer: Arc<dyn Mailer>,
+    account: &AccountConfig,
+    invite: &workspace_invites::Model,
+    workspace: &workspaces::Model,
+    inviter: &users::Model,
+) -> Result<String, AppError> {
+    let ttl = invite.expires_at.with_timezone(&Utc) - Utc::now();
+    let token = create_challenge_token(jwt, invite.workspace_id, INVITE_PURPOSE, invite.id, ttl)?;
+    let link = format!("{}/invites?token={token}", account.app_url);
+    let inviter = inviter.display_name.as_deref().unwrap_or(&inviter.username);
+    let email = Email {
+        to: invite.email.clone(),
+        subject: format!("You're invited to {}", workspace.name),
+        body: format!(
+            "{inviter} invited you to join the workspace \"{}\" as {}. The invitation is \
+             valid for {} days:\n\n{link}\n\nIf you weren't expecting it, you can ignore \
+             this email.",
+            workspace.name,
+            invite.role.to_value(),
+            account.invite_ttl.num_days(),
+        ),
+    };
+    send_in_background(mailer, email);
+    Ok(link)
+}
+
+/// Checks an invite link's signature and returns its invite if it can still be accepted.
+async fn find_open_invite(
+    database: &impl ConnectionTrait,
+    jwt: &JwtConfig,
+    token: &str,
+) -> Result<workspace_invites::Model, AppError> {
+    let claims = decode_challenge_token(jwt, token, INVITE_PURPOSE)?;
+    let id: Uuid = claims.jti.parse().map_err(|_| invalid_invite())?;
+    let workspace_id: i32 = claims.sub.parse().map_err(|_| invalid_invite())?;
+    WorkspaceInvites::find_by_id(id)
+        .filter(workspace_invites::Column::WorkspaceId.eq(workspace_id))
+        .one(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .filter(|invite| InviteStatus::of(invite) == InviteStatus::Pending)
+        .ok_or_else(invalid_invite)
+}
+
+/// Marks the invite accepted and adds the membership. The update only matches an invite
+/// that's still open, so of two concurrent acceptances one fails.
+async fn claim_invite(
+    txn: &impl ConnectionTrait,
+    invite: workspace_invites::Model,
+    user_id: i32,
+) -> Result<workspace_invites::Model, AppError> {
+    let now = DateTimeWithTimeZone::from(Utc::now());
+    let result = WorkspaceInvites::update_many()
+        .col_expr(workspace_invites::Column::AcceptedAt, Expr::value(now))
+        .col_expr(workspace_invites::Column::AcceptedBy, Expr::value(user_id))
+        .filter(workspace_invites::Column::Id.eq(invite.id))
+        .filter(workspace_invites::Column::AcceptedAt.is_null())
+        .filter(workspace_invites::Column::RevokedAt.is_null())
+        .filter(workspace_invites::Column::ExpiresAt.gt(now))
+        .exec(txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if result.rows_affected == 0 {
+        return Err(invalid_invite());
+    }
+    let existing = WorkspaceMemberships::find_by_id((invite.workspace_id, user_id))
+        .one(txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if existing.is_some() {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            "You're already a member of this workspace.",
+        ));
+    }
+    workspace_memberships::ActiveModel {
+        workspace_id: Set(invite.workspace_id),
+        user_id: Set(user_id),
+        role: Set(invite.role),
+        created_at: Set(now),
+    }
+    .insert(txn)
+    .await
+    .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(workspace_invites::Model {
+        accepted_at: Some(now),
+        accepted_by: Some(user_id),
+        ..invite
+    })
+}
+
+async fn find_invite(
+    database: &impl ConnectionTrait,
+    workspace_id: i32,
+    invite_id: Uuid,
+) -> Result<workspace_invites::Model, AppError> {
+    WorkspaceInvites::find_by_id(invite_id)
+        .filter(workspace_invites::Column::WorkspaceId.eq(workspace_id))
+        .one(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, "Invitation not found."))
+}
+
+fn ensure_open(invite: &workspace_invites::Model) -> Result<(), AppError> {
+    match InviteStatus::of(invite) {
+        InviteStatus::Accepted => Err(AppError::new(
+            StatusCode::CONFLICT,
+            "This invitation was already accepted.",
+        )),
+        InviteStatus::Revoked => Err(AppError::new(
+            StatusCode::CONFLICT,
+            "This invitation was revoked; send a new one instead.",
+        )),
+        InviteStatus::Pending | InviteStatus::Expired => Ok(()),
+    }
+}
+
+async fn is_member(
+    database: &impl ConnectionTrait,
+    workspace_id: i32,
+    email: &str,
+) -> Result<bool, AppError> {
+    let Some(user) = find_user_by_email(database, email).await? else {
+        return Ok(false);
+    };
+    WorkspaceMemberships::find_by_id((workspace_id, user.id))
+        .one(database)
+        .await
+        .map(|membership| membership.is_some())
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+/// Usernames are email addresses; invites match them case-insensitively.
+async fn find_user_by_email(
+    database: &impl ConnectionTrait,
+    email: &str,
+) -> Result<Option<users::Model>, AppError> {
+    Users::find()
+        .filter(
+            Expr::expr(Func::lower(Expr::col(users::Column::Username))).eq(email.to_lowercase()),
+        )
+        .filter(users::Column::DeletedAt.is_null())
+        .one(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+fn lower_email_is(email: &str) -> sea_orm::sea_query::SimpleExpr {
+    Expr::expr(Func::lower(Expr::col(workspace_invites::Column::Email))).eq(email.to_lowercase())
+}
+
+fn invalid_invite() -> AppError {
+    AppError::new(StatusCode::UNAUTHORIZED, "Invalid or expired invitation.")
+}
@@ -0,0 +1,404 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header::CONTENT_DISPOSITION, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::extract::CookieJar;
+use chrono::{Duration, Utc};
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, sea_query::Expr, ActiveModelTrait, ColumnTrait,
+    DatabaseConnection, EntityTrait, IntoActiveModel, PaginatorTrait, QueryFilter, QueryOrder, Set,
+    TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use super::cookie::clear_session_cookies;
+use super::guard::{AuthUser, SessionClaims};
+use super::mfa::claim_totp_step;
+use super::profile::delete_avatar_files;
+use super::session::{revoke_user_sessions, ClientInfo};
+use crate::database::{
+    account_tokens, login_attempts, oidc_auth_requests, passkeys, personal_access_tokens,
+    prelude::{
+        AccountTokens, LoginAttempts, OidcAuthRequests, Passkeys, PersonalAccessTokens,
+        RecoveryCodes, Sessions, TaskPriorities, Tasks, UserIdentities, WebauthnChallenges,
+        WorkspaceMemberships, Workspaces,
+    },
+    recovery_codes,
+    sea_orm_active_enums::{UserRole, WorkspaceRole},
+    sessions, tasks, user_identities, webauthn_challenges, workspace_memberships,
+};
+use crate::utils::app_error::AppError;
+use crate::utils::lockout::{account_key, LoginThrottle};
+use crate::utils::password::PasswordHasher;
+use crate::utils::storage::Storage;
+use crate::utils::totp::verify_code;
+
+/// How recently an account without a password must have signed in to delete itself.
+const REAUTHENTICATION_WINDOW: Duration = Duration::minutes(10);
+
+#[derive(Deserialize)]
+pub struct DeleteAccountRequest {
+    /// Required when the account has a password.
+    password: Option<String>,
+    /// Required when two-factor authentication is enabled.
+    code: Option<String>,
+}
+
+/// Everything stored about a user, minus secrets such as password and token hashes.
+#[derive(Serialize)]
+pub struct AccountExport {
+    exported_at: DateTimeWithTimeZone,
+    user: ExportedUser,
+    tasks: Vec<ExportedTask>,
+    sessions: Vec<ExportedSession>,
+    access_tokens: Vec<ExportedAccessToken>,
+    identities: Vec<ExportedIdentity>,
+    workspaces: Vec<ExportedMembership>,
+    passkeys: Vec<ExportedPasskey>,
+    unused_recovery_codes: u64,
+    login_attempts: Option<ExportedLoginAttempts>,
+}
+
+impl IntoResponse for AccountExport {
+    fn into_response(self) -> Response {
+        let disposition = format!("attachment; filename=\"account-{}.json\"", self.user.id);
+        ([(CONTENT_DISPOSITION, disposition)], Json(self)).into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct ExportedUser {
+    id: i32,
+    username: String,
+    role: UserRole,
+    has_password: bool,
+    email_verified_at: Option<DateTimeWithTimeZone>,
+    totp_enabled_at: Option<DateTimeWithTimeZone>,
+    disabled_at: Option<DateTimeWithTimeZone>,
+    display_name: Option<String>,
+    timezone: String,
+    locale: String,
+    avatar_updated_at: Option<DateTimeWithTimeZone>,
+}
+
+#[derive(Serialize)]
+struct ExportedTask {
+    id: i32,
+    title: String,
+    description: Option<String>,
+    priority: Option<String>,
+    completed_at: Option<DateTimeWithTimeZone>,
+    deleted_at: Option<DateTimeWithTimeZone>,
+    is_default: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct ExportedSession {
+    id: Uuid,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    created_at: DateTimeWithTimeZone,
+    last_seen_at: DateTimeWithTimeZone,
+    expires_at: DateTimeWithTimeZone,
+    revoked_at: Option<DateTimeWithTimeZone>,
+}
+
+#[derive(Serialize)]
+struct ExportedAccessToken {
+    id: i32,
+    name: String,
+    scopes: Vec<String>,
+    created_at: DateTimeWithTimeZone,
+    expires_at: Option<DateTimeWithTimeZone>,
+    last_used_at: Option<DateTimeWithTimeZone>,
+    revoked_at: Option<DateTimeWithTimeZone>,
+}
+
+#[derive(Serialize)]
+struct ExportedIdentity {
+    provider: String,
+    subject: String,
+    email: Option<String>,
+    created_at: DateTimeWithTimeZone,
+}
+
+#[derive(Serialize)]
+struct ExportedMembership {
+    workspace_id: i32,
+    name: String,
+    role: WorkspaceRole,
+    joined_at: DateTimeWithTimeZone,
+}
+
+#[derive(Serialize)]
+struct ExportedPasskey {
+    name: String,
+    transports: Vec<String>,
+    sign_count: i64,
+    created_at: DateTimeWithTimeZone,
+    last_used_at: Option<DateTimeWithTimeZone>,
+}
+
+#[derive(Serialize)]
+struct ExportedLoginAttempts {
+    failures: i32,
+    window_started_at: DateTimeWithTimeZone,
+    locked_until: Option<DateTimeWithTimeZone>,
+}
+
+/// Soft-deletes the caller's account and signs it out everywhere. The account disappears
+/// at once and is purged for good after the grace period.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn delete_me(
+    State(database): State<DatabaseConnection>,
+    State(hasher): State<PasswordHasher>,
+    State(throttle): State<LoginThrottle>,
+    State(storage): State<Arc<dyn Storage>>,
+    AuthUser(user): AuthUser,
+    SessionClaims(claims): SessionClaims,
+    client: ClientInfo,
+    jar: CookieJar,
+    Json(request): Json<DeleteAccountRequest>,
+) -> Result<CookieJar, AppError> {
+    throttle.check(&user.username, client.ip.as_deref()).await?;
+    if let Some(password_hash) = user.password.clone() {
+        let password = request.password.unwrap_or_default();
+        if !hasher.verify(password, password_hash).await?.is_valid() {
+            throttle
+                .record_failure(&user.username, client.ip.as_deref())
+                .await?;
+            return Err(AppError::new(
+                StatusCode::UNAUTHORIZED,
+                "Wrong credentials.",
+            ));
+        }
+    } else {
+        let session = Sessions::find_by_id(claims.sid)
+            .one(&database)
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        let fresh = session.is_some_and(|session| {
+            Utc::now() - session.created_at.to_utc() < REAUTHENTICATION_WINDOW
+        });
+        if !fresh {
+            return Err(AppError::new(
+                StatusCode::UNAUTHORIZED,
+                "Sign in again to confirm it's you.",
+            ));
+        }
+    }
+    if let Some(secret) = user
+        .totp_secret
+        .as_deref()
+        .filter(|_| user.totp_enabled_at.is_some())
+    {
+        let code = request.code.unwrap_or_default();
+        let Some(step) = verify_code(secret, &code, Utc::now().timestamp())? else {
+            return Err(AppError::new(StatusCode::UNAUTHORIZED, "Invalid code."));
+        };
+        claim_totp_step(&database, user.id, step).await?;
+    }
+
+    let now = DateTimeWithTimeZone::from(Utc::now());
+    let user_id = user.id;
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let mut user = user.into_active_model();
+    user.deleted_at = Set(Some(now));
+    user.avatar_updated_at = Set(None);
+    user.update(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    revoke_user_sessions(&txn, user_id, None).await?;
+    PersonalAccessTokens::update_many()
+        .col_expr(personal_access_tokens::Column::RevokedAt, Expr::value(now))
+        .filter(personal_access_tokens::Column::UserId.eq(user_id))
+        .filter(personal_access_tokens::Column::RevokedAt.is_null())
+        .exec(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    AccountTokens::delete_many()
+        .filter(account_tokens::Column::UserId.eq(user_id))
+        .exec(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    OidcAuthRequests::delete_many()
+        .filter(oidc_auth_requests::Column::UserId.eq(user_id))
+        .exec(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    WebauthnChallenges::delete_many()
+        .filter(webauthn_challenges::Column::UserId.eq(user_id))
+        .exec(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    // Avatars are personal data with no use once the account is gone, so they don't wait
+    // for the purge.
+    if let Err(error) = delete_avatar_files(storage.as_ref(), user_id).await {
+        warn!(?error, "could not delete avatar");
+    }
+    info!("account deleted");
+    Ok(clear_session_cookies(jar))
+}
+
+/// Everything stored about the caller as a downloadable JSON document.
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn export_me(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+) -> Result<AccountExport, AppError> {
+    let tasks = Tasks::find()
+        .filter(tasks::Column::UserId.eq(user.id))
+        .order_by_asc(tasks::Column::Id)
+        .find_also_related(TaskPriorities)
+        .all(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .into_iter()
+        .map(|(task, priority)| ExportedTask {
+            id: task.id,
+            title: task.title,
+            description: task.description,
+            priority: priority.map(|priority| priority.key),
+            completed_at: task.completed_at,
+            deleted_at: task.deleted_at,
+            is_default: task.is_default,
+        })
+        .collect();
+    let sessions = Sessions::find()
+        .filter(sessions::Column::UserId.eq(user.id))
+        .order_by_asc(sessions::Column::CreatedAt)
+        .all(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .into_iter()
+        .map(|session| ExportedSession {
+            id: session.id,
+            user_agent: session.user_agent,
+            ip: session.ip,
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+            expires_at: session.expires_at,
+            revoked_at: session.revoked_at,
+        })
+        .collect();
+    let access_tokens = PersonalAccessTokens::find()
+        .filter(personal_access_tokens::Column::UserId.eq(user.id))
+        .order_by_asc(personal_access_tokens::Column::Id)
+        .all(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .into_iter()
+        .map(|token| ExportedAccessToken {
+            id: token.id,
+            name: token.name,
+            scopes: token.scopes,
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+            last_used_at: token.last_used_at,
+            revoked_at: token.revoked_at,
+        })
+        .collect();
+    let identities = UserIdentities::find()
+        .filter(user_identities::Column::UserId.eq(user.id))
+        .order_by_asc(user_identities::Column::Id)
+        .all(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .into_iter()
+        .map(|identity| ExportedIdentity {
+            provider: identity.provider,
+            subject: identity.subject,
+            email: identity.email,
+            created_at: identity.created_at,
+        })
+        .collect();
+    let workspaces = WorkspaceMemberships::find()
+        .filter(workspace_memberships::Column::UserId.eq(user.id))
+        .find_also_related(Workspaces)
+        .order_by_asc(workspace_memberships::Column::WorkspaceId)
+        .all(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .into_iter()
+        .filter_map(|(membership, workspace)| {
+            workspace.map(|workspace| ExportedMembership {
+                workspace_id: workspace.id,
+                name: workspace.name,
+                role: membership.role,
+                joined_at: membership.created_at,
+            })
+        })
+        .collect();
+    let passkeys = Passkeys::find()
+        .filter(passkeys::Column::UserId.eq(user.id))
+        .order_by_asc(passkeys::Column::Id)
+        .all(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .into_iter()
+        .map(|passkey| ExportedPasskey {
+            name: passkey.name,
+            transports: passkey.transports,
+            sign_count: passkey.sign_count,
+            created_at: passkey.created_at,
+            last_used_at: passkey.last_used_at,
+        })
+        .collect();
+    let unused_recovery_codes = RecoveryCodes::find()
+        .filter(recovery_codes::Column::UserId.eq(user.id))
+        .filter(recovery_codes::Column::UsedAt.is_null())
+        .count(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let login_attempts = LoginAttempts::find()
+        .filter(login_attempts::Column::Key.eq(account_key(&user.username)))
+        .one(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .map(|attempts| ExportedLoginAttempts {
+            failures: attempts.failures,
+            window_started_at: attempts.window_started_at,
+            locked_until: attempts.locked_until,
+        });
+
+    let export = AccountExport {
+        exported_at: Utc::now().into(),
+        user: ExportedUser {
+            id: user.id,
+            username: user.username,
+            role: user.role,
+            has_password: user.password.is_some(),
+            email_verified_at: user.email_verified_at,
+            totp_enabled_at: user.totp_enabled_at,
+            disabled_at: user.disabled_at,
+            display_name: user.display_name,
+            timezone: user.timezone,
+            locale: user.locale,
+            avatar_updated_at: user.avatar_updated_at,
+        },
+        tasks,
+        sessions,
+        access_tokens,
+        identities,
+        workspaces,
+        passkeys,
+        unused_recovery_codes,
+        login_attempts,
+    };
+    info!("account exported");
+    Ok(export)
+}
@@ -0,0 +1,557 @@
+use axum::{extract::State, http::StatusCode, Json};
+use axum_extra::extract::CookieJar;
+use chrono::Utc;
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, sea_query::Expr, ActiveEnum, ActiveModelTrait, ColumnTrait,
+    ConnectionTrait, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter, Set,
+    TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use super::account::{consume_account_token, issue_account_token};
+use super::cookie::deliver_tokens;
+use super::guard::{AuthUser, SessionClaims};
+use super::session::{start_session, ClientInfo};
+use super::user::UserResponse;
+use crate::config::AuthConfig;
+use crate::database::{
+    prelude::{RecoveryCodes, Users},
+    recovery_codes,
+    sea_orm_active_enums::AccountTokenPurpose,
+    users,
+};
+use crate::utils::app_error::AppError;
+use crate::utils::jwt::{decode_challenge_token, JwtConfig};
+use crate::utils::lockout::LoginThrottle;
+use crate::utils::password::PasswordHasher;
+use crate::utils::token::hash_token;
+use crate::utils::totp::{
+    generate_recovery_codes, generate_secret, normalize_recovery_code, provisioning_uri,
+    verify_code, TotpConfig,
+};
+
+/// Either a finished sign-in, or the challenge to finish it with `POST /login/2fa`.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    SignedIn(UserResponse),
+    MfaRequired {
+        mfa_required: bool,
+        challenge_token: String,
+    },
+}
+
+#[derive(Serialize)]
+pub struct TotpEnrollmentResponse {
+    secret: String,
+    provisioning_uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpCodeRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodesResponse {
+    /// Shown once; only their hashes are kept.
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DisableTotpRequest {
+    /// Required when the account has a password.
+    password: Option<String>,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SecondFactorRequest {
+    challenge_token: String,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+/// Starts enrollment by storing a fresh secret. It only takes effect once confirmed.
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn enroll_totp(
+    State(database): State<DatabaseConnection>,
+    State(totp): State<TotpConfig>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+) -> Result<Json<TotpEnrollmentResponse>, AppError> {
+    if user.totp_enabled_at.is_some() {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            "Two-factor authentication is already enabled.",
+        ));
+    }
+    let secret = generate_secret();
+    let provisioning_uri = provisioning_uri(&secret, &totp.issuer, &user.username)?;
+    let mut user = user.into_active_model();
+    user.totp_secret = Set(Some(secret.clone()));
+    user.update(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(TotpEnrollmentResponse {
+        secret,
+        provisioning_uri,
+    }))
+}
+
+/// Enables two-factor authentication once the user proves their authenticator works.
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn confirm_totp(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+    Json(request): Json<TotpCodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>, AppError> {
+    if user.totp_enabled_at.is_some() {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            "Two-factor authentication is already enabled.",
+        ));
+    }
+    let Some(secret) = &user.totp_secret else {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "Two-factor enrollment has not been started.",
+        ));
+    };
+    let Some(step) = verify_code(secret, &request.code, Utc::now().timestamp())? else {
+        return Err(AppError::new(StatusCode::UNAUTHORIZED, "Invalid code."));
+    };
+
+    let recovery_codes = generate_recovery_codes();
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let user_id = user.id;
+    let mut user = user.into_active_model();
+    user.totp_enabled_at = Set(Some(Utc::now().into()));
+    user.totp_last_step = Set(Some(step));
+    user.update(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    replace_recovery_codes(&txn, user_id, &recovery_codes).await?;
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!("two-factor authentication enabled");
+    Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+/// Turns two-factor authentication off. Needs the password again as well as a current code
+/// or a recovery code, so neither a stolen session nor a leaked password can remove it. Wrong
+/// answers count towards the same lockout as sign-ins.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn disable_totp(
+    State(database): State<DatabaseConnection>,
+    State(hasher): State<PasswordHasher>,
+    State(throttle): State<LoginThrottle>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+    client: ClientInfo,
+    Json(request): Json<DisableTotpRequest>,
+) -> Result<(), AppError> {
+    let Some(secret) = user
+        .totp_secret
+        .as_deref()
+        .filter(|_| user.totp_enabled_at.is_some())
+    else {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "Two-factor authentication is not enabled.",
+        ));
+    };
+    let factor = SecondFactor::from_request(request.code, request.recovery_code)?;
+    throttle.check(&user.username, client.ip.as_deref()).await?;
+    if let Some(password_hash) = user.password.clone() {
+        let password = request.password.unwrap_or_default();
+        if !hasher.verify(password, password_hash).await?.is_valid() {
+            throttle
+                .record_failure(&user.username, client.ip.as_deref())
+                .await?;
+            return Err(AppError::new(
+                StatusCode::UNAUTHORIZED,
+                "Wrong credentials.",
+            ));
+        }
+    }
+
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if let Err(error) = spend_second_factor(&txn, user.id, secret, factor).await {
+        txn.rollback()
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        throttle
+            .record_failure(&user.username, client.ip.as_deref())
+            .await?;
+        return Err(error);
+    }
+    let user_id = user.id;
+    let mut user = user.into_active_model();
+    user.totp_secret = Set(None);
+    user.totp_enabled_at = Set(None);
+    user.totp_last_step = Set(None);
+    user.update(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    replace_recovery_codes(&txn, user_id, &[]).await?;
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!("two-factor authentication disabled");
+    Ok(())
+}
+
+/// Second login step: trades the challenge token and a TOTP or recovery code for a session.
+#[instrument(skip_all)]
+pub async fn login_second_factor(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    State(auth): State<AuthConfig>,
+    State(throttle): State<LoginThrottle>,
+    client: ClientInfo,
+    jar: CookieJar,
+    Json(request): Json<SecondFactorRequest>,
+) -> Result<(CookieJar, Json<UserResponse>), AppError> {
+    let claims = decode_challenge_token(
+        &jwt,
+        &request.challenge_token,
+        &AccountTokenPurpose::MfaChallenge.to_value(),
+    )?;
+    let user = Users::find_by_id(claims.user_id()?)
+        .filter(users::Column::DeletedAt.is_null())
+        .one(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or_else(|| AppError::new(StatusCode::UNAUTHORIZED, "Invalid token."))?;
+    if user.disabled_at.is_some() {
+        return Err(AppError::new(StatusCode::FORBIDDEN, "Account disabled."));
+    }
+    let Some(secret) = user
+        .totp_secret
+        .as_deref()
+        .filter(|_| user.totp_enabled_at.is_some())
+    else {
+        return Err(AppError::new(StatusCode::UNAUTHORIZED, "Invalid token."));
+    };
+    let factor = SecondFactor::from_request(request.code, request.recovery_code)?;
+    throttle.check(&user.username, client.ip.as_deref()).await?;
+
+    // The challenge is claimed before the code is spent, so a request that loses a race for
+    // it never burns a recovery code. A wrong code rolls the claim back and can be retried.
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    consume_account_token(
+        &txn,
+        &jwt,
+        &request.challenge_token,
+        AccountTokenPurpose::MfaChallenge,
+        None,
+    )
+    .await?;
+    if let Err(error) = spend_second_factor(&txn, user.id, secret, factor).await {
+        txn.rollback()
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        throttle
+            .record_failure(&user.username, client.ip.as_deref())
+            .await?;
+        return Err(error);
+    }
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    throttle.record_success(&user.username).await?;
+
+    let tokens = start_session(&database, &jwt, user.id, client).await?;
+    info!(user_id = user.id, "second factor accepted");
+    let (jar, tokens) = deliver_tokens(jar, &auth, &jwt, tokens);
+    Ok((jar, Json(UserResponse::signed_in(user, tokens))))
+}
+
+/// Finishes a sign-in whose first factor has been checked: users with two-factor
+/// authentication get a challenge, everyone else a session.
+pub async fn complete_sign_in(
+    database: &DatabaseConnection,
+    jwt: &JwtConfig,
+    totp: &TotpConfig,
+    auth: &AuthConfig,
+    jar: CookieJar,
+    user: users::Model,
+    client: ClientInfo,
+) -> Result<(CookieJar, LoginResponse), AppError> {
+    if user.totp_enabled_at.is_some() {
+        let challenge_token = issue_account_token(
+            database,
+            jwt,
+            user.id,
+            AccountTokenPurpose::MfaChallenge,
+            totp.challenge_ttl,
+            None,
+        )
+        .await?;
+        return Ok((
+            jar,
+            LoginResponse::MfaRequired {
+                mfa_required: true,
+                challenge_token,
+            },
+        ));
+    }
+    let tokens = start_session(database, jwt, user.id, client).await?;
+    let (jar, tokens) = deliver_tokens(jar, auth, jwt, tokens);
+    Ok((
+        jar,
+        LoginResponse::SignedIn(UserResponse::signed_in(user, tokens)),
+    ))
+}
+
+/// Records `step` as the last one used, failing if it (or a later one) already was, so each
+/// code only works once.
+pub async fn claim_totp_step(
+    database: &impl ConnectionTrait,
+    user_id: i32,
+    step: i64,
+) -> Result<(), AppError> {
+    let result = Users::update_many()
+        .col_expr(users::Column::TotpLastStep, Expr::value(step))
+        .filter(users::Column::Id.eq(user_id))
+        .filter(
+            users::Column::TotpLastStep
+                .is_null()
+                .or(users::Column::TotpLastStep.lt(step)),
+        )
+        .exec(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if result.rows_affected == 0 {
+        return Err(AppError::new(
+            StatusCode::UNAUTHORIZED,
+            "Code has already been used.",
+        ));
+    }
+    Ok(())
+}
+
+/// What a user proves their second factor with.
+enum SecondFactor {
+    Code(String),
+    RecoveryCode(String),
+}
+
+impl SecondFactor {
+    fn from_request(code: Option<String>, recovery_code: Option<String>) -> Result<Self, AppError> {
+        match (code, recovery_code) {
+            (Some(code), None) => Ok(SecondFactor::Code(code)),
+            (None, Some(recovery_code)) => Ok(SecondFactor::RecoveryCode(recovery_code)),
+            _ => Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                "Provide either a code or a recovery code.",
+            )),
+        }
+    }
+}
+
+/// Checks `factor` against the user's secret or recovery codes and uses it up.
+async fn spend_second_factor(
+    database: &impl ConnectionTrait,
+    user_id: i32,
+    secret: &str,
+    factor: SecondFactor,
+) -> Result<(), AppError> {
+    match factor {
+        SecondFactor::Code(code) => match verify_code(secret, &code, Utc::now().timestamp())? {
+            Some(step) => claim_totp_step(database, user_id, step).await,
+            None => Err(AppError::new(StatusCode::UNAUTHORIZED, "Invalid code.")),
+        },
+        SecondFactor::RecoveryCode(code) => use_recovery_code(database, user_id, &code).await,
+    }
+}
+
+async fn use_recovery_code(
+    database: &impl ConnectionTrait,
+    user_id: i32,
+    code: &str,
+) -> Result<(), AppError> {
+    let result = RecoveryCodes::update_many()
+        .col_expr(
+            recovery_codes::Column::UsedAt,
+            Expr::value(DateTimeWithTimeZone::from(Utc::now())),
+        )
+        .filter(recovery_codes::Column::UserId.eq(user_id))
+        .filter(recovery_codes::Column::CodeHash.eq(hash_token(&normalize_recovery_code(code))))
+        .filter(recovery_codes::Column::UsedAt.is_null())
+        .exec(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if result.rows_affected == 0 {
+        return Err(AppError::new(StatusCode::UNAUTHORIZED, "Invalid code."));
+    }
+    info!(user_id, "recovery code used");
+    Ok(())
+}
+
+async fn replace_recovery_codes(
+    database: &impl ConnectionTrait,
+    user_id: i32,
+    codes: &[String],
+) -> Result<(), AppError> {
+    RecoveryCodes::delete_many()
+        .filter(recovery_codes::Column::UserId.eq(user_id))
+        .exec(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if codes.is_empty() {
+        return Ok(());
+    }
+    RecoveryCodes::insert_many(codes.iter().map(|code| recovery_codes::ActiveModel {
+        user_id: Set(user_id),
+        code_hash: Set(hash_token(&normalize_recovery_code(code))),
+        ..Default::default()
+    }))
+    .exec(database)
+    .await
+    .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use serde_json::{json, Value};
+    use totp_rs::{Algorithm, Secret, TOTP};
+
+    use super::*;
+    use crate::test_support::{empty_request, json_request, TestApp, TestResponse};
+
+    const PASSWORD: &str = "Correct-Horse-9";
+
+    /// The code for `secret` `steps` 30-second steps from now.
+    fn code(secret: &str, steps: i64) -> String {
+        let secret = Secret::Encoded(secret.to_owned()).to_bytes().unwrap();
+        let totp = TOTP::new(Algorithm::SHA1, 6, 0, 30, secret, None, String::new()).unwrap();
+        totp.generate((Utc::now().timestamp() + steps * 30) as u64)
+    }
+
+    /// Signs up and turns two-factor authentication on, returning the bearer header value,
+    /// the secret and the recovery codes.
+    async fn enabled(app: &TestApp) -> (String, String, Vec<String>) {
+        let bearer = format!("Bearer {}", app.sign_up("ada@example.com").await);
+        let headers = [("authorization", bearer.as_str())];
+        let enrolled = app
+            .call(empty_request(Method::POST, "/2fa/totp", &headers))
+            .await;
+        let secret = enrolled.body["secret"].as_str().unwrap().to_owned();
+        let confirmed = app
+            .call(json_request(
+                Method::POST,
+                "/2fa/totp/confirm",
+                json!({ "code": code(&secret, 0) }),
+                &headers,
+            ))
+            .await;
+        assert_eq!(confirmed.status, StatusCode::OK, "{}", confirmed.body);
+        let recovery_codes = confirmed.body["recovery_codes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|code| code.as_str().unwrap().to_owned())
+            .collect();
+        (bearer, secret, recovery_codes)
+    }
+
+    async fn disable(app: &TestApp, bearer: &str, request: Value) -> TestResponse {
+        app.call(json_request(
+            Method::DELETE,
+            "/2fa/totp",
+            request,
+            &[("authorization", bearer)],
+        ))
+        .await
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn disabling_needs_the_password_and_a_second_factor() {
+        let app = TestApp::start(|_| {}).await;
+        let (bearer, secret, recovery_codes) = enabled(&app).await;
+
+        let response = disable(&app, &bearer, json!({ "password": PASSWORD })).await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.body["message"],
+            "Provide either a code or a recovery code."
+        );
+        let response = disable(
+            &app,
+            &bearer,
+            json!({ "password": "Wrong-Horse-9", "code": code(&secret, 1) }),
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response.body["message"], "Wrong credentials.");
+        let response = disable(
+            &app,
+            &bearer,
+            json!({ "password": PASSWORD, "recovery_code": "not-a-recovery-code" }),
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response.body["message"], "Invalid code.");
+
+        let response = disable(
+            &app,
+            &bearer,
+            json!({ "password": PASSWORD, "recovery_code": recovery_codes[0] }),
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        let response = disable(
+            &app,
+            &bearer,
+            json!({ "password": PASSWORD, "code": code(&secret, 1) }),
+        )
+        .await;
+        assert_eq!(
+            response.body["message"],
+            "Two-factor authentication is not enabled."
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn wrong_passwords_when_disabling_lead_to_a_lockout() {
+        let app = TestApp::start(|_| {}).await;
+        let (bearer, secret, _) = enabled(&app).await;
+
+        for _ in 0..5 {
+            let response = disable(
+                &app,
+                &bearer,
+                json!({ "password": "Wrong-Horse-9", "code": code(&secret, 1) }),
+            )
+            .await;
+            assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        }
+        let response = disable(
+            &app,
+            &bearer,
+            json!({ "password": PASSWORD, "code": code(&secret, 1) }),
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
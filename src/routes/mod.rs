@@ -1,46 +1,286 @@
+mod access_token;
+mod account;
+mod admin;
+mod auth;
+mod cookie;
 mod guard;
 mod health;
+mod invite;
+mod me;
+mod mfa;
+mod oidc;
+mod passkey;
+mod priority;
+mod profile;
+mod session;
 mod task;
 mod user;
+mod workspace;
 
+use std::{convert::Infallible, sync::Arc};
+
+use access_token::{create_access_token, get_my_access_tokens, revoke_access_token};
+use account::{
+    forgot_password, magic_link_login, request_magic_link, resend_verification_email,
+    reset_password, verify_email,
+};
+use admin::{disable_user, enable_user, list_users, set_user_role, unlock_user};
 use axum::{
-    extract::FromRef,
+    extract::{DefaultBodyLimit, FromRef, Request},
     middleware,
-    routing::{get, post},
+    response::Response,
+    routing::{delete, get, patch, post, put, MethodRouter, Route},
     Router,
 };
+use tower::{Layer, Service};
 
-use guard::check_authentication;
+use auth::{jwks, refresh};
+use guard::{check_authentication, require_permission};
 use health::heartbeat;
+use invite::{
+    accept_invite, create_invite, get_invites, preview_invite, register_from_invite, resend_invite,
+    revoke_invite,
+};
+use me::{delete_me, export_me};
+use mfa::{confirm_totp, disable_totp, enroll_totp, login_second_factor};
+use oidc::{get_my_identities, link_identity, oidc_callback, oidc_login, unlink_identity};
+use passkey::{
+    delete_passkey, finish_passkey_login, finish_passkey_registration, get_my_passkeys,
+    start_passkey_login, start_passkey_registration,
+};
+use priority::{get_priorities, update_priorities};
+use profile::{change_password, delete_avatar, get_avatar, get_me, update_me, upload_avatar};
 use sea_orm::DatabaseConnection;
+use session::{delete_other_sessions, delete_session, get_my_sessions};
+
+use crate::{
+    config::{AccountConfig, AuthConfig, Config},
+    utils::{
+        avatar::AvatarConfig,
+        jwt::JwtConfig,
+        lockout::LoginThrottle,
+        mailer::{build_mailer, Mailer},
+        oidc::OidcProviders,
+        passkey::Passkeys,
+        password::PasswordHasher,
+        password_policy::PasswordPolicy,
+        permission::Permission,
+        storage::{build_storage, Storage},
+        totp::TotpConfig,
+    },
+};
 use task::{
     atomic_task_update, create_task, delete_task, get_all_tasks, get_task, partial_task_update,
+    search_tasks,
 };
 use user::{create_user, get_all_users, login, logout};
+use workspace::{
+    create_workspace, delete_workspace, get_members, get_my_workspaces, get_workspace,
+    remove_member, update_member, update_workspace,
+};
 
 #[derive(Clone, FromRef)]
 pub struct AppState {
     pub database: DatabaseConnection,
+    pub jwt: JwtConfig,
+    pub oidc: OidcProviders,
+    pub totp: TotpConfig,
+    pub mailer: Arc<dyn Mailer>,
+    pub account: AccountConfig,
+    pub throttle: LoginThrottle,
+    pub password_policy: PasswordPolicy,
+    pub hasher: PasswordHasher,
+    pub auth: AuthConfig,
+    pub passkeys: Passkeys,
+    pub storage: Arc<dyn Storage>,
+    pub avatars: AvatarConfig,
 }
 
-pub async fn create_routes(database: DatabaseConnection) -> Router {
-    let app_state = AppState { database };
+pub async fn create_routes(database: DatabaseConnection, config: Config) -> Router {
+    let app_state = AppState {
+        throttle: LoginThrottle::new(config.lockout, database.clone()),
+        database,
+        jwt: config.jwt,
+        oidc: OidcProviders::new(config.oidc),
+        totp: config.totp,
+        mailer: build_mailer(config.mailer)
+            .unwrap_or_else(|error| panic!("Could not set up mailer: {error}")),
+        account: config.account,
+        auth: config.auth,
+        password_policy: config.password_policy,
+        hasher: PasswordHasher::new(config.password_hasher)
+            .unwrap_or_else(|error| panic!("Invalid password hashing parameters: {error}")),
+        passkeys: Passkeys::new(config.passkey)
+            .unwrap_or_else(|error| panic!("Invalid WebAuthn configuration: {error}")),
+        storage: build_storage(config.storage),
+        avatars: config.avatar,
+    };
+    let avatar_body_limit = DefaultBodyLimit::max(app_state.avatars.max_upload_bytes);
     Router::new()
-        .route("/health", get(heartbeat))
         .route("/logout", post(logout))
+        .route("/me", get(get_me).patch(update_me).delete(delete_me))
+        .route("/me/export", get(export_me))
+        .route("/me/password", post(change_password))
+        .route(
+            "/me/avatar",
+            put(upload_avatar)
+                .layer(avatar_body_limit)
+                .delete(delete_avatar),
+        )
+        .route("/users/:user_id/avatar", get(get_avatar))
+        .route(
+            "/sessions",
+            get(get_my_sessions).delete(delete_other_sessions),
+        )
+        .route("/sessions/:session_id", delete(delete_session))
+        .route(
+            "/tokens",
+            get(get_my_access_tokens).post(create_access_token),
+        )
+        .route("/tokens/:token_id", delete(revoke_access_token))
+        .route("/email/verify/send", post(resend_verification_email))
+        .route("/2fa/totp", post(enroll_totp).delete(disable_totp))
+        .route("/2fa/totp/confirm", post(confirm_totp))
+        .route("/passkeys", get(get_my_passkeys))
+        .route("/passkeys/register/start", post(start_passkey_registration))
+        .route(
+            "/passkeys/register/finish",
+            post(finish_passkey_registration),
+        )
+        .route("/passkeys/:passkey_id", delete(delete_passkey))
+        .route("/identities", get(get_my_identities))
+        .route("/identities/:provider/link", post(link_identity))
+        .route("/identities/:identity_id", delete(unlink_identity))
+        .route(
+            "/users",
+            get(get_all_users).route_layer(requires(Permission::ViewUsers)),
+        )
+        .route(
+            "/admin/users",
+            get(list_users).route_layer(requires(Permission::ManageUsers)),
+        )
+        .route(
+            "/admin/users/:user_id/disable",
+            post(disable_user).route_layer(requires(Permission::ManageUsers)),
+        )
+        .route(
+            "/admin/users/:user_id/enable",
+            post(enable_user).route_layer(requires(Permission::ManageUsers)),
+        )
+        .route(
+            "/admin/users/:user_id/unlock",
+            post(unlock_user).route_layer(requires(Permission::ManageUsers)),
+        )
+        .route(
+            "/admin/users/:user_id/role",
+            put(set_user_role).route_layer(requires(Permission::ManageUsers)),
+        )
+        .route(
+            "/workspaces",
+            get(get_my_workspaces)
+                .merge(post(create_workspace).route_layer(requires(Permission::ManageWorkspaces))),
+        )
+        .route(
+            "/workspaces/:workspace_id",
+            get(get_workspace).merge(
+                patch(update_workspace)
+                    .delete(delete_workspace)
+                    .route_layer(requires(Permission::ManageWorkspaces)),
+            ),
+        )
+        .route("/workspaces/:workspace_id/members", get(get_members))
+        .route(
+            "/workspaces/:workspace_id/members/:user_id",
+            put(update_member)
+                .route_layer(requires(Permission::ManageWorkspaces))
+                .delete(remove_member),
+        )
+        .route(
+            "/workspaces/:workspace_id/invites",
+            get(get_invites)
+                .merge(post(create_invite).route_layer(requires(Permission::ManageWorkspaces))),
+        )
+        .route(
+            "/workspaces/:workspace_id/invites/:invite_id",
+            delete(revoke_invite).route_layer(requires(Permission::ManageWorkspaces)),
+        )
+        .route(
+            "/workspaces/:workspace_id/invites/:invite_id/resend",
+            post(resend_invite).route_layer(requires(Permission::ManageWorkspaces)),
+        )
+        .route("/invites/accept", post(accept_invite))
+        .route(
+            "/workspaces/:workspace_id/priorities",
+            get(get_priorities)
+                .route_layer(requires(Permission::ReadTasks))
+                .merge(put(update_priorities).route_layer(requires(Permission::ManageWorkspaces))),
+        )
+        .route("/tasks", tasks_routes())
+        .route("/tasks/search", search_route())
+        .route("/tasks/:task_id", task_routes())
+        .route("/workspaces/:workspace_id/tasks", tasks_routes())
+        .route("/workspaces/:workspace_id/tasks/search", search_route())
+        .route("/workspaces/:workspace_id/tasks/:task_id", task_routes())
         .route_layer(middleware::from_fn_with_state(
             app_state.clone(),
             check_authentication,
         ))
+        .route("/health", get(heartbeat))
         .route("/login", post(login))
-        .route("/users", get(get_all_users).post(create_user))
-        .route("/tasks", get(get_all_tasks).post(create_task))
-        .route(
-            "/tasks/:task_id", // this can be middlewared
-            get(get_task)
-                .delete(delete_task)
+        .route("/login/2fa", post(login_second_factor))
+        .route("/login/magic-link", post(request_magic_link))
+        .route("/login/magic-link/verify", post(magic_link_login))
+        .route("/login/passkey/start", post(start_passkey_login))
+        .route("/login/passkey/finish", post(finish_passkey_login))
+        .route("/auth/refresh", post(refresh))
+        .route("/password/forgot", post(forgot_password))
+        .route("/password/reset", post(reset_password))
+        .route("/email/verify", get(verify_email))
+        .route("/.well-known/jwks.json", get(jwks))
+        .route("/auth/oidc/:provider/login", get(oidc_login))
+        .route("/auth/oidc/:provider/callback", get(oidc_callback))
+        .route("/users", post(create_user))
+        .route("/invites/preview", get(preview_invite))
+        .route("/invites/register", post(register_from_invite))
+        .with_state(app_state)
+}
+
+fn tasks_routes() -> MethodRouter<AppState> {
+    get(get_all_tasks)
+        .route_layer(requires(Permission::ReadTasks))
+        .merge(post(create_task).route_layer(requires(Permission::WriteTasks)))
+}
+
+fn search_route() -> MethodRouter<AppState> {
+    get(search_tasks).route_layer(requires(Permission::ReadTasks))
+}
+
+fn task_routes() -> MethodRouter<AppState> {
+    get(get_task)
+        .route_layer(requires(Permission::ReadTasks))
+        .merge(
+            delete(delete_task)
                 .put(atomic_task_update)
-                .patch(partial_task_update),
+                .patch(partial_task_update)
+                .route_layer(requires(Permission::WriteTasks)),
         )
-        .with_state(app_state)
+}
+
+/// Layer for a method router that only lets through callers granted `permission`.
+fn requires(
+    permission: Permission,
+) -> impl Layer<
+    Route,
+    Service = impl Service<
+        Request,
+        Response = Response,
+        Error = Infallible,
+        Future = impl Send + 'static,
+    > + Clone
+                  + Send
+                  + 'static,
+> + Clone
+       + Send
+       + 'static {
+    middleware::from_fn_with_state(permission, require_permission)
 }
@@ -0,0 +1,777 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+use axum_extra::extract::CookieJar;
+use chrono::{Duration, Utc};
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, ActiveModelTrait, ColumnTrait, ConnectionTrait,
+    DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder, Set,
+    TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use super::cookie::{set_oidc_state, take_oidc_state};
+use super::guard::{AuthUser, SessionClaims};
+use super::mfa::complete_sign_in;
+use super::session::ClientInfo;
+use super::workspace::create_personal_workspace;
+use crate::config::AuthConfig;
+use crate::database::{
+    oidc_auth_requests, passkeys,
+    prelude::{OidcAuthRequests, Passkeys, UserIdentities, Users},
+    user_identities, users,
+};
+use crate::utils::app_error::AppError;
+use crate::utils::jwt::JwtConfig;
+use crate::utils::oidc::{IdTokenClaims, OidcProviders, Pkce};
+use crate::utils::token::generate_opaque_token;
+use crate::utils::totp::TotpConfig;
+
+/// How long a user has to complete the provider's login page.
+const AUTH_REQUEST_TTL: Duration = Duration::minutes(10);
+
+#[derive(Deserialize)]
+pub struct CallbackParams {
+    state: String,
+    code: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AuthorizationResponse {
+    authorization_url: String,
+}
+
+#[derive(Serialize)]
+pub struct IdentityResponse {
+    id: i32,
+    provider: String,
+    email: Option<String>,
+    created_at: DateTimeWithTimeZone,
+}
+
+impl From<user_identities::Model> for IdentityResponse {
+    fn from(identity: user_identities::Model) -> Self {
+        IdentityResponse {
+            id: identity.id,
+            provider: identity.provider,
+            email: identity.email,
+            created_at: identity.created_at,
+        }
+    }
+}
+
+#[instrument(skip(database, oidc, auth, jar))]
+pub async fn oidc_login(
+    State(database): State<DatabaseConnection>,
+    State(oidc): State<OidcProviders>,
+    State(auth): State<AuthConfig>,
+    Path(provider): Path<String>,
+    jar: CookieJar,
+) -> Result<(CookieJar, Redirect), AppError> {
+    let (state, url) = begin_authorization(&database, &oidc, &provider, None).await?;
+    let jar = set_oidc_state(jar, &auth, state, AUTH_REQUEST_TTL);
+    Ok((jar, Redirect::to(&url)))
+}
+
+/// The returned URL only completes in the browser this was called from, so it can't be
+/// handed to someone else to link their identity to this account.
+#[instrument(skip(database, oidc, auth, user, _session, jar))]
+pub async fn link_identity(
+    State(database): State<DatabaseConnection>,
+    State(oidc): State<OidcProviders>,
+    State(auth): State<AuthConfig>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+    Path(provider): Path<String>,
+    jar: CookieJar,
+) -> Result<(CookieJar, Json<AuthorizationResponse>), AppError> {
+    let (state, authorization_url) =
+        begin_authorization(&database, &oidc, &provider, Some(user.id)).await?;
+    let jar = set_oidc_state(jar, &auth, state, AUTH_REQUEST_TTL);
+    Ok((jar, Json(AuthorizationResponse { authorization_url })))
+}
+
+/// Completes either a sign-in or a link, depending on how the flow was started.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(provider = %provider))]
+pub async fn oidc_callback(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    State(oidc): State<OidcProviders>,
+    State(totp): State<TotpConfig>,
+    State(auth): State<AuthConfig>,
+    Path(provider): Path<String>,
+    client: ClientInfo,
+    jar: CookieJar,
+    Query(params): Query<CallbackParams>,
+) -> Result<Response, AppError> {
+    let client_for_provider = oidc.get(&provider)?;
+    // Checked before the request is consumed, so a forged callback can't use it up either.
+    let (jar, same_browser) = take_oidc_state(jar, &params.state);
+    if !same_browser {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "This sign-in was started in another browser.",
+        ));
+    }
+    let auth_request = consume_auth_request(&database, &provider, &params.state).await?;
+    if let Some(error) = params.error {
+        return Err(AppError::new(
+            StatusCode::UNAUTHORIZED,
+            format!("Identity provider returned an error: {error}"),
+        ));
+    }
+    let Some(code) = params.code else {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "Missing authorization code.",
+        ));
+    };
+    let claims = client_for_provider
+        .exchange_code(&code, &auth_request.code_verifier, &auth_request.nonce)
+        .await?;
+
+    let existing = UserIdentities::find()
+        .filter(user_identities::Column::Provider.eq(&provider))
+        .filter(user_identities::Column::Subject.eq(&claims.sub))
+        .find_also_related(Users)
+        .one(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    if let Some(user_id) = auth_request.user_id {
+        if let Some((identity, _)) = existing {
+            if identity.user_id != user_id {
+                return Err(AppError::new(
+                    StatusCode::CONFLICT,
+                    "This identity is already linked to another account.",
+                ));
+            }
+            return Ok((jar, Json(IdentityResponse::from(identity))).into_response());
+        }
+        let identity = create_identity(&database, user_id, &provider, &claims).await?;
+        info!(user_id, "identity linked");
+        return Ok((
+            StatusCode::CREATED,
+            jar,
+            Json(IdentityResponse::from(identity)),
+        )
+            .into_response());
+    }
+
+    let user = match existing {
+        Some((_, Some(user))) if user.deleted_at.is_some() => {
+            return Err(AppError::new(
+                StatusCode::UNAUTHORIZED,
+                "This account has been deleted.",
+            ))
+        }
+        Some((_, Some(user))) => user,
+        Some((_, None)) => {
+            return Err(AppError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Identity has no user.",
+            ))
+        }
+        None => sign_up(&database, &provider, &claims).await?,
+    };
+    if user.disabled_at.is_some() {
+        return Err(AppError::new(StatusCode::FORBIDDEN, "Account disabled."));
+    }
+    let (jar, response) =
+        complete_sign_in(&database, &jwt, &totp, &auth, jar, user, client).await?;
+    Ok((jar, Json(response)).into_response())
+}
+
+#[instrument(skip(database, user))]
+pub async fn get_my_identities(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+) -> Result<Json<Vec<IdentityResponse>>, AppError> {
+    let identities = UserIdentities::find()
+        .filter(user_identities::Column::UserId.eq(user.id))
+        .order_by_asc(user_identities::Column::Id)
+        .all(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .into_iter()
+        .map(IdentityResponse::from)
+        .collect();
+    Ok(Json(identities))
+}
+
+#[instrument(skip(database, user, _session))]
+pub async fn unlink_identity(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+    Path(identity_id): Path<i32>,
+) -> Result<(), AppError> {
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let identity_count = UserIdentities::find()
+        .filter(user_identities::Column::UserId.eq(user.id))
+        .count(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let passkey_count = Passkeys::find()
+        .filter(passkeys::Column::UserId.eq(user.id))
+        .count(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if user.password.is_none() && identity_count <= 1 && passkey_count == 0 {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "You cannot unlink your only way to sign in.",
+        ));
+    }
+    let result = UserIdentities::delete_many()
+        .filter(user_identities::Column::Id.eq(identity_id))
+        .filter(user_identities::Column::UserId.eq(user.id))
+        .exec(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if result.rows_affected == 0 {
+        return Err(AppError::new(StatusCode::NOT_FOUND, "Identity not found."));
+    }
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(user_id = user.id, identity_id, "identity unlinked");
+    Ok(())
+}
+
+/// Records a pending authorization request and returns its state with the provider URL to
+/// send the user to.
+async fn begin_authorization(
+    database: &DatabaseConnection,
+    oidc: &OidcProviders,
+    provider: &str,
+    user_id: Option<i32>,
+) -> Result<(String, String), AppError> {
+    let client = oidc.get(provider)?;
+    let pkce = Pkce::generate();
+    let state = generate_opaque_token();
+    let nonce = generate_opaque_token();
+    let url = client
+        .authorization_url(&state, &nonce, &pkce.challenge)
+        .await?;
+
+    oidc_auth_requests::ActiveModel {
+        state: Set(state.clone()),
+        provider: Set(provider.to_owned()),
+        code_verifier: Set(pkce.verifier),
+        nonce: Set(nonce),
+        user_id: Set(user_id),
+        expires_at: Set((Utc::now() + AUTH_REQUEST_TTL).into()),
+    }
+    .insert(database)
+    .await
+    .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok((state, url))
+}
+
+/// Looks up and deletes the pending request for `state`, so each one can be redeemed once.
+async fn consume_auth_request(
+    database: &DatabaseConnection,
+    provider: &str,
+    state: &str,
+) -> Result<oidc_auth_requests::Model, AppError> {
+    let invalid_state = || AppError::new(StatusCode::BAD_REQUEST, "Invalid or expired state.");
+    let auth_request = OidcAuthRequests::find_by_id(state)
+        .filter(oidc_auth_requests::Column::Provider.eq(provider))
+        .one(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or_else(invalid_state)?;
+    let deleted = OidcAuthRequests::delete_many()
+        .filter(oidc_auth_requests::Column::State.eq(state))
+        .exec(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if deleted.rows_affected == 0 || auth_request.expires_at < Utc::now() {
+        return Err(invalid_state());
+    }
+    Ok(auth_request)
+}
+
+/// Creates a password-less account for a first-time provider sign-in. Existing accounts
+/// are never linked implicitly; their owner has to sign in and link the provider.
+async fn sign_up(
+    database: &DatabaseConnection,
+    provider: &str,
+    claims: &IdTokenClaims,
+) -> Result<users::Model, AppError> {
+    let Some(email) = claims.email.as_ref().filter(|_| claims.email_verified) else {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "Identity provider did not supply a verified email address.",
+        ));
+    };
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let taken = Users::find()
+        .filter(users::Column::Username.eq(email))
+        .count(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if taken > 0 {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            "An account with this email already exists. Sign in and link the provider instead.",
+        ));
+    }
+    let user = users::ActiveModel {
+        username: Set(email.clone()),
+        password: Set(None),
+        email_verified_at: Set(Some(Utc::now().into())),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await
+    .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    create_identity(&txn, user.id, provider, claims).await?;
+    create_personal_workspace(&txn, user.id).await?;
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(user_id = user.id, "account created from identity provider");
+    Ok(user)
+}
+
+async fn create_identity(
+    database: &impl ConnectionTrait,
+    user_id: i32,
+    provider: &str,
+    claims: &IdTokenClaims,
+) -> Result<user_identities::Model, AppError> {
+    user_identities::ActiveModel {
+        user_id: Set(user_id),
+        provider: Set(provider.to_owned()),
+        subject: Set(claims.sub.clone()),
+        email: Set(claims.email.clone()),
+        created_at: Set(Utc::now().into()),
+        ..Default::default()
+    }
+    .insert(database)
+    .await
+    .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        env, fs,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        extract::{Form, Query, State},
+        http::{header, Method},
+        routing::{get, post},
+        Router,
+    };
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use ed25519_dalek::pkcs8::{spki::der::pem::LineEnding, EncodePrivateKey};
+    use jsonwebtoken::{encode, Header};
+    use serde_json::{json, Map, Value};
+    use sha2::{Digest, Sha256};
+    use url::Url;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::routes::cookie::OIDC_STATE_COOKIE;
+    use crate::test_support::{empty_request, TestApp, TestResponse, APP_URL};
+    use crate::utils::keyring::Keyring;
+    use crate::utils::oidc::OidcProviderConfig;
+
+    const CLIENT_ID: &str = "axum_db";
+    const CLIENT_SECRET: &str = "client secret";
+    const EMAIL: &str = "grace@example.com";
+
+    /// An identity provider with just enough of OpenID Connect for the code flow with PKCE,
+    /// listening on a local port because the client reaches it over HTTP.
+    struct MockProvider {
+        issuer: String,
+        state: Arc<ProviderState>,
+    }
+
+    struct ProviderState {
+        issuer: String,
+        /// Signs ID tokens and is published in the JWKS.
+        key: Keyring,
+        /// Signs ID tokens when asked to, but is never published.
+        unknown_key: Keyring,
+        grants: Mutex<HashMap<String, Grant>>,
+        tweaks: Mutex<Tweaks>,
+    }
+
+    struct Grant {
+        client_id: String,
+        redirect_uri: String,
+        code_challenge: String,
+        nonce: String,
+    }
+
+    /// How the next ID tokens depart from valid ones.
+    #[derive(Default)]
+    struct Tweaks {
+        claims: Map<String, Value>,
+        unknown_key: bool,
+    }
+
+    #[derive(Deserialize)]
+    struct AuthorizeParams {
+        client_id: String,
+        redirect_uri: String,
+        state: String,
+        nonce: String,
+        code_challenge: String,
+        code_challenge_method: String,
+    }
+
+    #[derive(Deserialize)]
+    struct TokenParams {
+        grant_type: String,
+        code: String,
+        redirect_uri: String,
+        client_id: String,
+        client_secret: Option<String>,
+        code_verifier: String,
+    }
+
+    impl MockProvider {
+        async fn start() -> Self {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+            let state = Arc::new(ProviderState {
+                issuer: issuer.clone(),
+                key: generate_keyring("provider-key"),
+                unknown_key: generate_keyring("unknown-key"),
+                grants: Mutex::default(),
+                tweaks: Mutex::default(),
+            });
+            let router = Router::new()
+                .route("/.well-known/openid-configuration", get(discovery))
+                .route("/jwks", get(jwks))
+                .route("/authorize", get(authorize))
+                .route("/token", post(token))
+                .with_state(state.clone());
+            tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+            MockProvider { issuer, state }
+        }
+
+        fn config(&self) -> OidcProviderConfig {
+            OidcProviderConfig {
+                name: "mock".to_owned(),
+                issuer: self.issuer.clone(),
+                client_id: CLIENT_ID.to_owned(),
+                client_secret: Some(CLIENT_SECRET.to_owned()),
+                redirect_uri: format!("{APP_URL}/auth/oidc/mock/callback"),
+                scopes: "openid email".to_owned(),
+            }
+        }
+
+        fn tweak(&self, tweaks: Tweaks) {
+            *self.state.tweaks.lock().unwrap() = tweaks;
+        }
+
+        /// Plays the browser at the provider's login page: follows `authorization_url` and
+        /// returns the callback path the provider redirects back to.
+        async fn sign_in(&self, authorization_url: &str) -> String {
+            assert!(authorization_url.starts_with(&self.issuer));
+            let response = reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap()
+                .get(authorization_url)
+                .send()
+                .await
+                .unwrap();
+            assert!(response.status().is_redirection(), "{}", response.status());
+            let location = response.headers()[header::LOCATION].to_str().unwrap();
+            let callback = Url::parse(location).unwrap();
+            format!("{}?{}", callback.path(), callback.query().unwrap())
+        }
+    }
+
+    fn generate_keyring(kid: &str) -> Keyring {
+        let dir = env::temp_dir().join(format!("axum_db_oidc_{}", Uuid::new_v4().simple()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = ed25519_dalek::SigningKey::from_bytes(&rand::random());
+        let pem = key.to_pkcs8_pem(LineEnding::LF).unwrap();
+        fs::write(dir.join(format!("{kid}.pem")), pem.as_bytes()).unwrap();
+        Keyring::load_dir(&dir, kid).unwrap()
+    }
+
+    async fn discovery(State(provider): State<Arc<ProviderState>>) -> Json<Value> {
+        Json(json!({
+            "issuer": provider.issuer,
+            "authorization_endpoint": format!("{}/authorize", provider.issuer),
+            "token_endpoint": format!("{}/token", provider.issuer),
+            "jwks_uri": format!("{}/jwks", provider.issuer),
+        }))
+    }
+
+    async fn jwks(State(provider): State<Arc<ProviderState>>) -> Json<Value> {
+        Json(serde_json::to_value(provider.key.jwks()).unwrap())
+    }
+
+    /// Signs the user in straight away and sends them back with a code.
+    async fn authorize(
+        State(provider): State<Arc<ProviderState>>,
+        Query(params): Query<AuthorizeParams>,
+    ) -> Result<Redirect, StatusCode> {
+        if params.client_id != CLIENT_ID || params.code_challenge_method != "S256" {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        let code = Uuid::new_v4().to_string();
+        let mut callback = Url::parse(&params.redirect_uri).unwrap();
+        callback
+            .query_pairs_mut()
+            .append_pair("code", &code)
+            .append_pair("state", &params.state);
+        provider.grants.lock().unwrap().insert(
+            code,
+            Grant {
+                client_id: params.client_id,
+                redirect_uri: params.redirect_uri,
+                code_challenge: params.code_challenge,
+                nonce: params.nonce,
+            },
+        );
+        Ok(Redirect::to(callback.as_str()))
+    }
+
+    async fn token(
+        State(provider): State<Arc<ProviderState>>,
+        Form(params): Form<TokenParams>,
+    ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+        let invalid_grant = || {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "invalid_grant" })),
+            )
+        };
+        let grant = provider
+            .grants
+            .lock()
+            .unwrap()
+            .remove(&params.code)
+            .ok_or_else(invalid_grant)?;
+        let verifier_hash = URL_SAFE_NO_PAD.encode(Sha256::digest(params.code_verifier));
+        if params.grant_type != "authorization_code"
+            || params.client_id != grant.client_id
+            || params.client_secret.as_deref() != Some(CLIENT_SECRET)
+            || params.redirect_uri != grant.redirect_uri
+            || verifier_hash != grant.code_challenge
+        {
+            return Err(invalid_grant());
+        }
+
+        let now = Utc::now().timestamp();
+        let mut claims = json!({
+            "iss": provider.issuer,
+            "aud": grant.client_id,
+            "sub": "mock-user",
+            "email": EMAIL,
+            "email_verified": true,
+            "nonce": grant.nonce,
+            "iat": now,
+            "exp": now + 300,
+        });
+        let tweaks = provider.tweaks.lock().unwrap();
+        for (name, value) in &tweaks.claims {
+            claims[name] = value.clone();
+        }
+        let keyring = if tweaks.unknown_key {
+            &provider.unknown_key
+        } else {
+            &provider.key
+        };
+        let (kid, algorithm, key) = keyring.signing_key();
+        let mut header = Header::new(algorithm);
+        header.kid = Some(kid.to_owned());
+        let id_token = encode(&header, &claims, key).unwrap();
+        Ok(Json(json!({
+            "access_token": "provider access token",
+            "token_type": "Bearer",
+            "id_token": id_token,
+        })))
+    }
+
+    async fn start_app(provider: &MockProvider) -> TestApp {
+        let config = provider.config();
+        TestApp::start(|app| app.oidc = vec![config]).await
+    }
+
+    /// The authorization URL and state cookie from starting a sign-in.
+    async fn start_login(app: &TestApp) -> (String, String) {
+        let response = app
+            .call(empty_request(Method::GET, "/auth/oidc/mock/login", &[]))
+            .await;
+        assert_eq!(response.status, StatusCode::SEE_OTHER, "{}", response.body);
+        let location = response.headers[header::LOCATION].to_str().unwrap();
+        let state = response.cookie(OIDC_STATE_COOKIE).expect("state cookie");
+        (location.to_owned(), state)
+    }
+
+    async fn start_link(app: &TestApp, bearer: &str) -> (String, String) {
+        let response = app
+            .call(empty_request(
+                Method::POST,
+                "/identities/mock/link",
+                &[("authorization", bearer)],
+            ))
+            .await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        let authorization_url = response.body["authorization_url"].as_str().unwrap();
+        let state = response.cookie(OIDC_STATE_COOKIE).expect("state cookie");
+        (authorization_url.to_owned(), state)
+    }
+
+    async fn callback(app: &TestApp, callback: &str, state: Option<&str>) -> TestResponse {
+        let cookie = state.map(|state| format!("{OIDC_STATE_COOKIE}={state}"));
+        let headers: Vec<_> = cookie.iter().map(|c| ("cookie", c.as_str())).collect();
+        app.call(empty_request(Method::GET, callback, &headers))
+            .await
+    }
+
+    async fn sign_in(app: &TestApp, provider: &MockProvider) -> TestResponse {
+        let (authorization_url, state) = start_login(app).await;
+        let redirect = provider.sign_in(&authorization_url).await;
+        callback(app, &redirect, Some(&state)).await
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn signs_up_and_back_in_through_the_provider() {
+        let provider = MockProvider::start().await;
+        let app = start_app(&provider).await;
+        let (authorization_url, state) = start_login(&app).await;
+        let query: HashMap<_, _> = Url::parse(&authorization_url)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect();
+        assert_eq!(query["state"], state);
+        assert_eq!(query["code_challenge_method"], "S256");
+        assert_eq!(query["client_id"], CLIENT_ID);
+
+        let redirect = provider.sign_in(&authorization_url).await;
+        let signed_up = callback(&app, &redirect, Some(&state)).await;
+        assert_eq!(signed_up.status, StatusCode::OK, "{}", signed_up.body);
+        assert_eq!(signed_up.body["username"], EMAIL);
+        assert!(signed_up.body["token"].is_string());
+        assert_eq!(signed_up.cookie(OIDC_STATE_COOKIE), None);
+
+        let signed_in = sign_in(&app, &provider).await;
+        assert_eq!(signed_in.status, StatusCode::OK, "{}", signed_in.body);
+        assert_eq!(signed_in.body["id"], signed_up.body["id"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn links_and_unlinks_an_identity() {
+        let provider = MockProvider::start().await;
+        let app = start_app(&provider).await;
+        let bearer = format!("Bearer {}", app.sign_up("ada@example.com").await);
+        let authorized = [("authorization", bearer.as_str())];
+
+        let (authorization_url, state) = start_link(&app, &bearer).await;
+        let redirect = provider.sign_in(&authorization_url).await;
+        let linked = callback(&app, &redirect, Some(&state)).await;
+        assert_eq!(linked.status, StatusCode::CREATED, "{}", linked.body);
+        assert_eq!(linked.body["provider"], "mock");
+        assert_eq!(linked.body["email"], EMAIL);
+
+        // The provider now signs in to the linked account instead of creating one.
+        let signed_in = sign_in(&app, &provider).await;
+        assert_eq!(signed_in.status, StatusCode::OK, "{}", signed_in.body);
+        assert_eq!(signed_in.body["username"], "ada@example.com");
+
+        let identity = format!("/identities/{}", linked.body["id"]);
+        let unlinked = app
+            .call(empty_request(Method::DELETE, &identity, &authorized))
+            .await;
+        assert_eq!(unlinked.status, StatusCode::OK, "{}", unlinked.body);
+        let identities = app
+            .call(empty_request(Method::GET, "/identities", &authorized))
+            .await;
+        assert_eq!(identities.body, json!([]));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn rejects_a_callback_without_the_state_cookie() {
+        let provider = MockProvider::start().await;
+        let app = start_app(&provider).await;
+        let (authorization_url, state) = start_login(&app).await;
+        let redirect = provider.sign_in(&authorization_url).await;
+
+        for cookie in [None, Some("another-state")] {
+            let response = callback(&app, &redirect, cookie).await;
+            assert_eq!(response.status, StatusCode::BAD_REQUEST, "{cookie:?}");
+            assert_eq!(
+                response.body["message"],
+                "This sign-in was started in another browser."
+            );
+        }
+        // Neither attempt used up the request, but it only completes once.
+        let response = callback(&app, &redirect, Some(&state)).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        let replayed = callback(&app, &redirect, Some(&state)).await;
+        assert_eq!(replayed.status, StatusCode::BAD_REQUEST);
+        assert_eq!(replayed.body["message"], "Invalid or expired state.");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn rejects_invalid_id_tokens() {
+        let provider = MockProvider::start().await;
+        let app = start_app(&provider).await;
+        let claims = |claims: Value| Tweaks {
+            claims: claims.as_object().unwrap().clone(),
+            unknown_key: false,
+        };
+        let cases = [
+            ("wrong nonce", claims(json!({ "nonce": "another-nonce" }))),
+            ("wrong audience", claims(json!({ "aud": "another-client" }))),
+            (
+                "wrong issuer",
+                claims(json!({ "iss": "https://issuer.example" })),
+            ),
+            (
+                "expired",
+                claims(json!({ "exp": Utc::now().timestamp() - 3600 })),
+            ),
+            (
+                "unknown key",
+                Tweaks {
+                    claims: Map::new(),
+                    unknown_key: true,
+                },
+            ),
+        ];
+        for (case, tweaks) in cases {
+            provider.tweak(tweaks);
+            let response = sign_in(&app, &provider).await;
+            assert_eq!(response.status, StatusCode::UNAUTHORIZED, "{case}");
+            assert_eq!(response.body["message"], "Invalid ID token.", "{case}");
+        }
+
+        provider.tweak(Tweaks::default());
+        let response = sign_in(&app, &provider).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+    }
+}
@@ -0,0 +1,620 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use axum_extra::extract::CookieJar;
+use chrono::Utc;
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, sea_query::Expr, ActiveModelTrait, ColumnTrait,
+    DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder, Set,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+use webauthn_rs::prelude::{
+    AuthenticationResult, CreationChallengeResponse, Credential, CredentialID,
+    DiscoverableAuthentication, Passkey, PasskeyRegistration, PublicKeyCredential,
+    RegisterPublicKeyCredential, RequestChallengeResponse, WebauthnError,
+};
+
+use super::cookie::deliver_tokens;
+use super::guard::{AuthUser, SessionClaims};
+use super::session::{start_session, ClientInfo};
+use super::user::UserResponse;
+use crate::config::AuthConfig;
+use crate::database::{
+    passkeys,
+    prelude::{Passkeys, UserIdentities, Users, WebauthnChallenges},
+    sea_orm_active_enums::WebauthnCeremony,
+    user_identities, webauthn_challenges,
+};
+use crate::utils::app_error::AppError;
+use crate::utils::jwt::JwtConfig;
+use crate::utils::passkey::{ceremony_error, Passkeys as PasskeyRelyingParty};
+
+const MAX_NAME_LENGTH: usize = 100;
+
+/// Options for `navigator.credentials`, and the id to send back with the result.
+#[derive(Serialize)]
+pub struct CeremonyResponse<T> {
+    challenge_id: Uuid,
+    options: T,
+}
+
+#[derive(Deserialize)]
+pub struct FinishRegistrationRequest {
+    challenge_id: Uuid,
+    name: Option<String>,
+    credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Deserialize)]
+pub struct FinishLoginRequest {
+    challenge_id: Uuid,
+    credential: PublicKeyCredential,
+}
+
+#[derive(Serialize)]
+pub struct PasskeyResponse {
+    id: i32,
+    name: String,
+    transports: Vec<String>,
+    created_at: DateTimeWithTimeZone,
+    last_used_at: Option<DateTimeWithTimeZone>,
+}
+
+impl From<passkeys::Model> for PasskeyResponse {
+    fn from(passkey: passkeys::Model) -> Self {
+        PasskeyResponse {
+            id: passkey.id,
+            name: passkey.name,
+            transports: passkey.transports,
+            created_at: passkey.created_at,
+            last_used_at: passkey.last_used_at,
+        }
+    }
+}
+
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn get_my_passkeys(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+) -> Result<Json<Vec<PasskeyResponse>>, AppError> {
+    let passkeys = Passkeys::find()
+        .filter(passkeys::Column::UserId.eq(user.id))
+        .order_by_asc(passkeys::Column::Id)
+        .all(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .into_iter()
+        .map(PasskeyResponse::from)
+        .collect();
+    Ok(Json(passkeys))
+}
+
+/// Starts adding a passkey. Authenticators are told about the user's existing passkeys, so
+/// the same one isn't registered twice.
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn start_passkey_registration(
+    State(database): State<DatabaseConnection>,
+    State(relying_party): State<PasskeyRelyingParty>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+) -> Result<Json<CeremonyResponse<CreationChallengeResponse>>, AppError> {
+    let existing: Vec<CredentialID> = Passkeys::find()
+        .filter(passkeys::Column::UserId.eq(user.id))
+        .all(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .into_iter()
+        .map(|passkey| passkey.credential_id.into())
+        .collect();
+    let (options, state) = relying_party
+        .webauthn
+        .start_passkey_registration(
+            user.webauthn_user_id,
+            &user.username,
+            &user.username,
+            Some(existing),
+        )
+        .map_err(ceremony_error)?;
+    let challenge_id = store_challenge(
+        &database,
+        &relying_party,
+        WebauthnCeremony::Registration,
+        Some(user.id),
+        &state,
+    )
+    .await?;
+    Ok(Json(CeremonyResponse {
+        challenge_id,
+        options,
+    }))
+}
+
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn finish_passkey_registration(
+    State(database): State<DatabaseConnection>,
+    State(relying_party): State<PasskeyRelyingParty>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+    Json(request): Json<FinishRegistrationRequest>,
+) -> Result<(StatusCode, Json<PasskeyResponse>), AppError> {
+    let name = request
+        .name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .unwrap_or("Passkey")
+        .to_owned();
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Name can be at most {MAX_NAME_LENGTH} characters long."),
+        ));
+    }
+    let state: PasskeyRegistration = consume_challenge(
+        &database,
+        request.challenge_id,
+        WebauthnCeremony::Registration,
+        Some(user.id),
+    )
+    .await?;
+    let passkey = relying_party
+        .webauthn
+        .finish_passkey_registration(&request.credential, &state)
+        .map_err(ceremony_error)?;
+
+    let credential_id = passkey.cred_id().to_vec();
+    let taken = Passkeys::find()
+        .filter(passkeys::Column::CredentialId.eq(credential_id.clone()))
+        .count(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if taken > 0 {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            "This passkey is already registered.",
+        ));
+    }
+    let transports = request
+        .credential
+        .response
+        .transports
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|transport| serde_json::to_value(transport).ok())
+        .filter_map(|value| value.as_str().map(str::to_owned))
+        .collect();
+    let public_key = serde_json::to_value(&passkey)
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let passkey = passkeys::ActiveModel {
+        user_id: Set(user.id),
+        credential_id: Set(credential_id),
+        public_key: Set(public_key),
+        sign_count: Set(i64::from(Credential::from(passkey).counter)),
+        transports: Set(transports),
+        name: Set(name),
+        created_at: Set(Utc::now().into()),
+        ..Default::default()
+    }
+    .insert(&database)
+    .await
+    .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(passkey_id = passkey.id, "passkey registered");
+    Ok((StatusCode::CREATED, Json(PasskeyResponse::from(passkey))))
+}
+
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn delete_passkey(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+    Path(passkey_id): Path<i32>,
+) -> Result<(), AppError> {
+    if user.password.is_none() {
+        let identity_count = UserIdentities::find()
+            .filter(user_identities::Column::UserId.eq(user.id))
+            .count(&database)
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        let passkey_count = Passkeys::find()
+            .filter(passkeys::Column::UserId.eq(user.id))
+            .count(&database)
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        if identity_count == 0 && passkey_count <= 1 {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                "You cannot remove your only way to sign in.",
+            ));
+        }
+    }
+    let result = Passkeys::delete_many()
+        .filter(passkeys::Column::Id.eq(passkey_id))
+        .filter(passkeys::Column::UserId.eq(user.id))
+        .exec(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if result.rows_affected == 0 {
+        return Err(AppError::new(StatusCode::NOT_FOUND, "Passkey not found."));
+    }
+    info!(passkey_id, "passkey deleted");
+    Ok(())
+}
+
+/// Starts a usernameless sign-in: the authenticator picks the passkey and tells us whose it is.
+#[instrument(skip_all)]
+pub async fn start_passkey_login(
+    State(database): State<DatabaseConnection>,
+    State(relying_party): State<PasskeyRelyingParty>,
+) -> Result<Json<CeremonyResponse<RequestChallengeResponse>>, AppError> {
+    let (options, state) = relying_party
+        .webauthn
+        .start_discoverable_authentication()
+        .map_err(ceremony_error)?;
+    let challenge_id = store_challenge(
+        &database,
+        &relying_party,
+        WebauthnCeremony::Authentication,
+        None,
+        &state,
+    )
+    .await?;
+    Ok(Json(CeremonyResponse {
+        challenge_id,
+        options,
+    }))
+}
+
+/// A passkey verifies the user itself, so it signs in without a second factor.
+#[instrument(skip_all)]
+pub async fn finish_passkey_login(
+    State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    State(relying_party): State<PasskeyRelyingParty>,
+    State(auth): State<AuthConfig>,
+    client: ClientInfo,
+    jar: CookieJar,
+    Json(request): Json<FinishLoginRequest>,
+) -> Result<(CookieJar, Json<UserResponse>), AppError> {
+    let rejected = || AppError::new(StatusCode::UNAUTHORIZED, "Passkey could not be verified.");
+    let state: DiscoverableAuthentication = consume_challenge(
+        &database,
+        request.challenge_id,
+        WebauthnCeremony::Authentication,
+        None,
+    )
+    .await?;
+    let (user_handle, credential_id) = relying_party
+        .webauthn
+        .identify_discoverable_authentication(&request.credential)
+        .map_err(ceremony_error)?;
+    let (stored, user) = Passkeys::find()
+        .filter(passkeys::Column::CredentialId.eq(credential_id.to_vec()))
+        .find_also_related(Users)
+        .one(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or_else(rejected)?;
+    let user = user
+        .filter(|user| user.webauthn_user_id == user_handle && user.deleted_at.is_none())
+        .ok_or_else(rejected)?;
+    let mut passkey: Passkey = serde_json::from_value(stored.public_key.clone())
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    let result = match relying_party.webauthn.finish_discoverable_authentication(
+        &request.credential,
+        state,
+        &[(&passkey).into()],
+    ) {
+        Ok(result) => result,
+        Err(WebauthnError::CredentialPossibleCompromise) => {
+            return Err(counter_regression(user.id, stored.id))
+        }
+        Err(error) => return Err(ceremony_error(error)),
+    };
+    record_passkey_use(&database, &stored, &mut passkey, &result).await?;
+    if user.disabled_at.is_some() {
+        return Err(AppError::new(StatusCode::FORBIDDEN, "Account disabled."));
+    }
+
+    let tokens = start_session(&database, &jwt, user.id, client).await?;
+    info!(
+        user_id = user.id,
+        passkey_id = stored.id,
+        "signed in with passkey"
+    );
+    let (jar, tokens) = deliver_tokens(jar, &auth, &jwt, tokens);
+    Ok((jar, Json(UserResponse::signed_in(user, tokens))))
+}
+
+/// Saves the new signature counter. The update only applies if the counter moved forward, so
+/// two sign-ins racing with the same counter can't both succeed.
+async fn record_passkey_use(
+    database: &DatabaseConnection,
+    stored: &passkeys::Model,
+    passkey: &mut Passkey,
+    result: &AuthenticationResult,
+) -> Result<(), AppError> {
+    passkey.update_credential(result);
+    let counter = i64::from(result.counter());
+    let public_key = serde_json::to_value(&*passkey)
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let mut update = Passkeys::update_many()
+        .col_expr(passkeys::Column::PublicKey, Expr::value(public_key))
+        .col_expr(passkeys::Column::SignCount, Expr::value(counter))
+        .col_expr(
+            passkeys::Column::LastUsedAt,
+            Expr::value(DateTimeWithTimeZone::from(Utc::now())),
+        )
+        .filter(passkeys::Column::Id.eq(stored.id));
+    // Synced passkeys don't keep a counter and always report 0.
+    if counter > 0 || stored.sign_count > 0 {
+        update = update.filter(passkeys::Column::SignCount.lt(counter));
+    }
+    let result = update
+        .exec(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if result.rows_affected == 0 {
+        return Err(counter_regression(stored.user_id, stored.id));
+    }
+    Ok(())
+}
+
+/// A counter that didn't increase suggests the authenticator has been cloned.
+fn counter_regression(user_id: i32, passkey_id: i32) -> AppError {
+    warn!(
+        user_id,
+        passkey_id, "passkey signature counter went backwards"
+    );
+    AppError::new(
+        StatusCode::UNAUTHORIZED,
+        "Passkey rejected: its signature counter went backwards, which can mean it was cloned.",
+    )
+}
+
+async fn store_challenge<T: Serialize>(
+    database: &DatabaseConnection,
+    relying_party: &PasskeyRelyingParty,
+    ceremony: WebauthnCeremony,
+    user_id: Option<i32>,
+    state: &T,
+) -> Result<Uuid, AppError> {
+    let id = Uuid::new_v4();
+    let state = serde_json::to_value(state)
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    webauthn_challenges::ActiveModel {
+        id: Set(id),
+        ceremony: Set(ceremony),
+        user_id: Set(user_id),
+        state: Set(state),
+        expires_at: Set((Utc::now() + relying_party.challenge_ttl).into()),
+    }
+    .insert(database)
+    .await
+    .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(id)
+}
+
+/// Looks up and deletes the ceremony state for `id`, so each challenge can be answered once.
+async fn consume_challenge<T: DeserializeOwned>(
+    database: &DatabaseConnection,
+    id: Uuid,
+    ceremony: WebauthnCeremony,
+    user_id: Option<i32>,
+) -> Result<T, AppError> {
+    let invalid_challenge =
+        || AppError::new(StatusCode::BAD_REQUEST, "Invalid or expired challenge.");
+    let challenge = WebauthnChallenges::find_by_id(id)
+        .filter(webauthn_challenges::Column::Ceremony.eq(ceremony))
+        .one(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .filter(|challenge| challenge.user_id == user_id)
+        .ok_or_else(invalid_challenge)?;
+    let deleted = WebauthnChallenges::delete_by_id(id)
+        .exec(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if deleted.rows_affected == 0 || challenge.expires_at < Utc::now() {
+        return Err(invalid_challenge());
+    }
+    serde_json::from_value(challenge.state)
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use sea_orm::ConnectionTrait;
+    use serde_json::{json, Value};
+    use url::Url;
+    use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+    use webauthn_rs::prelude::Base64UrlSafeData;
+
+    use super::*;
+    use crate::test_support::{empty_request, json_request, TestApp, TestResponse, APP_URL};
+
+    /// A software authenticator playing the browser's part too. `SoftPasskey` only keeps
+    /// keys, not the user they belong to, so the credential id and user handle a platform
+    /// authenticator would remember are kept here.
+    struct Device {
+        authenticator: WebauthnAuthenticator<SoftPasskey>,
+        credential_id: Option<Base64UrlSafeData>,
+        user_handle: Option<Base64UrlSafeData>,
+    }
+
+    impl Device {
+        fn new() -> Self {
+            Device {
+                // Passkeys require user verification, which the soft token can only claim.
+                authenticator: WebauthnAuthenticator::new(SoftPasskey::new(true)),
+                credential_id: None,
+                user_handle: None,
+            }
+        }
+
+        async fn register(&mut self, app: &TestApp, bearer: &str, name: &str) -> TestResponse {
+            let headers = [("authorization", bearer)];
+            let start = app
+                .call(empty_request(
+                    Method::POST,
+                    "/passkeys/register/start",
+                    &headers,
+                ))
+                .await;
+            assert_eq!(start.status, StatusCode::OK, "{}", start.body);
+            let options: CreationChallengeResponse =
+                serde_json::from_value(start.body["options"].clone()).unwrap();
+            self.user_handle = Some(options.public_key.user.id.clone());
+            let credential = self
+                .authenticator
+                .do_registration(Url::parse(APP_URL).unwrap(), options)
+                .unwrap();
+            self.credential_id = Some(credential.raw_id.clone());
+            app.call(json_request(
+                Method::POST,
+                "/passkeys/register/finish",
+                json!({
+                    "challenge_id": start.body["challenge_id"],
+                    "name": name,
+                    "credential": credential,
+                }),
+                &headers,
+            ))
+            .await
+        }
+
+        async fn sign_in(&mut self, app: &TestApp) -> TestResponse {
+            let start = app
+                .call(empty_request(Method::POST, "/login/passkey/start", &[]))
+                .await;
+            assert_eq!(start.status, StatusCode::OK, "{}", start.body);
+            let mut options = start.body["options"].clone();
+            options["publicKey"]["allowCredentials"] = json!([{
+                "type": "public-key",
+                "id": self.credential_id.as_ref().expect("registered"),
+            }]);
+            let options: RequestChallengeResponse = serde_json::from_value(options).unwrap();
+            let mut credential = self
+                .authenticator
+                .do_authentication(Url::parse(APP_URL).unwrap(), options)
+                .unwrap();
+            credential.response.user_handle = self.user_handle.clone();
+            app.call(json_request(
+                Method::POST,
+                "/login/passkey/finish",
+                json!({
+                    "challenge_id": start.body["challenge_id"],
+                    "credential": credential,
+                }),
+                &[],
+            ))
+            .await
+        }
+    }
+
+    async fn signed_up(app: &TestApp) -> String {
+        format!("Bearer {}", app.sign_up("ada@example.com").await)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn registers_and_signs_in_with_a_passkey() {
+        let app = TestApp::start(|_| {}).await;
+        let bearer = signed_up(&app).await;
+        let mut device = Device::new();
+
+        let registered = device.register(&app, &bearer, " Laptop ").await;
+        assert_eq!(
+            registered.status,
+            StatusCode::CREATED,
+            "{}",
+            registered.body
+        );
+        assert_eq!(registered.body["name"], "Laptop");
+        assert_eq!(registered.body["last_used_at"], Value::Null);
+
+        let signed_in = device.sign_in(&app).await;
+        assert_eq!(signed_in.status, StatusCode::OK, "{}", signed_in.body);
+        assert_eq!(signed_in.body["username"], "ada@example.com");
+        assert!(signed_in.body["token"].is_string());
+
+        let passkeys = app
+            .call(empty_request(
+                Method::GET,
+                "/passkeys",
+                &[("authorization", &bearer)],
+            ))
+            .await;
+        assert!(passkeys.body[0]["last_used_at"].is_string());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn keeps_several_passkeys_per_user() {
+        let app = TestApp::start(|_| {}).await;
+        let bearer = signed_up(&app).await;
+        let mut laptop = Device::new();
+        let mut phone = Device::new();
+        assert_eq!(
+            laptop.register(&app, &bearer, "Laptop").await.status,
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            phone.register(&app, &bearer, "Phone").await.status,
+            StatusCode::CREATED
+        );
+
+        let passkeys = app
+            .call(empty_request(
+                Method::GET,
+                "/passkeys",
+                &[("authorization", &bearer)],
+            ))
+            .await;
+        let names: Vec<_> = passkeys
+            .body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| &p["name"])
+            .collect();
+        assert_eq!(names, ["Laptop", "Phone"]);
+
+        for device in [&mut laptop, &mut phone] {
+            let signed_in = device.sign_in(&app).await;
+            assert_eq!(signed_in.status, StatusCode::OK, "{}", signed_in.body);
+            assert_eq!(signed_in.body["username"], "ada@example.com");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn rejects_a_signature_counter_that_went_backwards() {
+        let app = TestApp::start(|_| {}).await;
+        let bearer = signed_up(&app).await;
+        let mut device = Device::new();
+        device.register(&app, &bearer, "Laptop").await;
+        assert_eq!(device.sign_in(&app).await.status, StatusCode::OK);
+
+        // As if a copy of the key had signed in a hundred times since.
+        app.database
+            .execute_unprepared(
+                "UPDATE passkeys SET sign_count = 100, \
+                 public_key = jsonb_set(public_key, '{cred,counter}', '100')",
+            )
+            .await
+            .unwrap();
+        let rejected = device.sign_in(&app).await;
+        assert_eq!(rejected.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            rejected.body["message"],
+            "Passkey rejected: its signature counter went backwards, which can mean it was \
+             cloned."
+        );
+    }
+}
@@ -0,0 +1,514 @@
+use axum::{extract::State, http::StatusCode, Json};
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseConnection,
+    EntityTrait, QueryFilter, QueryOrder, QuerySelect, Set, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use super::guard::SessionClaims;
+use super::workspace::ActiveWorkspace;
+use crate::database::{
+    prelude::{TaskPriorities, Workspaces},
+    task_priorities,
+};
+use crate::utils::app_error::AppError;
+use crate::utils::permission::WorkspacePermission;
+
+pub const MAX_PRIORITY_LEVELS: usize = 10;
+const MAX_PRIORITY_KEY_LENGTH: usize = 32;
+const MAX_PRIORITY_LABEL_LENGTH: usize = 32;
+
+/// The levels every new workspace starts with, lowest first.
+const DEFAULT_PRIORITIES: [(&str, &str, &str); 4] = [
+    ("low", "Low", "#6b7280"),
+    ("medium", "Medium", "#2563eb"),
+    ("high", "High", "#f59e0b"),
+    ("urgent", "Urgent", "#dc2626"),
+];
+
+#[derive(Deserialize)]
+pub struct PriorityRequest {
+    key: String,
+    label: String,
+    color: String,
+}
+
+#[derive(Serialize)]
+pub struct PriorityResponse {
+    key: String,
+    label: String,
+    color: String,
+    /// Position from 1 for the lowest priority; tasks sort by it.
+    level: i16,
+}
+
+impl From<task_priorities::Model> for PriorityResponse {
+    fn from(priority: task_priorities::Model) -> Self {
+        PriorityResponse {
+            key: priority.key,
+            label: priority.label,
+            color: priority.color,
+            level: priority.level,
+        }
+    }
+}
+
+/// A workspace's priority levels, lowest first. Tasks store the level and clients use the
+/// key, so these translate between the two.
+pub struct PriorityLevels {
+    levels: Vec<task_priorities::Model>,
+}
+
+impl PriorityLevels {
+    pub async fn load(
+        database: &impl ConnectionTrait,
+        workspace_id: i32,
+    ) -> Result<Self, AppError> {
+        let levels = TaskPriorities::find()
+            .filter(task_priorities::Column::WorkspaceId.eq(workspace_id))
+            .order_by_asc(task_priorities::Column::Level)
+            .all(database)
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        Ok(PriorityLevels { levels })
+    }
+
+    /// The level of the priority called `key`, ignoring case.
+    pub fn find(&self, key: &str) -> Option<i16> {
+        self.levels
+            .iter()
+            .find(|priority| priority.key.eq_ignore_ascii_case(key.trim()))
+            .map(|priority| priority.level)
+    }
+
+    /// Like `find`, but a 400 naming the workspace's priorities when there's no such key.
+    pub fn parse(&self, key: &str) -> Result<i16, AppError> {
+        self.find(key).ok_or_else(|| {
+            AppError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Unknown priority `{key}`; {}.", self.describe()),
+            )
+        })
+    }
+
+    /// The key of a level a task is stored with.
+    pub fn key(&self, level: Option<i16>) -> Option<String> {
+        let level = level?;
+        self.levels
+            .iter()
+            .find(|priority| priority.level == level)
+            .map(|priority| priority.key.clone())
+    }
+
+    /// Lists the keys for error messages, as in "this workspace uses low, medium, high".
+    pub fn describe(&self) -> String {
+        match self.levels.is_empty() {
+            true => "this workspace has no priorities".to_owned(),
+            false => format!(
+                "this workspace uses {}",
+                self.levels
+                    .iter()
+                    .map(|priority| priority.key.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// Gives a new workspace the default priority levels.
+pub async fn create_default_priorities(
+    database: &impl ConnectionTrait,
+    workspace_id: i32,
+) -> Result<(), AppError> {
+    let levels = DEFAULT_PRIORITIES
+        .iter()
+        .zip(1..)
+        .map(
+            |(&(key, label, color), level)| task_priorities::ActiveModel {
+                workspace_id: Set(workspace_id),
+                level: Set(level),
+                key: Set(key.to_owned()),
+                label: Set(label.to_owned()),
+                color: Set(color.to_owned()),
+            },
+        );
+    TaskPriorities::insert_many(levels)
+        .exec(database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(())
+}
+
+pub async fn get_priorities(
+    State(database): State<DatabaseConnection>,
+    active: ActiveWorkspace,
+) -> Result<Json<Vec<PriorityResponse>>, AppError> {
+    let levels = PriorityLevels::load(&database, active.workspace.id).await?;
+    Ok(Json(levels.levels.into_iter().map(Into::into).collect()))
+}
+
+/// Replaces the workspace's priorities with `request`, lowest first. Tasks keep priorities
+/// whose key is still listed, even when they move, and lose the ones that aren't.
+#[instrument(skip_all, fields(workspace_id = active.workspace.id))]
+pub async fn update_priorities(
+    State(database): State<DatabaseConnection>,
+    active: ActiveWorkspace,
+    _session: SessionClaims,
+    Json(request): Json<Vec<PriorityRequest>>,
+) -> Result<Json<Vec<PriorityResponse>>, AppError> {
+    active.require(WorkspacePermission::ConfigureTasks)?;
+    let priorities = validate_priorities(request)?;
+    let workspace_id = active.workspace.id;
+
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    // Serializes concurrent updates, which would otherwise collide on levels.
+    Workspaces::find_by_id(workspace_id)
+        .lock_exclusive()
+        .one(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let existing = PriorityLevels::load(&txn, workspace_id).await?;
+    let keys: Vec<&str> = priorities
+        .iter()
+        .map(|priority| priority.key.as_str())
+        .collect();
+    // Tasks lose removed priorities through the foreign key.
+    TaskPriorities::delete_many()
+        .filter(task_priorities::Column::WorkspaceId.eq(workspace_id))
+        .filter(task_priorities::Column::Key.is_not_in(keys))
+        .exec(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    // Kept levels step aside first so renumbering never hits a level that's still taken.
+    // Their tasks follow every move through the foreign key.
+    TaskPriorities::update_many()
+        .col_expr(
+            task_priorities::Column::Level,
+            Expr::col(task_priorities::Column::Level).mul(-1),
+        )
+        .filter(task_priorities::Column::WorkspaceId.eq(workspace_id))
+        .exec(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    for (priority, level) in priorities.into_iter().zip(1i16..) {
+        if existing.find(&priority.key).is_some() {
+            TaskPriorities::update_many()
+                .col_expr(task_priorities::Column::Level, Expr::value(level))
+                .col_expr(task_priorities::Column::Label, Expr::value(priority.label))
+                .col_expr(task_priorities::Column::Color, Expr::value(priority.color))
+                .filter(task_priorities::Column::WorkspaceId.eq(workspace_id))
+                .filter(task_priorities::Column::Key.eq(priority.key))
+                .exec(&txn)
+                .await
+                .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        } else {
+            task_priorities::ActiveModel {
+                workspace_id: Set(workspace_id),
+                level: Set(level),
+                key: Set(priority.key),
+                label: Set(priority.label),
+                color: Set(priority.color),
+            }
+            .insert(&txn)
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        }
+    }
+    let levels = PriorityLevels::load(&txn, workspace_id).await?;
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(levels = levels.levels.len(), "priorities updated");
+    Ok(Json(levels.levels.into_iter().map(Into::into).collect()))
+}
+
+/// Checks and normalizes a new list of priorities: keys are lowercased and colors are
+/// `#rrggbb` in lowercase.
+fn validate_priorities(request: Vec<PriorityRequest>) -> Result<Vec<PriorityRequest>, AppError> {
+    if request.is_empty() || request.len() > MAX_PRIORITY_LEVELS {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            format!("List between 1 and {MAX_PRIORITY_LEVELS} priorities."),
+        ));
+    }
+    let mut priorities: Vec<PriorityRequest> = Vec::with_capacity(request.len());
+    for priority in request {
+        let key = priority.key.trim().to_lowercase();
+        if key.is_empty()
+            || key.len() > MAX_PRIORITY_KEY_LENGTH
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'))
+        {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Priority keys are 1 to {MAX_PRIORITY_KEY_LENGTH} letters, digits, `_` or \
+                     `-`; `{}` isn't.",
+                    priority.key
+                ),
+            ));
+        }
+        if priorities.iter().any(|existing| existing.key == key) {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                format!("The priority `{key}` appears more than once."),
+            ));
+        }
+        let label = priority.label.trim().to_owned();
+        if label.is_empty() || label.chars().count() > MAX_PRIORITY_LABEL_LENGTH {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "The label of `{key}` must be 1 to {MAX_PRIORITY_LABEL_LENGTH} characters."
+                ),
+            ));
+        }
+        if priorities
+            .iter()
+            .any(|existing| existing.label.to_lowercase() == label.to_lowercase())
+        {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                format!("The label `{label}` is used by more than one priority."),
+            ));
+        }
+        let color = priority.color.trim().to_lowercase();
+        let is_hex_color = color.len() == 7
+            && color
+                .strip_prefix('#')
+                .is_some_and(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()));
+        if !is_hex_color {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "The color of `{key}` must look like #1a2b3c, not `{}`.",
+                    priority.color
+                ),
+            ));
+        }
+        priorities.push(PriorityRequest { key, label, color });
+    }
+    Ok(priorities)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+    use sea_orm::{DatabaseBackend, FromQueryResult, Statement};
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{base_database, json_request, migrate, TestApp};
+
+    fn request(levels: &[(&str, &str, &str)]) -> Vec<PriorityRequest> {
+        levels
+            .iter()
+            .map(|&(key, label, color)| PriorityRequest {
+                key: key.to_owned(),
+                label: label.to_owned(),
+                color: color.to_owned(),
+            })
+            .collect()
+    }
+
+    fn rejection(levels: &[(&str, &str, &str)]) -> String {
+        let error = validate_priorities(request(levels))
+            .err()
+            .expect("rejected");
+        let (status, message) = error.into();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        message
+    }
+
+    fn levels(keys: &[&str]) -> PriorityLevels {
+        PriorityLevels {
+            levels: keys
+                .iter()
+                .zip(1..)
+                .map(|(key, level)| task_priorities::Model {
+                    workspace_id: 1,
+                    level,
+                    key: key.to_string(),
+                    label: key.to_string(),
+                    color: "#000000".to_owned(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn keeps_the_order_and_normalizes() {
+        let priorities = validate_priorities(request(&[
+            ("Later", " Some day ", "#ABCDEF"),
+            ("now", "Now", " #00ff00 "),
+        ]))
+        .unwrap();
+        let normalized: Vec<_> = priorities
+            .iter()
+            .map(|p| (p.key.as_str(), p.label.as_str(), p.color.as_str()))
+            .collect();
+        assert_eq!(
+            normalized,
+            [("later", "Some day", "#abcdef"), ("now", "Now", "#00ff00")]
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_keys() {
+        assert_eq!(
+            rejection(&[("high", "High", "#000000"), ("HIGH", "Higher", "#111111")]),
+            "The priority `high` appears more than once."
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_labels() {
+        assert_eq!(
+            rejection(&[("high", "High", "#000000"), ("p1", " high ", "#111111")]),
+            "The label `high` is used by more than one priority."
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_colors() {
+        for color in ["red", "#12345", "#1234567", "123456#", "#12345g", "#"] {
+            assert_eq!(
+                rejection(&[("high", "High", color)]),
+                format!("The color of `high` must look like #1a2b3c, not `{color}`."),
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_keys_labels_and_counts() {
+        assert!(rejection(&[("very high", "Very high", "#000000")]).contains("`very high` isn't"));
+        assert!(rejection(&[(" ", "Blank", "#000000")]).contains("isn't"));
+        assert_eq!(
+            rejection(&[("high", "  ", "#000000")]),
+            "The label of `high` must be 1 to 32 characters."
+        );
+        assert_eq!(rejection(&[]), "List between 1 and 10 priorities.");
+        let eleven: Vec<_> = (0..11)
+            .map(|level| (format!("p{level}"), "P", "#000000"))
+            .collect();
+        let eleven: Vec<_> = eleven
+            .iter()
+            .map(|(key, label, color)| (key.as_str(), *label, *color))
+            .collect();
+        assert_eq!(rejection(&eleven), "List between 1 and 10 priorities.");
+    }
+
+    #[test]
+    fn parses_keys_and_names_the_levels_otherwise() {
+        let levels = levels(&["low", "high"]);
+        assert_eq!(levels.parse(" HIGH ").unwrap(), 2);
+        assert_eq!(levels.key(Some(1)).as_deref(), Some("low"));
+
+        let (status, message) = levels.parse("someday").unwrap_err().into();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            message,
+            "Unknown priority `someday`; this workspace uses low, high."
+        );
+        let (_, message) = self::levels(&[]).parse("low").unwrap_err().into();
+        assert_eq!(
+            message,
+            "Unknown priority `low`; this workspace has no priorities."
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn task_handlers_list_the_priorities_for_an_unknown_one() {
+        let app = TestApp::start(|_| {}).await;
+        let token = app.sign_up("ada@example.com").await;
+        let bearer = format!("Bearer {token}");
+        let headers = [("authorization", bearer.as_str())];
+        let unknown = "Unknown priority `someday`; this workspace uses low, medium, high, urgent.";
+
+        let create = |priority: &str| {
+            json_request(
+                Method::POST,
+                "/tasks",
+                json!({ "title": "Write tests", "priority": priority }),
+                &headers,
+            )
+        };
+        let response = app.call(create("someday")).await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.text, unknown);
+
+        let response = app.call(create("High")).await;
+        assert_eq!(response.status, StatusCode::CREATED, "{}", response.text);
+        assert_eq!(response.body["priority"], "high");
+        let task = format!("/tasks/{}", response.body["id"]);
+
+        for method in [Method::PUT, Method::PATCH] {
+            let response = app
+                .call(json_request(
+                    method.clone(),
+                    &task,
+                    json!({ "title": "Write tests", "priority": "someday" }),
+                    &headers,
+                ))
+                .await;
+            assert_eq!(response.status, StatusCode::BAD_REQUEST, "{method}");
+            assert_eq!(response.text, unknown, "{method}");
+        }
+    }
+
+    #[derive(Debug, PartialEq, FromQueryResult)]
+    struct MigratedTask {
+        title: String,
+        priority: Option<i16>,
+        legacy_priority: Option<String>,
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn migration_maps_free_text_priorities_onto_levels() {
+        let database = base_database().await;
+        database
+            .execute_unprepared(
+                "INSERT INTO users (id, username, password) VALUES (1, 'ada@example.com', 'x');
+                 INSERT INTO tasks (title, priority, user_id) VALUES
+                     ('a', 'Low', 1), ('b', ' P2 ', 1), ('c', 'important', 1),
+                     ('d', 'ASAP', 1), ('e', 'someday', 1), ('f', '  ', 1), ('g', NULL, 1);",
+            )
+            .await
+            .unwrap();
+        migrate(&database, ..).await;
+
+        let tasks = MigratedTask::find_by_statement(Statement::from_string(
+            DatabaseBackend::Postgres,
+            "SELECT title, priority, legacy_priority FROM tasks ORDER BY title",
+        ))
+        .all(&database)
+        .await
+        .unwrap();
+        let expected = [
+            ("a", Some(1), None),
+            ("b", Some(2), None),
+            ("c", Some(3), None),
+            ("d", Some(4), None),
+            ("e", None, Some("someday")),
+            ("f", None, None),
+            ("g", None, None),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(title, priority, legacy_priority)| MigratedTask {
+                title: title.to_owned(),
+                priority,
+                legacy_priority: legacy_priority.map(str::to_owned),
+            })
+            .collect();
+        assert_eq!(tasks, expected);
+    }
+}
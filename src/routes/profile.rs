@@ -0,0 +1,321 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use chrono_tz::Tz;
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, sea_query::Expr, ActiveModelTrait, ColumnTrait,
+    DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter, Set, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use super::guard::{AuthUser, SessionClaims};
+use super::session::{revoke_user_sessions, ClientInfo};
+use super::user::visible_to;
+use crate::database::{
+    account_tokens,
+    prelude::{AccountTokens, Users},
+    sea_orm_active_enums::{AccountTokenPurpose, UserRole},
+    users,
+};
+use crate::utils::app_error::AppError;
+use crate::utils::avatar::{
+    avatar_key, render_avatars, AvatarConfig, AVATAR_SIZES, DEFAULT_AVATAR_SIZE,
+};
+use crate::utils::lockout::LoginThrottle;
+use crate::utils::password::PasswordHasher;
+use crate::utils::password_policy::PasswordPolicy;
+use crate::utils::storage::Storage;
+
+const MAX_DISPLAY_NAME_LENGTH: usize = 64;
+
+#[derive(Serialize)]
+pub struct ProfileResponse {
+    id: i32,
+    username: String,
+    display_name: Option<String>,
+    timezone: String,
+    locale: String,
+    role: UserRole,
+    email_verified: bool,
+    two_factor_enabled: bool,
+    /// Carries the avatar's version, so a new upload never hits a stale cache entry.
+    avatar_url: Option<String>,
+}
+
+impl From<users::Model> for ProfileResponse {
+    fn from(user: users::Model) -> Self {
+        ProfileResponse {
+            avatar_url: avatar_url(&user),
+            id: user.id,
+            username: user.username,
+            display_name: user.display_name,
+            timezone: user.timezone,
+            locale: user.locale,
+            role: user.role,
+            email_verified: user.email_verified_at.is_some(),
+            two_factor_enabled: user.totp_enabled_at.is_some(),
+        }
+    }
+}
+
+/// Where to download a user's avatar, if they have one.
+pub fn avatar_url(user: &users::Model) -> Option<String> {
+    user.avatar_updated_at
+        .map(|updated_at| format!("/users/{}/avatar?v={}", user.id, updated_at.timestamp()))
+}
+
+/// Omitted fields are left alone; an empty `display_name` removes it.
+#[derive(Deserialize)]
+pub struct ProfileUpdate {
+    display_name: Option<String>,
+    timezone: Option<String>,
+    locale: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AvatarQuery {
+    size: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+pub async fn get_me(AuthUser(user): AuthUser) -> Json<ProfileResponse> {
+    Json(user.into())
+}
+
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn update_me(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+    Json(request): Json<ProfileUpdate>,
+) -> Result<Json<ProfileResponse>, AppError> {
+    let mut user = user.into_active_model();
+    if let Some(display_name) = request.display_name {
+        let display_name = display_name.trim();
+        if display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH
+            || display_name.chars().any(char::is_control)
+        {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Display names can be at most {MAX_DISPLAY_NAME_LENGTH} characters, without control characters."
+                ),
+            ));
+        }
+        user.display_name = match display_name.is_empty() {
+            true => Set(None),
+            false => Set(Some(display_name.to_owned())),
+        }
+    }
+    if let Some(timezone) = request.timezone {
+        let timezone: Tz = timezone.parse().map_err(|_| {
+            AppError::new(
+                StatusCode::BAD_REQUEST,
+                "Unknown time zone; use an IANA name such as Europe/Berlin.",
+            )
+        })?;
+        user.timezone = Set(timezone.name().to_owned());
+    }
+    if let Some(locale) = request.locale {
+        if !is_language_tag(&locale) {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                "Invalid locale; use a language tag such as en-GB.",
+            ));
+        }
+        user.locale = Set(locale);
+    }
+    let user = user
+        .update(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(user.into()))
+}
+
+/// Takes the raw image as the request body and stores it resized to every avatar size.
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn upload_avatar(
+    State(database): State<DatabaseConnection>,
+    State(storage): State<Arc<dyn Storage>>,
+    State(avatars): State<AvatarConfig>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+    body: Bytes,
+) -> Result<Json<ProfileResponse>, AppError> {
+    let renditions =
+        tokio::task::spawn_blocking(move || render_avatars(&body, avatars.max_dimension))
+            .await
+            .map_err(|error| {
+                AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+            })??;
+    for (size, png) in renditions {
+        storage.put(&avatar_key(user.id, size), png).await?;
+    }
+    let mut user = user.into_active_model();
+    user.avatar_updated_at = Set(Some(Utc::now().into()));
+    let user = user
+        .update(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!("avatar updated");
+    Ok(Json(user.into()))
+}
+
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn delete_avatar(
+    State(database): State<DatabaseConnection>,
+    State(storage): State<Arc<dyn Storage>>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+) -> Result<Json<ProfileResponse>, AppError> {
+    let user_id = user.id;
+    let mut user = user.into_active_model();
+    user.avatar_updated_at = Set(None);
+    let user = user
+        .update(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    delete_avatar_files(storage.as_ref(), user_id).await?;
+    Ok(Json(user.into()))
+}
+
+/// Serves the avatar of any user the caller can see in the directory, at one of
+/// `AVATAR_SIZES`.
+#[instrument(skip(database, storage, caller))]
+pub async fn get_avatar(
+    Path(user_id): Path<i32>,
+    Query(query): Query<AvatarQuery>,
+    State(database): State<DatabaseConnection>,
+    State(storage): State<Arc<dyn Storage>>,
+    AuthUser(caller): AuthUser,
+) -> Result<Response, AppError> {
+    let size = query.size.unwrap_or(DEFAULT_AVATAR_SIZE);
+    if !AVATAR_SIZES.contains(&size) {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Avatar size must be one of {AVATAR_SIZES:?}."),
+        ));
+    }
+    let not_found = || AppError::new(StatusCode::NOT_FOUND, "Avatar not found.");
+    Users::find_by_id(user_id)
+        .filter(users::Column::DeletedAt.is_null())
+        .filter(users::Column::AvatarUpdatedAt.is_not_null())
+        .filter(visible_to(&caller))
+        .one(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or_else(not_found)?;
+    let png = storage
+        .get(&avatar_key(user_id, size))
+        .await?
+        .ok_or_else(not_found)?;
+    Ok((
+        [
+            (CONTENT_TYPE, "image/png"),
+            (CACHE_CONTROL, "private, max-age=86400"),
+        ],
+        png,
+    )
+        .into_response())
+}
+
+/// Replaces the caller's password and signs out every other session.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn change_password(
+    State(database): State<DatabaseConnection>,
+    State(hasher): State<PasswordHasher>,
+    State(password_policy): State<PasswordPolicy>,
+    State(throttle): State<LoginThrottle>,
+    AuthUser(user): AuthUser,
+    SessionClaims(claims): SessionClaims,
+    client: ClientInfo,
+    Json(request): Json<ChangePasswordRequest>,
+) -> Result<(), AppError> {
+    let Some(password_hash) = user.password.clone() else {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "This account has no password yet; set one through a password reset.",
+        ));
+    };
+    throttle.check(&user.username, client.ip.as_deref()).await?;
+    if !hasher
+        .verify(request.current_password, password_hash)
+        .await?
+        .is_valid()
+    {
+        throttle
+            .record_failure(&user.username, client.ip.as_deref())
+            .await?;
+        return Err(AppError::new(
+            StatusCode::UNAUTHORIZED,
+            "Wrong credentials.",
+        ));
+    }
+    password_policy.check(&request.new_password, Some(&user.username))?;
+
+    let user_id = user.id;
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let mut user = user.into_active_model();
+    user.password = Set(Some(hasher.hash(request.new_password).await?));
+    user.update(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    // A reset link requested before the change would otherwise still undo it.
+    AccountTokens::update_many()
+        .col_expr(
+            account_tokens::Column::UsedAt,
+            Expr::value(DateTimeWithTimeZone::from(Utc::now())),
+        )
+        .filter(account_tokens::Column::UserId.eq(user_id))
+        .filter(account_tokens::Column::Purpose.eq(AccountTokenPurpose::PasswordReset))
+        .filter(account_tokens::Column::UsedAt.is_null())
+        .exec(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    revoke_user_sessions(&txn, user_id, Some(claims.sid)).await?;
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!("password changed");
+    Ok(())
+}
+
+/// Removes every stored rendition of a user's avatar.
+pub async fn delete_avatar_files(storage: &dyn Storage, user_id: i32) -> Result<(), AppError> {
+    for size in AVATAR_SIZES {
+        storage.delete(&avatar_key(user_id, size)).await?;
+    }
+    Ok(())
+}
+
+/// Loose BCP 47 check: a two- or three-letter language followed by alphanumeric subtags.
+fn is_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let language = subtags.next().unwrap_or_default();
+    tag.len() <= 35
+        && (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
@@ -0,0 +1,400 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRef, FromRequestParts, Path, State},
+    http::{
+        header::{FORWARDED, USER_AGENT},
+        request::Parts,
+        HeaderMap, StatusCode,
+    },
+    Json,
+};
+use chrono::Utc;
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, sea_query::Expr, ActiveModelTrait, ColumnTrait, ConnectionTrait,
+    DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, Set,
+};
+use serde::Serialize;
+use tracing::instrument;
+use uuid::Uuid;
+
+use super::auth::{record_refresh_token, TokenResponse};
+use super::guard::{AuthUser, SessionClaims};
+use crate::config::AuthConfig;
+use crate::database::{
+    prelude::{RefreshTokens, Sessions},
+    refresh_tokens, sessions,
+};
+use crate::utils::app_error::AppError;
+use crate::utils::jwt::{create_jwt, JwtConfig};
+use crate::utils::token::generate_opaque_token;
+
+/// Where a request came from, recorded against the sessions it starts.
+#[derive(Debug, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// The address is the peer's, unless the peer is one of `AuthConfig::trusted_proxies`: then
+/// it's the nearest untrusted hop in `Forwarded`, or in `X-Forwarded-For` without it.
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    AuthConfig: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        let auth = AuthConfig::from_ref(state);
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| {
+                client_ip(addr.ip(), &parts.headers, &auth.trusted_proxies).to_string()
+            });
+        Ok(ClientInfo { user_agent, ip })
+    }
+}
+
+/// Walks the forwarded hops back from `peer` while they're trusted proxies. A hop that
+/// isn't an address, such as `unknown`, stops the walk at the proxy that reported it.
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    let mut hops = forwarded_hops(headers).into_iter().rev();
+    while trusted_proxies.contains(&client) {
+        match hops.next() {
+            Some(Some(hop)) => client = hop,
+            _ => break,
+        }
+    }
+    client
+}
+
+/// The `for` addresses of `Forwarded`, or else of `X-Forwarded-For`, client first.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+            .map(str::trim)
+            .filter(|element| !element.is_empty())
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values(FORWARDED.as_str());
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(key, _)| key.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect();
+    }
+    values("x-forwarded-for")
+        .into_iter()
+        .map(parse_node)
+        .collect()
+}
+
+/// Parses `192.0.2.1`, `192.0.2.1:80`, `2001:db8::1` or `"[2001:db8::1]:80"`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+}
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    id: Uuid,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    created_at: DateTimeWithTimeZone,
+    last_seen_at: DateTimeWithTimeZone,
+    expires_at: DateTimeWithTimeZone,
+    current: bool,
+}
+
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn get_my_sessions(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+    SessionClaims(claims): SessionClaims,
+) -> Result<Json<Vec<SessionResponse>>, AppError> {
+    let sessions = Sessions::find()
+        .filter(sessions::Column::UserId.eq(user.id))
+        .filter(sessions::Column::RevokedAt.is_null())
+        .filter(sessions::Column::ExpiresAt.gt(DateTimeWithTimeZone::from(Utc::now())))
+        .order_by_desc(sessions::Column::LastSeenAt)
+        .all(&database)
+        .await
+        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?
+        .into_iter()
+        .map(|session| SessionResponse {
+            current: session.id == claims.sid,
+            id: session.id,
+            user_agent: session.user_agent,
+            ip: session.ip,
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+            expires_at: session.expires_at,
+        })
+        .collect();
+    Ok(Json(sessions))
+}
+
+#[instrument(skip_all, fields(user_id = user.id, %session_id))]
+pub async fn delete_session(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+    Path(session_id): Path<Uuid>,
+) -> Result<(), AppError> {
+    let session = Sessions::find_by_id(session_id)
+        .filter(sessions::Column::UserId.eq(user.id))
+        .filter(sessions::Column::RevokedAt.is_null())
+        .one(&database)
+        .await
+        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+    if session.is_none() {
+        return Err(AppError::new(StatusCode::NOT_FOUND, "Session not found."));
+    }
+    revoke_session(&database, session_id).await
+}
+
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn delete_other_sessions(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+    SessionClaims(claims): SessionClaims,
+) -> Result<(), AppError> {
+    revoke_user_sessions(&database, user.id, Some(claims.sid)).await
+}
+
+/// Opens a new session for `user_id` and returns its first token pair.
+pub async fn start_session(
+    database: &impl ConnectionTrait,
+    jwt: &JwtConfig,
+    user_id: i32,
+    client: ClientInfo,
+) -> Result<TokenResponse, AppError> {
+    let session_id = Uuid::new_v4();
+    let refresh_token = generate_opaque_token();
+    let now = Utc::now();
+    let expires_at: DateTimeWithTimeZone = (now + jwt.refresh_token_ttl).into();
+    sessions::ActiveModel {
+        id: Set(session_id),
+        user_id: Set(user_id),
+        user_agent: Set(client.user_agent),
+        ip: Set(client.ip),
+        created_at: Set(now.into()),
+        last_seen_at: Set(now.into()),
+        expires_at: Set(expires_at),
+        revoked_at: Set(None),
+    }
+    .insert(database)
+    .await
+    .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+    record_refresh_token(database, user_id, session_id, &refresh_token, expires_at).await?;
+
+    Ok(TokenResponse {
+        token: create_jwt(jwt, user_id, session_id)?,
+        refresh_token,
+    })
+}
+
+/// Revokes a session together with every refresh token issued under it.
+pub async fn revoke_session(
+    database: &impl ConnectionTrait,
+    session_id: Uuid,
+) -> Result<(), AppError> {
+    let now = DateTimeWithTimeZone::from(Utc::now());
+    Sessions::update_many()
+        .col_expr(sessions::Column::RevokedAt, Expr::value(now))
+        .filter(sessions::Column::Id.eq(session_id))
+        .filter(sessions::Column::RevokedAt.is_null())
+        .exec(database)
+        .await
+        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+    RefreshTokens::update_many()
+        .col_expr(refresh_tokens::Column::RevokedAt, Expr::value(now))
+        .filter(refresh_tokens::Column::SessionId.eq(session_id))
+        .filter(refresh_tokens::Column::RevokedAt.is_null())
+        .exec(database)
+        .await
+        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+    Ok(())
+}
+
+/// Revokes every session belonging to `user_id`, optionally sparing `keep`.
+pub async fn revoke_user_sessions(
+    database: &impl ConnectionTrait,
+    user_id: i32,
+    keep: Option<Uuid>,
+) -> Result<(), AppError> {
+    let now = DateTimeWithTimeZone::from(Utc::now());
+    let mut sessions_query = Sessions::update_many()
+        .col_expr(sessions::Column::RevokedAt, Expr::value(now))
+        .filter(sessions::Column::UserId.eq(user_id))
+        .filter(sessions::Column::RevokedAt.is_null());
+    let mut tokens_query = RefreshTokens::update_many()
+        .col_expr(refresh_tokens::Column::RevokedAt, Expr::value(now))
+        .filter(refresh_tokens::Column::UserId.eq(user_id))
+        .filter(refresh_tokens::Column::RevokedAt.is_null());
+    if let Some(keep) = keep {
+        sessions_query = sessions_query.filter(sessions::Column::Id.ne(keep));
+        tokens_query = tokens_query.filter(refresh_tokens::Column::SessionId.ne(keep));
+    }
+    sessions_query
+        .exec(database)
+        .await
+        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+    tokens_query
+        .exec(database)
+        .await
+        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderValue, Method};
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{json_request, TestApp};
+
+    const PROXY: [u8; 4] = [127, 0, 0, 1];
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_addresses_count_only_behind_trusted_proxies() {
+        let trusted = [IpAddr::from(PROXY), ip("10.0.0.2")];
+        let forwarded = headers(&[("x-forwarded-for", "198.51.100.1, 203.0.113.7, 10.0.0.2")]);
+
+        assert_eq!(
+            client_ip(ip("192.0.2.9"), &forwarded, &trusted),
+            ip("192.0.2.9")
+        );
+        assert_eq!(
+            client_ip(PROXY.into(), &forwarded, &[]),
+            IpAddr::from(PROXY)
+        );
+        // The client can prepend anything; only the hop the trusted proxy saw counts.
+        assert_eq!(
+            client_ip(PROXY.into(), &forwarded, &trusted),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client_ip(PROXY.into(), &headers(&[]), &trusted),
+            IpAddr::from(PROXY)
+        );
+    }
+
+    #[test]
+    fn reads_the_forwarded_header_first() {
+        let trusted = [IpAddr::from(PROXY)];
+        let forwarded = headers(&[
+            ("x-forwarded-for", "198.51.100.1"),
+            ("forwarded", "for=192.0.2.43;proto=https"),
+            ("forwarded", "For=\"[2001:db8:cafe::17]:4711\";by=127.0.0.1"),
+        ]);
+        assert_eq!(
+            client_ip(PROXY.into(), &forwarded, &trusted),
+            ip("2001:db8:cafe::17")
+        );
+
+        let forwarded = headers(&[("forwarded", "for=192.0.2.43:8080, for=unknown")]);
+        assert_eq!(
+            client_ip(PROXY.into(), &forwarded, &trusted),
+            IpAddr::from(PROXY)
+        );
+    }
+
+    async fn login_from(app: &TestApp, username: &str, forwarded_for: &str) -> StatusCode {
+        app.call(json_request(
+            Method::POST,
+            "/login",
+            json!({ "username": username, "password": "Wrong-Horse-9" }),
+            &[("x-forwarded-for", forwarded_for)],
+        ))
+        .await
+        .status
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn locks_out_the_forwarded_address_behind_a_trusted_proxy() {
+        let app = TestApp::start(|config| {
+            config.auth.trusted_proxies = vec![PROXY.into()];
+            config.lockout.max_failures_per_ip = 3;
+        })
+        .await;
+
+        for username in ["a@example.com", "b@example.com", "c@example.com"] {
+            assert_eq!(
+                login_from(&app, username, "203.0.113.7").await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert_eq!(
+            login_from(&app, "d@example.com", "203.0.113.7").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // Other clients of the same proxy aren't caught up in it.
+        assert_eq!(
+            login_from(&app, "d@example.com", "203.0.113.8").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn ignores_forwarded_addresses_from_untrusted_peers() {
+        let app = TestApp::start(|config| config.lockout.max_failures_per_ip = 3).await;
+
+        for (username, forwarded_for) in [
+            ("a@example.com", "203.0.113.7"),
+            ("b@example.com", "203.0.113.8"),
+            ("c@example.com", "203.0.113.9"),
+        ] {
+            assert_eq!(
+                login_from(&app, username, forwarded_for).await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert_eq!(
+            login_from(&app, "d@example.com", "203.0.113.10").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+}
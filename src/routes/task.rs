@@ -1,34 +1,39 @@
 use axum::{
-    extract::{Path, Query, State},
+    extract::{OriginalUri, Path, Query, State},
     http::StatusCode,
     response::{IntoResponse, Response},
     Json,
 };
-use axum_extra::{
-    headers::{authorization::Bearer, Authorization},
-    TypedHeader,
-};
 use chrono::{DateTime, FixedOffset};
 use sea_orm::{
-    prelude::DateTimeWithTimeZone, ActiveModelTrait, ColumnTrait, Condition, DatabaseConnection,
-    EntityTrait, IntoActiveModel, QueryFilter, Set,
+    prelude::DateTimeWithTimeZone,
+    sea_query::{Expr, Order},
+    ActiveModelTrait, ColumnTrait, Condition, ConnectionTrait, DatabaseConnection, EntityTrait,
+    FromQueryResult, IntoActiveModel, PaginatorTrait, QueryFilter, QueryOrder, QuerySelect, Set,
+    TransactionTrait,
 };
 use serde::{Deserialize, Serialize};
 
-use crate::database::{
-    tasks::{self, Entity as Tasks},
-    users::{self, Entity as Users},
+use super::guard::AuthUser;
+use super::priority::PriorityLevels;
+use super::workspace::ActiveWorkspace;
+use crate::config::AccountConfig;
+use crate::database::{prelude::Tasks, tasks};
+use crate::utils::app_error::AppError;
+use crate::utils::filter::{filter_error, parse_filter, Comparison, Operator, Test, ValueKind};
+use crate::utils::pagination::{
+    CursorValue, Keyset, Page, PageParams, SortKey, SortOrder, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE,
 };
+use crate::utils::permission::WorkspacePermission;
 
 #[derive(Deserialize)]
 pub struct TaskRequest {
-    id: Option<i32>,
+    /// The key of one of the workspace's priorities.
     priority: Option<String>,
     title: Option<String>,
     completed_at: Option<DateTimeWithTimeZone>,
     description: Option<String>,
     deleted_at: Option<DateTimeWithTimeZone>,
-    user_id: Option<i32>,
     is_default: Option<bool>,
 }
 
@@ -37,9 +42,12 @@ pub struct TaskResponse {
     id: Option<i32>,
     title: String,
     description: Option<String>,
+    /// The key of the task's priority.
     priority: Option<String>,
+    completed_at: Option<DateTime<FixedOffset>>,
     deleted_at: Option<DateTime<FixedOffset>>,
     user_id: Option<i32>,
+    workspace_id: i32,
 }
 
 impl IntoResponse for TaskResponse {
@@ -52,6 +60,102 @@ impl IntoResponse for TaskResponse {
 pub struct TaskQueryParams {
     title: Option<String>,
     priority: Option<String>,
+    /// Comma-separated fields, each optionally prefixed with `-` for descending order,
+    /// e.g. `-priority,title`. Ties are broken by id.
+    sort: Option<String>,
+    /// An expression such as `completed = false and priority in (high, urgent)`; see
+    /// `Filter` for the syntax and `task_filter` for the fields.
+    filter: Option<String>,
+    /// Deleted tasks are hidden unless asked for here or filtered on by `deleted` or
+    /// `deleted_at`.
+    include_deleted: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TaskSortField {
+    Id,
+    Title,
+    Priority,
+    CompletedAt,
+    DeletedAt,
+}
+
+impl TaskSortField {
+    const NAMES: [(&'static str, TaskSortField); 5] = [
+        ("id", TaskSortField::Id),
+        ("title", TaskSortField::Title),
+        ("priority", TaskSortField::Priority),
+        ("completed_at", TaskSortField::CompletedAt),
+        ("deleted_at", TaskSortField::DeletedAt),
+    ];
+
+    fn column(self) -> tasks::Column {
+        match self {
+            TaskSortField::Id => tasks::Column::Id,
+            TaskSortField::Title => tasks::Column::Title,
+            TaskSortField::Priority => tasks::Column::Priority,
+            TaskSortField::CompletedAt => tasks::Column::CompletedAt,
+            TaskSortField::DeletedAt => tasks::Column::DeletedAt,
+        }
+    }
+
+    fn value(self, task: &tasks::Model) -> CursorValue {
+        match self {
+            TaskSortField::Id => task.id.into(),
+            TaskSortField::Title => task.title.clone().into(),
+            TaskSortField::Priority => task.priority.into(),
+            TaskSortField::CompletedAt => task.completed_at.into(),
+            TaskSortField::DeletedAt => task.deleted_at.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TaskSearchParams {
+    q: String,
+    limit: Option<u64>,
+    include_deleted: Option<bool>,
+}
+
+/// A task matching a search, best matches first.
+#[derive(Serialize)]
+pub struct TaskSearchHit {
+    #[serde(flatten)]
+    task: TaskResponse,
+    rank: f32,
+    /// The title and an excerpt of the description, HTML-escaped with matches wrapped in
+    /// `<mark>`.
+    title_highlight: String,
+    description_snippet: Option<String>,
+}
+
+#[derive(FromQueryResult)]
+struct TaskSearchRow {
+    id: i32,
+    priority: Option<i16>,
+    title: String,
+    completed_at: Option<DateTimeWithTimeZone>,
+    description: Option<String>,
+    deleted_at: Option<DateTimeWithTimeZone>,
+    user_id: Option<i32>,
+    workspace_id: i32,
+    rank: f32,
+    title_highlight: String,
+    description_snippet: Option<String>,
+}
+
+/// At most this many words of a search are used.
+const MAX_SEARCH_TERMS: usize = 10;
+
+const MIN_FUZZY_TERM_LENGTH: usize = 5;
+
+/// How close a misspelled word has to be to a word of a title, from 0 to 1.
+const FUZZY_MATCH_THRESHOLD: f32 = 0.4;
+
+/// Task routes are served both at the top level and below `/workspaces/:workspace_id`.
+#[derive(Deserialize)]
+pub struct TaskPath {
+    task_id: i32,
 }
 
 #[derive(Deserialize)]
@@ -61,30 +165,42 @@ pub struct DeleteParams {
 
 pub async fn create_task(
     State(database): State<DatabaseConnection>,
-    authorisation: TypedHeader<Authorization<Bearer>>,
+    State(account): State<AccountConfig>,
+    AuthUser(user): AuthUser,
+    active: ActiveWorkspace,
     Json(req): Json<TaskRequest>,
 ) -> Result<(StatusCode, TaskResponse), (StatusCode, String)> {
-    let token = authorisation.token();
-    let user = if let Some(user) = Users::find()
-        .filter(users::Column::Token.eq(token))
-        .one(&database)
-        .await
-        .map_err(|error| (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?
-    {
-        user
-    } else {
-        return Err((StatusCode::UNAUTHORIZED, "Action not allowed.".to_owned()));
+    active.require(WorkspacePermission::WriteTasks)?;
+    let Some(title) = req.title else {
+        return Err((StatusCode::BAD_REQUEST, "Title is required.".to_owned()));
     };
 
-    if let None = req.title {
-        return Err((StatusCode::BAD_REQUEST, "Title is required.".to_owned()));
+    if user.email_verified_at.is_none() {
+        let task_count = Tasks::find()
+            .filter(tasks::Column::UserId.eq(user.id))
+            .count(&database)
+            .await
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        if task_count >= account.unverified_task_limit {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "Verify your email address to create more tasks.".to_owned(),
+            ));
+        }
     }
 
+    let priorities = PriorityLevels::load(&database, active.workspace.id).await?;
+    let priority = req
+        .priority
+        .map(|priority| priorities.parse(&priority))
+        .transpose()?;
+
     let task = tasks::ActiveModel {
-        title: Set(req.title.unwrap()),
+        title: Set(title),
         description: Set(req.description),
-        priority: Set(req.priority),
+        priority: Set(priority),
         user_id: Set(Some(user.id)),
+        workspace_id: Set(active.workspace.id),
         ..Default::default()
     };
 
@@ -95,110 +211,268 @@ pub async fn create_task(
                 id: Some(saved_task.id.unwrap()),
                 title: saved_task.title.unwrap(),
                 description: saved_task.description.unwrap(),
-                priority: saved_task.priority.unwrap(),
+                priority: priorities.key(saved_task.priority.unwrap()),
+                completed_at: saved_task.completed_at.unwrap(),
                 deleted_at: saved_task.deleted_at.unwrap(),
                 user_id: Some(user.id),
+                workspace_id: active.workspace.id,
             },
         )),
         Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
     }
 }
 
-/**
- * todo paginate
- * map_err here to show diff (more idiomatic) way of err handling
- */
+/// Lists the workspace's tasks page by page, sorted by `sort` (id by default).
 pub async fn get_all_tasks(
     State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+    active: ActiveWorkspace,
     Query(query_params): Query<TaskQueryParams>,
-) -> Result<Json<Vec<TaskResponse>>, StatusCode> {
-    let conditions = parse_query_params_into_conditions(query_params);
-    let tasks = Tasks::find()
-        .filter(conditions)
+    Query(page): Query<PageParams>,
+    OriginalUri(uri): OriginalUri,
+) -> Result<Json<Page<TaskResponse>>, (StatusCode, String)> {
+    let sort = parse_sort(query_params.sort.as_deref())?;
+    let keyset = Keyset::new(
+        page,
+        sort.iter()
+            .map(|&(field, order)| SortKey {
+                column: field.column(),
+                order,
+            })
+            .collect(),
+    )?;
+    let priorities = PriorityLevels::load(&database, active.workspace.id).await?;
+    let conditions = parse_query_params_into_conditions(query_params, user.id, &priorities)?;
+    let select = Tasks::find()
+        .filter(tasks::Column::WorkspaceId.eq(active.workspace.id))
+        .filter(conditions);
+    let total = match keyset.wants_total() {
+        true => Some(
+            select
+                .clone()
+                .count(&database)
+                .await
+                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?,
+        ),
+        false => None,
+    };
+    let tasks = keyset
+        .apply(select)
         .all(&database)
         .await
-        .map_err(|_err| StatusCode::INTERNAL_SERVER_ERROR)?
-        .into_iter()
-        .map(|task| TaskResponse {
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let page = keyset.page(
+        tasks,
+        |task| sort.iter().map(|&(field, _)| field.value(task)).collect(),
+        |task| TaskResponse {
             id: Some(task.id),
             title: task.title,
             description: task.description,
-            priority: task.priority,
+            priority: priorities.key(task.priority),
+            completed_at: task.completed_at,
             deleted_at: task.deleted_at,
             user_id: task.user_id,
+            workspace_id: task.workspace_id,
+        },
+        total,
+        &uri,
+    )?;
+    Ok(Json(page))
+}
+
+/// Searches titles and descriptions of the workspace's tasks. Every word matches as a prefix
+/// after stemming, and titles also match misspellings of longer words.
+pub async fn search_tasks(
+    State(database): State<DatabaseConnection>,
+    active: ActiveWorkspace,
+    Query(params): Query<TaskSearchParams>,
+) -> Result<Json<Vec<TaskSearchHit>>, (StatusCode, String)> {
+    let terms: Vec<&str> = params
+        .q
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .take(MAX_SEARCH_TERMS)
+        .collect();
+    if terms.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Search for at least one word.".to_owned(),
+        ));
+    }
+    // Only letters and digits get this far, so the terms can't smuggle in tsquery syntax.
+    let tsquery = terms
+        .iter()
+        .map(|term| format!("{}:*", term.to_lowercase()))
+        .collect::<Vec<_>>()
+        .join(" & ");
+    // Short words have too few trigrams to tell a typo from a different word.
+    let fuzzy = terms
+        .iter()
+        .filter(|term| term.chars().count() >= MIN_FUZZY_TERM_LENGTH)
+        .copied()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let mut select = Tasks::find()
+        .filter(tasks::Column::WorkspaceId.eq(active.workspace.id))
+        .filter(Expr::cust_with_values(
+            "(search @@ to_tsquery('english', $1) OR $2 <% title)",
+            [tsquery.clone(), fuzzy.clone()],
+        ))
+        .column_as(
+            Expr::cust_with_values(
+                "ts_rank_cd(search, to_tsquery('english', $1)) + word_similarity($2, title)",
+                [tsquery.clone(), fuzzy],
+            ),
+            "rank",
+        )
+        .column_as(
+            Expr::cust_with_values(
+                format!(
+                    "ts_headline('english', {}, to_tsquery('english', $1), \
+                     'StartSel=<mark>, StopSel=</mark>, HighlightAll=true')",
+                    html_escape_sql("title"),
+                ),
+                [tsquery.clone()],
+            ),
+            "title_highlight",
+        )
+        .column_as(
+            Expr::cust_with_values(
+                format!(
+                    "CASE WHEN description IS NULL THEN NULL ELSE ts_headline('english', {}, \
+                     to_tsquery('english', $1), 'StartSel=<mark>, StopSel=</mark>, \
+                     MaxFragments=2, MaxWords=25, MinWords=8, FragmentDelimiter=\" … \"') END",
+                    html_escape_sql("description"),
+                ),
+                [tsquery],
+            ),
+            "description_snippet",
+        )
+        .order_by(Expr::cust("rank"), Order::Desc)
+        .order_by_asc(tasks::Column::Id)
+        .limit(limit);
+    if !params.include_deleted.unwrap_or(false) {
+        select = select.filter(tasks::Column::DeletedAt.is_null());
+    }
+
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    // Set per query so the `<%` operator, and with it the trigram index, uses our threshold.
+    txn.execute_unprepared(&format!(
+        "SET LOCAL pg_trgm.word_similarity_threshold = {FUZZY_MATCH_THRESHOLD}"
+    ))
+    .await
+    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let rows = select
+        .into_model::<TaskSearchRow>()
+        .all(&txn)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    txn.commit()
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let priorities = PriorityLevels::load(&database, active.workspace.id).await?;
+    let hits = rows
+        .into_iter()
+        .map(|row| TaskSearchHit {
+            task: TaskResponse {
+                id: Some(row.id),
+                title: row.title,
+                description: row.description,
+                priority: priorities.key(row.priority),
+                completed_at: row.completed_at,
+                deleted_at: row.deleted_at,
+                user_id: row.user_id,
+                workspace_id: row.workspace_id,
+            },
+            rank: row.rank,
+            title_highlight: row.title_highlight,
+            description_snippet: row.description_snippet,
         })
         .collect();
-    Ok(Json(tasks))
+    Ok(Json(hits))
 }
 
 pub async fn get_task(
-    Path(task_id): Path<i32>,
+    Path(TaskPath { task_id }): Path<TaskPath>,
     State(database): State<DatabaseConnection>,
-) -> Result<TaskResponse, StatusCode> {
-    let db_req = Tasks::find_by_id(task_id)
+    active: ActiveWorkspace,
+) -> Result<TaskResponse, (StatusCode, String)> {
+    let task = Tasks::find_by_id(task_id)
+        .filter(tasks::Column::WorkspaceId.eq(active.workspace.id))
         .filter(tasks::Column::DeletedAt.is_null())
         .one(&database)
-        .await;
-    if let Err(error) = db_req {
-        eprintln!("{error}");
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-    }
-
-    match db_req.unwrap() {
-        Some(task) => Ok(TaskResponse {
-            id: Some(task.id),
-            title: task.title,
-            description: task.description,
-            priority: task.priority,
-            deleted_at: task.deleted_at,
-            user_id: task.user_id,
-        }),
-        None => Err(StatusCode::NOT_FOUND),
-    }
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, String::new()))?;
+    let priorities = PriorityLevels::load(&database, active.workspace.id).await?;
+    Ok(TaskResponse {
+        id: Some(task.id),
+        title: task.title,
+        description: task.description,
+        priority: priorities.key(task.priority),
+        completed_at: task.completed_at,
+        deleted_at: task.deleted_at,
+        user_id: task.user_id,
+        workspace_id: task.workspace_id,
+    })
 }
 
 pub async fn atomic_task_update(
-    Path(task_id): Path<i32>,
+    Path(TaskPath { task_id }): Path<TaskPath>,
     State(database): State<DatabaseConnection>,
+    active: ActiveWorkspace,
     Json(req): Json<TaskRequest>,
 ) -> Result<(), (StatusCode, String)> {
-    if let None = req.title {
+    active.require(WorkspacePermission::WriteTasks)?;
+    let Some(title) = req.title else {
         return Err((StatusCode::BAD_REQUEST, "Title is required.".to_owned()));
-    }
+    };
+    let existing = find_workspace_task(&database, task_id, active.workspace.id).await?;
+    let priorities = PriorityLevels::load(&database, active.workspace.id).await?;
+    let priority = req
+        .priority
+        .map(|priority| priorities.parse(&priority))
+        .transpose()?;
 
     let concrete_task = tasks::ActiveModel {
         id: Set(task_id),
-        priority: Set(req.priority),
-        title: Set(req.title.unwrap()),
+        priority: Set(priority),
+        title: Set(title),
         completed_at: Set(req.completed_at),
         description: Set(req.description),
         deleted_at: Set(req.deleted_at),
-        user_id: Set(req.user_id),
+        user_id: Set(existing.user_id),
         is_default: Set(req.is_default),
+        workspace_id: Set(active.workspace.id),
     };
 
-    let _ = Tasks::update(concrete_task)
+    Tasks::update(concrete_task)
         .filter(tasks::Column::Id.eq(task_id))
+        .filter(tasks::Column::WorkspaceId.eq(active.workspace.id))
         .exec(&database)
         .await
-        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
     Ok(())
 }
 
 pub async fn partial_task_update(
-    Path(task_id): Path<i32>,
+    Path(TaskPath { task_id }): Path<TaskPath>,
     State(database): State<DatabaseConnection>,
+    active: ActiveWorkspace,
     Json(req): Json<TaskRequest>,
 ) -> Result<(), (StatusCode, String)> {
-    let mut task = if let Some(task) = Tasks::find_by_id(task_id)
-        .one(&database)
-        .await
-        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
-    {
-        task.into_active_model()
-    } else {
-        return Err((StatusCode::NOT_FOUND, String::new()));
-    };
+    active.require(WorkspacePermission::WriteTasks)?;
+    let mut task = find_workspace_task(&database, task_id, active.workspace.id)
+        .await?
+        .into_active_model();
     if let Some(description) = req.description {
         task.description = match description.is_empty() {
             true => Set(None),
@@ -208,54 +482,142 @@ pub async fn partial_task_update(
     if let Some(priority) = req.priority {
         task.priority = match priority.is_empty() {
             true => Set(None),
-            false => Set(Some(priority)),
+            false => {
+                let priorities = PriorityLevels::load(&database, active.workspace.id).await?;
+                Set(Some(priorities.parse(&priority)?))
+            }
         }
     }
-    let _ = Tasks::update(task)
+    Tasks::update(task)
         .filter(tasks::Column::Id.eq(task_id))
+        .filter(tasks::Column::WorkspaceId.eq(active.workspace.id))
         .exec(&database)
         .await
-        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
     Ok(())
 }
 
 pub async fn delete_task(
-    Path(task_id): Path<i32>,
+    Path(TaskPath { task_id }): Path<TaskPath>,
     State(database): State<DatabaseConnection>,
+    active: ActiveWorkspace,
     Query(query_params): Query<DeleteParams>,
 ) -> Result<(), (StatusCode, String)> {
+    active.require(WorkspacePermission::WriteTasks)?;
     if let Some(soft) = query_params.soft {
         if soft {
-            let mut task = if let Some(task) = Tasks::find_by_id(task_id)
-                .one(&database)
-                .await
-                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
-            {
-                task.into_active_model()
-            } else {
-                return Err((StatusCode::NOT_FOUND, String::new()));
-            };
+            let mut task = find_workspace_task(&database, task_id, active.workspace.id)
+                .await?
+                .into_active_model();
             task.deleted_at = Set(Some(chrono::Utc::now().into()));
 
-            let _ = Tasks::update(task)
+            Tasks::update(task)
                 .filter(tasks::Column::Id.eq(task_id))
+                .filter(tasks::Column::WorkspaceId.eq(active.workspace.id))
                 .exec(&database)
                 .await
-                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
             return Ok(());
         }
     }
-    let _ = Tasks::delete_many()
+    let result = Tasks::delete_many()
         .filter(tasks::Column::Id.eq(task_id))
+        .filter(tasks::Column::WorkspaceId.eq(active.workspace.id))
         .exec(&database)
         .await
         .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if result.rows_affected == 0 {
+        return Err((StatusCode::NOT_FOUND, String::new()));
+    }
     Ok(())
 }
 
-fn parse_query_params_into_conditions(params: TaskQueryParams) -> Condition {
+/// Loads a task only if it belongs to `workspace_id`. Tasks in other workspaces are
+/// reported as 404 so callers can't probe for their existence.
+async fn find_workspace_task(
+    database: &DatabaseConnection,
+    task_id: i32,
+    workspace_id: i32,
+) -> Result<tasks::Model, (StatusCode, String)> {
+    Tasks::find_by_id(task_id)
+        .filter(tasks::Column::WorkspaceId.eq(workspace_id))
+        .one(database)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, String::new()))
+}
+
+/// SQL escaping `column` for HTML, so highlighted snippets only ever contain our own tags.
+fn html_escape_sql(column: &str) -> String {
+    format!(
+        "replace(replace(replace(replace({column}, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), \
+         '\"', '&quot;')"
+    )
+}
+
+/// Parses the `sort` parameter into sort keys, always ending with `id` so that every task
+/// has a distinct position.
+fn parse_sort(sort: Option<&str>) -> Result<Vec<(TaskSortField, SortOrder)>, (StatusCode, String)> {
+    let mut keys: Vec<(TaskSortField, SortOrder)> = Vec::new();
+    for name in sort.unwrap_or_default().split(',').map(str::trim) {
+        if name.is_empty() {
+            continue;
+        }
+        let (name, order) = match name.strip_prefix('-') {
+            Some(name) => (name, SortOrder::Desc),
+            None => (name, SortOrder::Asc),
+        };
+        let Some(&(_, field)) = TaskSortField::NAMES
+            .iter()
+            .find(|(known, _)| *known == name)
+        else {
+            let known: Vec<_> = TaskSortField::NAMES
+                .iter()
+                .map(|(known, _)| *known)
+                .collect();
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Can't sort by `{name}`; use {}.", known.join(", ")),
+            ));
+        };
+        if keys.iter().any(|&(existing, _)| existing == field) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("`{name}` appears more than once in `sort`."),
+            ));
+        }
+        keys.push((field, order));
+        // Ids are unique, so later keys could never decide anything.
+        if field == TaskSortField::Id {
+            return Ok(keys);
+        }
+    }
+    keys.push((TaskSortField::Id, SortOrder::Asc));
+    Ok(keys)
+}
+
+fn parse_query_params_into_conditions(
+    params: TaskQueryParams,
+    user_id: i32,
+    priorities: &PriorityLevels,
+) -> Result<Condition, AppError> {
     let mut filter = Condition::all();
-    filter = filter.add(tasks::Column::DeletedAt.is_null());
+    let expression = match params.filter.as_deref().map(str::trim) {
+        Some(expression) if !expression.is_empty() => Some(parse_filter(expression)?),
+        _ => None,
+    };
+    let wants_deleted = params.include_deleted.unwrap_or(false)
+        || expression
+            .as_ref()
+            .is_some_and(|expression| expression.mentions(&["deleted", "deleted_at"]));
+    if !wants_deleted {
+        filter = filter.add(tasks::Column::DeletedAt.is_null());
+    }
+    if let Some(expression) = expression {
+        filter = filter.add(
+            expression.to_condition(&|comparison| task_filter(comparison, user_id, priorities))?,
+        );
+    }
     if let Some(title) = params.title {
         filter = if title.is_empty() {
             filter.add(tasks::Column::Title.is_null())
@@ -267,8 +629,274 @@ fn parse_query_params_into_conditions(params: TaskQueryParams) -> Condition {
         filter = if priority.is_empty() {
             filter.add(tasks::Column::Priority.is_null())
         } else {
-            filter.add(tasks::Column::Priority.eq(priority))
+            filter.add(tasks::Column::Priority.eq(priorities.parse(&priority)?))
+        }
+    }
+    Ok(filter)
+}
+
+const FILTER_FIELDS: [&str; 10] = [
+    "id",
+    "title",
+    "description",
+    "priority",
+    "completed",
+    "completed_at",
+    "deleted",
+    "deleted_at",
+    "is_default",
+    "owner",
+];
+
+/// The condition for one comparison of a `filter=` expression. `completed` and `deleted`
+/// are true when the matching timestamp is set; `owner` takes a user id or `me`; `priority`
+/// takes the workspace's keys and compares by their order, as in `priority >= high`.
+fn task_filter(
+    comparison: &Comparison,
+    user_id: i32,
+    priorities: &PriorityLevels,
+) -> Result<Condition, AppError> {
+    match comparison.field.text.as_str() {
+        "id" => comparison.on_column(tasks::Column::Id, ValueKind::Int),
+        "title" => comparison.on_column(tasks::Column::Title, ValueKind::Text),
+        "description" => comparison.on_column(tasks::Column::Description, ValueKind::Text),
+        // Priorities are stored as numbers, which reject `~` before a key is looked up.
+        "priority" if matches!(comparison.test, Test::Compare(Operator::Contains, _)) => {
+            comparison.on_column(tasks::Column::Priority, ValueKind::Int)
+        }
+        "priority" => comparison
+            .map_values(|value| match priorities.find(&value.text) {
+                Some(level) => Ok(level.to_string()),
+                None => Err(filter_error(
+                    value.at,
+                    format!(
+                        "unknown priority `{}`; {}",
+                        value.text,
+                        priorities.describe()
+                    ),
+                )),
+            })?
+            .on_column(tasks::Column::Priority, ValueKind::Int),
+        "completed" => comparison.on_presence(tasks::Column::CompletedAt),
+        "completed_at" => comparison.on_column(tasks::Column::CompletedAt, ValueKind::Time),
+        "deleted" => comparison.on_presence(tasks::Column::DeletedAt),
+        "deleted_at" => comparison.on_column(tasks::Column::DeletedAt, ValueKind::Time),
+        "is_default" => comparison.on_column(tasks::Column::IsDefault, ValueKind::Bool),
+        "owner" => comparison
+            .with_alias("me", &user_id.to_string())
+            .on_column(tasks::Column::UserId, ValueKind::Int),
+        field => Err(filter_error(
+            comparison.field.at,
+            format!("unknown field `{field}`; use {}", FILTER_FIELDS.join(", ")),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::{json, Value};
+    use url::form_urlencoded::byte_serialize;
+
+    use crate::test_support::{empty_request, json_request, TestApp};
+
+    /// Creates a task at `uri` and returns its id.
+    async fn create(app: &TestApp, bearer: &str, uri: &str, title: &str, description: &str) -> i64 {
+        let response = app
+            .call(json_request(
+                Method::POST,
+                uri,
+                json!({ "title": title, "description": description }),
+                &[("authorization", bearer)],
+            ))
+            .await;
+        assert_eq!(response.status, StatusCode::CREATED, "{}", response.text);
+        response.body["id"].as_i64().unwrap()
+    }
+
+    async fn search(app: &TestApp, bearer: &str, query: &str) -> (StatusCode, Vec<Value>) {
+        let query: String = byte_serialize(query.as_bytes()).collect();
+        let response = app
+            .call(empty_request(
+                Method::GET,
+                &format!("/tasks/search?q={query}"),
+                &[("authorization", bearer)],
+            ))
+            .await;
+        let hits = response.body.as_array().cloned().unwrap_or_default();
+        (response.status, hits)
+    }
+
+    fn ids(hits: &[Value]) -> Vec<i64> {
+        hits.iter().map(|hit| hit["id"].as_i64().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn title_matches_rank_above_description_matches() {
+        let app = TestApp::start(|_| {}).await;
+        let bearer = format!("Bearer {}", app.sign_up("ada@example.com").await);
+        let in_description = create(
+            &app,
+            &bearer,
+            "/tasks",
+            "Tidy the wiki",
+            "Mention the invoice template",
+        )
+        .await;
+        let in_title = create(&app, &bearer, "/tasks", "Send the invoice", "By Friday").await;
+        create(
+            &app,
+            &bearer,
+            "/tasks",
+            "Water the plants",
+            "Every other day",
+        )
+        .await;
+
+        let (status, hits) = search(&app, &bearer, "invoice").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ids(&hits), [in_title, in_description]);
+        assert!(hits[0]["rank"].as_f64() > hits[1]["rank"].as_f64());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn words_match_as_prefixes_and_titles_match_misspellings() {
+        let app = TestApp::start(|_| {}).await;
+        let bearer = format!("Bearer {}", app.sign_up("ada@example.com").await);
+        let deployment = create(
+            &app,
+            &bearer,
+            "/tasks",
+            "Plan the deployment",
+            "Staging first",
+        )
+        .await;
+        let quarterly = create(&app, &bearer, "/tasks", "Quarterly report", "For the board").await;
+
+        let (_, hits) = search(&app, &bearer, "deplo").await;
+        assert_eq!(ids(&hits), [deployment]);
+        let (_, hits) = search(&app, &bearer, "quartrly").await;
+        assert_eq!(ids(&hits), [quarterly]);
+        let (_, hits) = search(&app, &bearer, "unrelated").await;
+        assert!(hits.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn highlights_matches_in_escaped_text() {
+        let app = TestApp::start(|_| {}).await;
+        let bearer = format!("Bearer {}", app.sign_up("ada@example.com").await);
+        create(
+            &app,
+            &bearer,
+            "/tasks",
+            "Escape <script> in the banner",
+            "The banner shows \"<b>bold</b>\" & breaks the banner layout",
+        )
+        .await;
+
+        let (_, hits) = search(&app, &bearer, "banner").await;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(
+            hits[0]["title_highlight"],
+            "Escape &lt;script&gt; in the <mark>banner</mark>"
+        );
+        let snippet = hits[0]["description_snippet"].as_str().unwrap();
+        assert!(snippet.contains("<mark>banner</mark>"), "{snippet}");
+        assert!(
+            snippet.contains("&quot;&lt;b&gt;bold&lt;/b&gt;&quot; &amp; breaks"),
+            "{snippet}"
+        );
+        assert!(!snippet.contains("<b>"), "{snippet}");
+        // The task itself comes back as it was written.
+        assert_eq!(hits[0]["title"], "Escape <script> in the banner");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn never_returns_other_workspaces_or_deleted_tasks() {
+        let app = TestApp::start(|_| {}).await;
+        let bearer = format!("Bearer {}", app.sign_up("ada@example.com").await);
+        let other = format!("Bearer {}", app.sign_up("grace@example.com").await);
+        let visible = create(&app, &bearer, "/tasks", "Renew the domain", "").await;
+        let deleted = create(&app, &bearer, "/tasks", "Renew the certificate", "").await;
+        let response = app
+            .call(empty_request(
+                Method::DELETE,
+                &format!("/tasks/{deleted}?soft=true"),
+                &[("authorization", &bearer)],
+            ))
+            .await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.text);
+        create(&app, &other, "/tasks", "Renew the lease", "").await;
+
+        let (_, hits) = search(&app, &bearer, "renew").await;
+        assert_eq!(ids(&hits), [visible]);
+        let (_, hits) = search(&app, &other, "renew").await;
+        assert_eq!(hits.len(), 1);
+        assert!(!ids(&hits).contains(&visible));
+
+        let response = app
+            .call(json_request(
+                Method::POST,
+                "/workspaces",
+                json!({ "name": "Side project" }),
+                &[("authorization", &bearer)],
+            ))
+            .await;
+        assert_eq!(response.status, StatusCode::CREATED, "{}", response.text);
+        let workspace_id = response.body["id"].as_i64().unwrap();
+        let elsewhere = create(
+            &app,
+            &bearer,
+            &format!("/workspaces/{workspace_id}/tasks"),
+            "Renew the passport",
+            "",
+        )
+        .await;
+        let response = app
+            .call(empty_request(
+                Method::GET,
+                &format!("/workspaces/{workspace_id}/tasks/search?q=renew"),
+                &[("authorization", &bearer)],
+            ))
+            .await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.text);
+        assert_eq!(ids(response.body.as_array().unwrap()), [elsewhere]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn tsquery_syntax_in_the_search_is_taken_as_words() {
+        let app = TestApp::start(|_| {}).await;
+        let bearer = format!("Bearer {}", app.sign_up("ada@example.com").await);
+        let task = create(
+            &app,
+            &bearer,
+            "/tasks",
+            "Review the budget",
+            "Costs & income",
+        )
+        .await;
+
+        for query in [
+            "budget & review",
+            "budget | nothing",
+            "!budget",
+            "budget:*",
+            "\"review the budget\"",
+            "'budget' <-> (review)",
+            "review:A & budget:* | !(x)",
+        ] {
+            let (status, hits) = search(&app, &bearer, query).await;
+            assert_eq!(status, StatusCode::OK, "{query}");
+            assert!(ids(&hits).contains(&task), "{query}");
+        }
+        for query in ["&", "!", ":*", "\"\"", "| & !"] {
+            let (status, _) = search(&app, &bearer, query).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{query}");
         }
     }
-    filter
 }
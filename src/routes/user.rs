@@ -1,132 +1,370 @@
-use axum::extract::State;
-use axum::{http::StatusCode, Extension, Json};
+use std::sync::Arc;
+
+use axum::extract::{OriginalUri, Query, State};
+use axum::{http::StatusCode, Json};
+use axum_extra::extract::CookieJar;
 use sea_orm::{
-    ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter,
-    Set,
+    sea_query::{self, Expr, Func, LikeExpr},
+    ActiveModelTrait, ColumnTrait, Condition, DatabaseConnection, EntityTrait, PaginatorTrait,
+    QueryFilter, Set, TransactionTrait,
 };
 use serde::{Deserialize, Serialize};
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 use validator::Validate;
 
-use crate::database::users::{self, Entity as Users, Model};
+use super::account::send_welcome_verification;
+use super::auth::TokenResponse;
+use super::cookie::{clear_session_cookies, deliver_tokens};
+use super::guard::{AuthUser, SessionClaims};
+use super::mfa::{complete_sign_in, LoginResponse};
+use super::profile::avatar_url;
+use super::session::{revoke_session, start_session, ClientInfo};
+use super::workspace::create_personal_workspace;
+use crate::config::{AccountConfig, AuthConfig};
+use crate::database::prelude::{Users, WorkspaceMemberships};
+use crate::database::sea_orm_active_enums::UserRole;
+use crate::database::{users, workspace_memberships};
 use crate::utils::app_error::AppError;
-use crate::utils::jwt::create_jwt;
-use crate::utils::password::{hash_password, validate_password, verify_password};
+use crate::utils::filter::escape_like;
+use crate::utils::jwt::JwtConfig;
+use crate::utils::lockout::LoginThrottle;
+use crate::utils::mailer::Mailer;
+use crate::utils::pagination::{Keyset, Page, PageParams, SortKey, SortOrder};
+use crate::utils::password::{PasswordHasher, Verification};
+use crate::utils::password_policy::PasswordPolicy;
+use crate::utils::permission::Permission;
+use crate::utils::totp::TotpConfig;
 
 #[derive(Debug, Serialize)]
 pub struct UserResponse {
     id: i32,
     username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+}
+
+impl UserResponse {
+    /// `tokens` is `None` when they were only delivered as cookies.
+    pub fn signed_in(user: users::Model, tokens: Option<TokenResponse>) -> Self {
+        let (token, refresh_token) = match tokens {
+            Some(tokens) => (Some(tokens.token), Some(tokens.refresh_token)),
+            None => (None, None),
+        };
+        UserResponse {
+            id: user.id,
+            username: user.username,
+            token,
+            refresh_token,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserStatus {
+    Active,
+    Disabled,
+    Deleted,
+}
+
+impl UserStatus {
+    fn of(user: &users::Model) -> Self {
+        match (user.deleted_at, user.disabled_at) {
+            (Some(_), _) => UserStatus::Deleted,
+            (None, Some(_)) => UserStatus::Disabled,
+            (None, None) => UserStatus::Active,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DirectoryQuery {
+    /// Case-insensitive prefix of the username or display name.
+    q: Option<String>,
+    status: Option<UserStatus>,
+}
+
+#[derive(Serialize)]
+pub struct DirectoryEntry {
+    id: i32,
+    username: String,
+    display_name: Option<String>,
+    avatar_url: Option<String>,
+    /// Only shown to admins.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<UserRole>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<UserStatus>,
+}
+
+impl DirectoryEntry {
+    fn new(user: users::Model, admin: bool) -> Self {
+        DirectoryEntry {
+            avatar_url: avatar_url(&user),
+            status: admin.then(|| UserStatus::of(&user)),
+            role: admin.then_some(user.role),
+            id: user.id,
+            username: user.username,
+            display_name: user.display_name,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Validate)]
 pub struct UserRequest {
     #[validate(email)]
     username: String,
-    #[validate(custom(function=validate_password))]
     password: String,
 }
 
-#[instrument(skip(database))]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(username = %user_req.username))]
 pub async fn create_user(
     State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    State(auth): State<AuthConfig>,
+    State(hasher): State<PasswordHasher>,
+    State(mailer): State<Arc<dyn Mailer>>,
+    State(account): State<AccountConfig>,
+    State(password_policy): State<PasswordPolicy>,
+    client: ClientInfo,
+    jar: CookieJar,
     Json(user_req): Json<UserRequest>,
-) -> Result<Json<UserResponse>, AppError> {
+) -> Result<(CookieJar, Json<UserResponse>), AppError> {
     if let Err(err) = user_req.validate() {
         return Err(AppError::new(StatusCode::BAD_REQUEST, format!("{}", err)));
     }
+    password_policy.check(&user_req.password, Some(&user_req.username))?;
 
+    let password = hasher.hash(user_req.password).await?;
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
     let user_model = users::ActiveModel {
         username: Set(user_req.username),
-        password: Set(hash_password(user_req.password).unwrap()),
-        token: Set(Some(create_jwt()?)),
+        password: Set(Some(password)),
         ..Default::default()
     }
-    .save(&database)
+    .insert(&txn)
     .await
     .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
-    let response = UserResponse {
-        id: user_model.id.unwrap(),
-        username: user_model.username.unwrap(),
-        token: user_model.token.unwrap(),
-    };
-    info!("{:?}", response);
-    Ok(Json(response))
+    create_personal_workspace(&txn, user_model.id).await?;
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if let Err(error) =
+        send_welcome_verification(&database, &jwt, mailer, &account, &user_model).await
+    {
+        warn!(?error, "could not send verification email");
+    }
+    let tokens = start_session(&database, &jwt, user_model.id, client).await?;
+    let (jar, tokens) = deliver_tokens(jar, &auth, &jwt, tokens);
+    let response = UserResponse::signed_in(user_model, tokens);
+    info!(user_id = response.id, "user created");
+    Ok((jar, Json(response)))
 }
 
-#[instrument]
+/// Who a user can look up: admins see everyone, everybody else only the people they share a
+/// workspace with.
+pub fn visible_to(caller: &users::Model) -> Condition {
+    if Permission::ManageUsers.granted_to(caller.role) {
+        return Condition::all();
+    }
+    let my_workspaces = sea_query::Query::select()
+        .column(workspace_memberships::Column::WorkspaceId)
+        .from(WorkspaceMemberships)
+        .and_where(workspace_memberships::Column::UserId.eq(caller.id))
+        .to_owned();
+    let co_members = sea_query::Query::select()
+        .column(workspace_memberships::Column::UserId)
+        .from(WorkspaceMemberships)
+        .and_where(workspace_memberships::Column::WorkspaceId.in_subquery(my_workspaces))
+        .to_owned();
+    Condition::any()
+        .add(users::Column::Id.eq(caller.id))
+        .add(users::Column::Id.in_subquery(co_members))
+}
+
+/// Lists users page by page in username order, without any credentials. Admins may also
+/// list disabled and deleted accounts.
+#[instrument(skip(database, caller, uri), fields(user_id = caller.id))]
 pub async fn get_all_users(
     State(database): State<DatabaseConnection>,
-) -> Result<Json<Vec<UserResponse>>, AppError> {
-    let users = Users::find()
+    AuthUser(caller): AuthUser,
+    Query(query): Query<DirectoryQuery>,
+    Query(page): Query<PageParams>,
+    OriginalUri(uri): OriginalUri,
+) -> Result<Json<Page<DirectoryEntry>>, AppError> {
+    let admin = Permission::ManageUsers.granted_to(caller.role);
+    let status_condition = match query.status {
+        Some(UserStatus::Active) => Condition::all()
+            .add(users::Column::DeletedAt.is_null())
+            .add(users::Column::DisabledAt.is_null()),
+        Some(_) if !admin => {
+            return Err(AppError::new(
+                StatusCode::FORBIDDEN,
+                "Only administrators can list inactive users.",
+            ))
+        }
+        Some(UserStatus::Disabled) => Condition::all()
+            .add(users::Column::DeletedAt.is_null())
+            .add(users::Column::DisabledAt.is_not_null()),
+        Some(UserStatus::Deleted) => Condition::all().add(users::Column::DeletedAt.is_not_null()),
+        None if admin => Condition::all().add(users::Column::DeletedAt.is_null()),
+        None => Condition::all()
+            .add(users::Column::DeletedAt.is_null())
+            .add(users::Column::DisabledAt.is_null()),
+    };
+    let keyset = Keyset::new(
+        page,
+        vec![SortKey {
+            column: users::Column::Username,
+            order: SortOrder::Asc,
+        }],
+    )?;
+    let mut select = Users::find()
+        .filter(visible_to(&caller))
+        .filter(status_condition);
+    if let Some(prefix) = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+        let pattern = format!("{}%", escape_like(&prefix.to_lowercase()));
+        select = select.filter(
+            Condition::any()
+                .add(
+                    Expr::expr(Func::lower(Expr::col(users::Column::Username)))
+                        .like(LikeExpr::new(pattern.clone()).escape('\\')),
+                )
+                .add(
+                    Expr::expr(Func::lower(Expr::col(users::Column::DisplayName)))
+                        .like(LikeExpr::new(pattern).escape('\\')),
+                ),
+        );
+    }
+    let total =
+        match keyset.wants_total() {
+            true => Some(select.clone().count(&database).await.map_err(|err| {
+                AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+            })?),
+            false => None,
+        };
+    let users = keyset
+        .apply(select)
         .all(&database)
         .await
-        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
-        .into_iter()
-        .map(|raw_user| UserResponse {
-            id: raw_user.id,
-            username: raw_user.username,
-            token: raw_user.token,
-        })
-        .collect();
-    Ok(Json(users))
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let page = keyset.page(
+        users,
+        |user| vec![user.username.clone().into()],
+        |user| DirectoryEntry::new(user, admin),
+        total,
+        &uri,
+    )?;
+    Ok(Json(page))
 }
 
-#[instrument(skip(database))]
+/// Unknown accounts and wrong passwords get the same answer after the same amount of work, so
+/// the response doesn't reveal which accounts exist.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(username = %user_req.username))]
 pub async fn login(
     State(database): State<DatabaseConnection>,
+    State(jwt): State<JwtConfig>,
+    State(auth): State<AuthConfig>,
+    State(hasher): State<PasswordHasher>,
+    State(totp): State<TotpConfig>,
+    State(throttle): State<LoginThrottle>,
+    client: ClientInfo,
+    jar: CookieJar,
     Json(user_req): Json<UserRequest>,
-) -> Result<Json<UserResponse>, AppError> {
+) -> Result<(CookieJar, Json<LoginResponse>), AppError> {
     if user_req.username.is_empty() || user_req.password.is_empty() {
         return Err(AppError::new(
             StatusCode::BAD_REQUEST,
             "Please enter all login details.".to_owned(),
         ));
     }
+    let ip = client.ip.clone();
+    throttle.check(&user_req.username, ip.as_deref()).await?;
     let user = Users::find()
-        .filter(users::Column::Username.eq(user_req.username))
+        .filter(users::Column::Username.eq(&user_req.username))
+        .filter(users::Column::DeletedAt.is_null())
         .one(&database)
         .await
         .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
 
-    if let None = user {
-        return Err(AppError::new(
-            StatusCode::NOT_FOUND,
-            "Username not found.".to_owned(),
-        ));
-    }
-
-    let user = user.unwrap();
-    if !verify_password(user_req.password, &user.password[..])? {
+    let verification = match user.as_ref().and_then(|user| user.password.clone()) {
+        Some(password_hash) => {
+            hasher
+                .verify(user_req.password.clone(), password_hash)
+                .await?
+        }
+        None => hasher.verify_dummy(user_req.password.clone()).await?,
+    };
+    let Some(user) = user.filter(|_| verification.is_valid()) else {
+        throttle
+            .record_failure(&user_req.username, ip.as_deref())
+            .await?;
         return Err(AppError::new(
             StatusCode::UNAUTHORIZED,
             "Wrong credentials.".to_owned(),
         ));
+    };
+    if user.disabled_at.is_some() {
+        return Err(AppError::new(StatusCode::FORBIDDEN, "Account disabled."));
     }
-    let mut user = user.into_active_model();
-    user.token = Set(Some(create_jwt()?));
-    let user = user
-        .save(&database)
-        .await
-        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
-    Ok(Json(UserResponse {
-        id: user.id.unwrap(),
-        username: user.username.unwrap(),
-        token: user.token.unwrap(),
-    }))
+    if verification == Verification::ValidNeedsRehash {
+        upgrade_password_hash(&database, &hasher, &user, user_req.password).await;
+    }
+    let (jar, response) =
+        complete_sign_in(&database, &jwt, &totp, &auth, jar, user, client).await?;
+    // With two-factor authentication the count is only cleared once the second step passes,
+    // so knowing the password doesn't allow unlimited code guesses.
+    if let LoginResponse::SignedIn(_) = response {
+        throttle.record_success(&user_req.username).await?;
+    }
+    Ok((jar, Json(response)))
 }
 
 #[instrument(skip(database))]
 pub async fn logout(
     State(database): State<DatabaseConnection>,
-    Extension(user): Extension<Model>,
-) -> Result<(), AppError> {
-    let mut user = user.into_active_model();
+    SessionClaims(claims): SessionClaims,
+    jar: CookieJar,
+) -> Result<CookieJar, AppError> {
+    revoke_session(&database, claims.sid).await?;
+    Ok(clear_session_cookies(jar))
+}
 
-    user.token = Set(None);
-    user.save(&database)
-        .await
-        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
-    Ok(())
+/// Re-hashes a password that verified against a legacy or weaker hash. Failure only means
+/// the upgrade is retried on the next login, so it's logged rather than returned.
+async fn upgrade_password_hash(
+    database: &DatabaseConnection,
+    hasher: &PasswordHasher,
+    user: &users::Model,
+    password: String,
+) {
+    let new_hash = match hasher.hash(password).await {
+        Ok(hash) => hash,
+        Err(error) => {
+            warn!(?error, user_id = user.id, "could not rehash password");
+            return;
+        }
+    };
+    // Only replace the hash that was verified, in case the password changed meanwhile.
+    let result = Users::update_many()
+        .col_expr(users::Column::Password, Expr::value(new_hash))
+        .filter(users::Column::Id.eq(user.id))
+        .filter(users::Column::Password.eq(user.password.clone()))
+        .exec(database)
+        .await;
+    match result {
+        Ok(_) => info!(user_id = user.id, "password hash upgraded"),
+        Err(error) => warn!(
+            ?error,
+            user_id = user.id,
+            "could not store rehashed password"
+        ),
+    }
 }
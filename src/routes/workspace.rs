@@ -0,0 +1,445 @@
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, Path, RawPathParams, State},
+    http::{request::Parts, StatusCode},
+    Json,
+};
+use chrono::Utc;
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, ActiveModelTrait, ColumnTrait, ConnectionTrait,
+    DatabaseConnection, EntityTrait, IntoActiveModel, PaginatorTrait, QueryFilter, QueryOrder,
+    QuerySelect, Set, TransactionTrait,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument};
+
+use super::guard::{AuthUser, SessionClaims};
+use super::priority::create_default_priorities;
+use super::profile::avatar_url;
+use crate::database::{
+    prelude::{Users, WorkspaceMemberships, Workspaces},
+    sea_orm_active_enums::WorkspaceRole,
+    users, workspace_memberships, workspaces,
+};
+use crate::utils::app_error::AppError;
+use crate::utils::permission::{Permission, WorkspacePermission};
+
+/// Names the workspace for routes that don't carry it in the path.
+pub const WORKSPACE_HEADER: &str = "x-workspace-id";
+
+const MAX_WORKSPACE_NAME_LENGTH: usize = 100;
+
+/// The workspace a request acts in, with the caller's role there. Taken from the
+/// `:workspace_id` path segment, else the `X-Workspace-Id` header, else the caller's only
+/// workspace. Workspaces the caller doesn't belong to are reported as 404.
+#[derive(Clone, Debug)]
+pub struct ActiveWorkspace {
+    pub workspace: workspaces::Model,
+    pub role: WorkspaceRole,
+}
+
+impl ActiveWorkspace {
+    pub fn require(&self, permission: WorkspacePermission) -> Result<(), AppError> {
+        match permission.granted_to(self.role) {
+            true => Ok(()),
+            false => Err(AppError::new(
+                StatusCode::FORBIDDEN,
+                "Your role in this workspace doesn't allow this.",
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ActiveWorkspace
+where
+    DatabaseConnection: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let AuthUser(user) = AuthUser::from_request_parts(parts, state).await?;
+        let from_path = RawPathParams::from_request_parts(parts, state)
+            .await
+            .ok()
+            .and_then(|params| {
+                params
+                    .iter()
+                    .find(|(key, _)| *key == "workspace_id")
+                    .map(|(_, value)| value.to_owned())
+            });
+        let from_header = parts
+            .headers
+            .get(WORKSPACE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        let requested =
+            match from_path.or(from_header) {
+                Some(raw) => Some(raw.trim().parse::<i32>().map_err(|_| {
+                    AppError::new(StatusCode::BAD_REQUEST, "Invalid workspace id.")
+                })?),
+                None => None,
+            };
+
+        let database = DatabaseConnection::from_ref(state);
+        let mut query = WorkspaceMemberships::find()
+            .filter(workspace_memberships::Column::UserId.eq(user.id))
+            .find_also_related(Workspaces);
+        if let Some(workspace_id) = requested {
+            query = query.filter(workspace_memberships::Column::WorkspaceId.eq(workspace_id));
+        }
+        let mut memberships = query
+            .limit(2)
+            .all(&database)
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        match (requested, memberships.len()) {
+            (Some(_), 0) => Err(AppError::new(StatusCode::NOT_FOUND, "Workspace not found.")),
+            (None, 0) => Err(AppError::new(
+                StatusCode::FORBIDDEN,
+                "You don't belong to any workspace.",
+            )),
+            (None, 2..) => Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                "You belong to several workspaces; choose one with the X-Workspace-Id header.",
+            )),
+            _ => {
+                let (membership, workspace) = memberships.remove(0);
+                let workspace = workspace
+                    .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, "Workspace not found."))?;
+                Ok(ActiveWorkspace {
+                    workspace,
+                    role: membership.role,
+                })
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct WorkspaceResponse {
+    id: i32,
+    name: String,
+    role: WorkspaceRole,
+    created_at: DateTimeWithTimeZone,
+}
+
+impl WorkspaceResponse {
+    fn new(workspace: workspaces::Model, role: WorkspaceRole) -> Self {
+        WorkspaceResponse {
+            id: workspace.id,
+            name: workspace.name,
+            role,
+            created_at: workspace.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct MemberResponse {
+    user_id: i32,
+    username: String,
+    display_name: Option<String>,
+    avatar_url: Option<String>,
+    role: WorkspaceRole,
+    joined_at: DateTimeWithTimeZone,
+}
+
+#[derive(Deserialize)]
+pub struct WorkspaceRequest {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct MemberRoleRequest {
+    role: WorkspaceRole,
+}
+
+#[derive(Deserialize)]
+pub struct MemberPath {
+    user_id: i32,
+}
+
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn get_my_workspaces(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+) -> Result<Json<Vec<WorkspaceResponse>>, AppError> {
+    let workspaces = WorkspaceMemberships::find()
+        .filter(workspace_memberships::Column::UserId.eq(user.id))
+        .find_also_related(Workspaces)
+        .order_by_asc(workspace_memberships::Column::WorkspaceId)
+        .all(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .into_iter()
+        .filter_map(|(membership, workspace)| {
+            workspace.map(|workspace| WorkspaceResponse::new(workspace, membership.role))
+        })
+        .collect();
+    Ok(Json(workspaces))
+}
+
+#[instrument(skip_all, fields(user_id = user.id))]
+pub async fn create_workspace(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+    _session: SessionClaims,
+    Json(request): Json<WorkspaceRequest>,
+) -> Result<(StatusCode, Json<WorkspaceResponse>), AppError> {
+    let name = workspace_name(&request.name)?;
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let workspace = insert_workspace(&txn, user.id, name).await?;
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(workspace_id = workspace.id, "workspace created");
+    Ok((
+        StatusCode::CREATED,
+        Json(WorkspaceResponse::new(workspace, WorkspaceRole::Owner)),
+    ))
+}
+
+pub async fn get_workspace(active: ActiveWorkspace) -> Json<WorkspaceResponse> {
+    Json(WorkspaceResponse::new(active.workspace, active.role))
+}
+
+#[instrument(skip_all, fields(workspace_id = active.workspace.id))]
+pub async fn update_workspace(
+    State(database): State<DatabaseConnection>,
+    active: ActiveWorkspace,
+    _session: SessionClaims,
+    Json(request): Json<WorkspaceRequest>,
+) -> Result<Json<WorkspaceResponse>, AppError> {
+    active.require(WorkspacePermission::ManageWorkspace)?;
+    let name = workspace_name(&request.name)?;
+    let mut workspace = active.workspace.into_active_model();
+    workspace.name = Set(name);
+    let workspace = workspace
+        .update(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(WorkspaceResponse::new(workspace, active.role)))
+}
+
+/// Deletes the workspace with all of its tasks.
+#[instrument(skip_all, fields(workspace_id = active.workspace.id))]
+pub async fn delete_workspace(
+    State(database): State<DatabaseConnection>,
+    active: ActiveWorkspace,
+    _session: SessionClaims,
+) -> Result<(), AppError> {
+    active.require(WorkspacePermission::ManageWorkspace)?;
+    Workspaces::delete_by_id(active.workspace.id)
+        .exec(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!("workspace deleted");
+    Ok(())
+}
+
+#[instrument(skip_all, fields(workspace_id = active.workspace.id))]
+pub async fn get_members(
+    State(database): State<DatabaseConnection>,
+    active: ActiveWorkspace,
+) -> Result<Json<Vec<MemberResponse>>, AppError> {
+    let members = WorkspaceMemberships::find()
+        .filter(workspace_memberships::Column::WorkspaceId.eq(active.workspace.id))
+        .find_also_related(Users)
+        .filter(users::Column::DeletedAt.is_null())
+        .order_by_asc(workspace_memberships::Column::CreatedAt)
+        .all(&database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .into_iter()
+        .filter_map(|(membership, user)| {
+            user.map(|user| MemberResponse {
+                user_id: user.id,
+                avatar_url: avatar_url(&user),
+                username: user.username,
+                display_name: user.display_name,
+                role: membership.role,
+                joined_at: membership.created_at,
+            })
+        })
+        .collect();
+    Ok(Json(members))
+}
+
+/// Changes a member's role. Only owners can hand out or take away the owner and admin roles,
+/// and the last owner can't be demoted.
+#[instrument(skip_all, fields(workspace_id = active.workspace.id, member_id = path.user_id))]
+pub async fn update_member(
+    State(database): State<DatabaseConnection>,
+    active: ActiveWorkspace,
+    _session: SessionClaims,
+    Path(path): Path<MemberPath>,
+    Json(request): Json<MemberRoleRequest>,
+) -> Result<(), AppError> {
+    active.require(WorkspacePermission::ManageMembers)?;
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let membership = lock_membership(&txn, active.workspace.id, path.user_id).await?;
+    if active.role != WorkspaceRole::Owner
+        && (is_privileged(membership.role) || is_privileged(request.role))
+    {
+        return Err(AppError::new(
+            StatusCode::FORBIDDEN,
+            "Only owners can change who is an owner or admin.",
+        ));
+    }
+    if membership.role == WorkspaceRole::Owner && request.role != WorkspaceRole::Owner {
+        ensure_another_owner(&txn, active.workspace.id, path.user_id).await?;
+    }
+    let mut membership = membership.into_active_model();
+    membership.role = Set(request.role);
+    membership
+        .update(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!(role = ?request.role, "member role changed");
+    Ok(())
+}
+
+/// Removes a member, or lets a member leave. The last owner can't leave. Leaving needs no
+/// global permission, so read-only users aren't stuck in workspaces they were invited to.
+#[instrument(skip_all, fields(workspace_id = active.workspace.id, member_id = path.user_id))]
+pub async fn remove_member(
+    State(database): State<DatabaseConnection>,
+    AuthUser(user): AuthUser,
+    active: ActiveWorkspace,
+    _session: SessionClaims,
+    Path(path): Path<MemberPath>,
+) -> Result<(), AppError> {
+    if path.user_id != user.id {
+        if !Permission::ManageWorkspaces.granted_to(user.role) {
+            return Err(AppError::new(StatusCode::FORBIDDEN, "Action not allowed."));
+        }
+        active.require(WorkspacePermission::ManageMembers)?;
+    }
+    let txn = database
+        .begin()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let membership = lock_membership(&txn, active.workspace.id, path.user_id).await?;
+    if path.user_id != user.id
+        && active.role != WorkspaceRole::Owner
+        && is_privileged(membership.role)
+    {
+        return Err(AppError::new(
+            StatusCode::FORBIDDEN,
+            "Only owners can remove owners and admins.",
+        ));
+    }
+    if membership.role == WorkspaceRole::Owner {
+        ensure_another_owner(&txn, active.workspace.id, path.user_id).await?;
+    }
+    WorkspaceMemberships::delete_by_id((membership.workspace_id, membership.user_id))
+        .exec(&txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    txn.commit()
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    info!("member removed");
+    Ok(())
+}
+
+/// Gives a newly created account a workspace of its own to put tasks in.
+pub async fn create_personal_workspace(
+    database: &impl ConnectionTrait,
+    user_id: i32,
+) -> Result<workspaces::Model, AppError> {
+    insert_workspace(database, user_id, "Personal".to_owned()).await
+}
+
+async fn insert_workspace(
+    database: &impl ConnectionTrait,
+    owner_id: i32,
+    name: String,
+) -> Result<workspaces::Model, AppError> {
+    let now: DateTimeWithTimeZone = Utc::now().into();
+    let workspace = workspaces::ActiveModel {
+        name: Set(name),
+        created_at: Set(now),
+        ..Default::default()
+    }
+    .insert(database)
+    .await
+    .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    workspace_memberships::ActiveModel {
+        workspace_id: Set(workspace.id),
+        user_id: Set(owner_id),
+        role: Set(WorkspaceRole::Owner),
+        created_at: Set(now),
+    }
+    .insert(database)
+    .await
+    .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    create_default_priorities(database, workspace.id).await?;
+    Ok(workspace)
+}
+
+/// Loads a membership and locks its workspace row, so concurrent role changes can't
+/// leave a workspace without an owner.
+async fn lock_membership(
+    txn: &impl ConnectionTrait,
+    workspace_id: i32,
+    user_id: i32,
+) -> Result<workspace_memberships::Model, AppError> {
+    Workspaces::find_by_id(workspace_id)
+        .lock_exclusive()
+        .one(txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    WorkspaceMemberships::find_by_id((workspace_id, user_id))
+        .one(txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, "Member not found."))
+}
+
+async fn ensure_another_owner(
+    txn: &impl ConnectionTrait,
+    workspace_id: i32,
+    user_id: i32,
+) -> Result<(), AppError> {
+    let owners = WorkspaceMemberships::find()
+        .filter(workspace_memberships::Column::WorkspaceId.eq(workspace_id))
+        .filter(workspace_memberships::Column::UserId.ne(user_id))
+        .filter(workspace_memberships::Column::Role.eq(WorkspaceRole::Owner))
+        .count(txn)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    match owners {
+        0 => Err(AppError::new(
+            StatusCode::CONFLICT,
+            "A workspace needs at least one owner; make someone else owner first.",
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub fn is_privileged(role: WorkspaceRole) -> bool {
+    matches!(role, WorkspaceRole::Owner | WorkspaceRole::Admin)
+}
+
+fn workspace_name(name: &str) -> Result<String, AppError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_WORKSPACE_NAME_LENGTH {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Workspace names need 1 to {MAX_WORKSPACE_NAME_LENGTH} characters."),
+        ));
+    }
+    Ok(name.to_owned())
+}
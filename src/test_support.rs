@@ -0,0 +1,362 @@
+//! Setup shared by tests that need a database or the whole application.
+//!
+//! These tests run against the PostgreSQL server in `TEST_DATABASE_URL`, e.g.
+//! `postgres://postgres@localhost:5432/postgres`, as a user allowed to create databases.
+//! Each test gets a database of its own, named `axum_db_test_<uuid>`, which is left behind
+//! for inspection. They are marked `#[ignore = "needs TEST_DATABASE_URL"]`, so a plain
+//! `cargo test` leaves them out and reports them as ignored; run everything with
+//! `cargo test -- --include-ignored`.
+
+use std::{
+    env, fs,
+    net::SocketAddr,
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::ConnectInfo,
+    http::{header, HeaderMap, Method, Request, StatusCode},
+    Router,
+};
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use chrono::Duration;
+use sea_orm::{ConnectionTrait, Database, DatabaseConnection};
+use serde_json::Value;
+use tower::ServiceExt;
+use url::Url;
+use uuid::Uuid;
+
+use crate::config::{AccountConfig, AuthConfig, Config};
+use crate::routes::create_routes;
+use crate::utils::{
+    avatar::AvatarConfig,
+    jwt::JwtConfig,
+    keyring::Keyring,
+    lockout::{AttemptStoreKind, LockoutConfig},
+    mailer::{MailTransport, MailerConfig},
+    passkey::PasskeyConfig,
+    password::PasswordHasherConfig,
+    password_policy::PasswordPolicy,
+    storage::StorageBackend,
+    totp::TotpConfig,
+};
+
+/// The tables the migrations start from.
+const BASE_SCHEMA: &str = r#"
+CREATE TABLE users (
+    id SERIAL PRIMARY KEY,
+    username VARCHAR NOT NULL UNIQUE,
+    password VARCHAR NOT NULL,
+    deleted_at TIMESTAMPTZ,
+    token TEXT
+);
+
+CREATE TABLE tasks (
+    id SERIAL PRIMARY KEY,
+    priority VARCHAR,
+    title VARCHAR NOT NULL,
+    completed_at TIMESTAMPTZ,
+    description TEXT,
+    deleted_at TIMESTAMPTZ,
+    user_id INTEGER REFERENCES users (id),
+    is_default BOOLEAN DEFAULT false
+);
+"#;
+
+pub const APP_URL: &str = "http://localhost:3000";
+
+/// A fresh database with every migration applied.
+pub async fn test_database() -> DatabaseConnection {
+    let database = base_database().await;
+    migrate(&database, ..).await;
+    database
+}
+
+/// A fresh database holding only the tables the migrations start from.
+pub async fn base_database() -> DatabaseConnection {
+    let server_url = env::var("TEST_DATABASE_URL")
+        .expect("TEST_DATABASE_URL must name a PostgreSQL server to run database tests");
+    let name = format!("axum_db_test_{}", Uuid::new_v4().simple());
+    let server = Database::connect(&server_url)
+        .await
+        .expect("could not connect to TEST_DATABASE_URL");
+    server
+        .execute_unprepared(&format!("CREATE DATABASE {name}"))
+        .await
+        .expect("could not create a test database");
+    server.close().await.expect("could not disconnect");
+
+    let mut url = Url::parse(&server_url).expect("TEST_DATABASE_URL is not a URL");
+    url.set_path(&name);
+    let database = Database::connect(url.as_str())
+        .await
+        .expect("could not connect to the test database");
+    database
+        .execute_unprepared(BASE_SCHEMA)
+        .await
+        .expect("could not create the base schema");
+    database
+}
+
+/// Applies the migrations whose file names fall in `names`, in order, as in
+/// `migrate(&database, .."0016").await`.
+pub async fn migrate<'a>(database: &DatabaseConnection, names: impl RangeBounds<&'a str>) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+    let mut migrations: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("could not list migrations")
+        .map(|entry| entry.expect("could not list migrations").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sql"))
+        .collect();
+    migrations.sort();
+    for path in migrations {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        if !names.contains(&name) {
+            continue;
+        }
+        let sql = fs::read_to_string(&path).expect("could not read migration");
+        database
+            .execute_unprepared(&sql)
+            .await
+            .unwrap_or_else(|error| panic!("{name} failed: {error}"));
+    }
+}
+
+/// Settings for a test instance: cheap password hashing, in-memory counters and storage,
+/// and email written to a directory of its own.
+pub fn test_config(database_url: String) -> Config {
+    Config {
+        database_url,
+        jwt: JwtConfig {
+            keyring: Arc::new(Keyring::from_secret("test", b"test secret")),
+            issuer: "axum_db".to_owned(),
+            audience: "axum_db".to_owned(),
+            access_token_ttl: Duration::minutes(15),
+            refresh_token_ttl: Duration::days(30),
+        },
+        oidc: Vec::new(),
+        totp: TotpConfig {
+            issuer: "axum_db".to_owned(),
+            challenge_ttl: Duration::minutes(5),
+        },
+        mailer: MailerConfig {
+            from: "axum_db <no-reply@localhost>".to_owned(),
+            transport: MailTransport::File(
+                env::temp_dir().join(format!("axum_db_mail_{}", Uuid::new_v4().simple())),
+            ),
+        },
+        account: AccountConfig {
+            app_url: APP_URL.to_owned(),
+            password_reset_ttl: Duration::hours(1),
+            email_verification_ttl: Duration::hours(48),
+            magic_link_ttl: Duration::minutes(15),
+            invite_ttl: Duration::days(7),
+            deletion_grace_period: Duration::days(30),
+            purge_interval: Duration::hours(1),
+            unverified_task_limit: 10,
+        },
+        lockout: LockoutConfig {
+            store: AttemptStoreKind::Memory,
+            max_failures: 5,
+            max_failures_per_ip: 50,
+            window: Duration::minutes(15),
+            lockout: Duration::minutes(15),
+            base_delay: Duration::milliseconds(1),
+        },
+        password_policy: PasswordPolicy {
+            min_length: 8,
+            max_length: 128,
+            require_lowercase: false,
+            require_uppercase: true,
+            require_digit: true,
+            require_special: true,
+            min_entropy_bits: 36.0,
+            reject_username: true,
+            breached: None,
+        },
+        password_hasher: PasswordHasherConfig {
+            memory_kib: 1024,
+            iterations: 1,
+            parallelism: 1,
+            workers: 4,
+            legacy_bcrypt_cost: Some(4),
+        },
+        auth: AuthConfig {
+            bearer: true,
+            cookie: false,
+            cookie_secure: false,
+            cookie_same_site: SameSite::Lax,
+            trusted_proxies: Vec::new(),
+        },
+        passkey: PasskeyConfig {
+            rp_id: "localhost".to_owned(),
+            rp_name: "axum_db".to_owned(),
+            rp_origin: APP_URL.to_owned(),
+            challenge_ttl: Duration::minutes(5),
+        },
+        storage: StorageBackend::Memory,
+        avatar: AvatarConfig {
+            max_upload_bytes: 1024 * 1024,
+            max_dimension: 1024,
+        },
+    }
+}
+
+/// The application on a test database, called in-process.
+pub struct TestApp {
+    pub router: Router,
+    pub database: DatabaseConnection,
+    pub mail_dir: PathBuf,
+}
+
+impl TestApp {
+    /// Starts the app with `test_config`, adjusted by `configure`.
+    pub async fn start(configure: impl FnOnce(&mut Config)) -> TestApp {
+        let database = test_database().await;
+        let mut config = test_config(String::new());
+        configure(&mut config);
+        let MailTransport::File(mail_dir) = config.mailer.transport.clone() else {
+            panic!("tests read email from a directory");
+        };
+        TestApp {
+            router: create_routes(database.clone(), config).await,
+            database,
+            mail_dir,
+        }
+    }
+
+    pub async fn call(&self, mut request: Request<Body>) -> TestResponse {
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        TestResponse {
+            status,
+            headers,
+            body: serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+            text: String::from_utf8_lossy(&bytes).into_owned(),
+        }
+    }
+
+    /// Signs up `username` and returns their access token.
+    pub async fn sign_up(&self, username: &str) -> String {
+        let response = self
+            .call(json_request(
+                Method::POST,
+                "/users",
+                serde_json::json!({ "username": username, "password": "Correct-Horse-9" }),
+                &[],
+            ))
+            .await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.body);
+        response.body["token"].as_str().unwrap().to_owned()
+    }
+
+    /// Waits for the next email to `to` with `subject` and returns its decoded body. Emails
+    /// are sent in the background, so they can arrive after the response.
+    pub async fn email(&self, to: &str, subject: &str) -> String {
+        for _ in 0..100 {
+            for entry in fs::read_dir(&self.mail_dir).into_iter().flatten() {
+                let path = entry.unwrap().path();
+                let message = fs::read_to_string(&path).unwrap();
+                if message.contains(&format!("To: {to}\r\n"))
+                    && message.contains(&format!("Subject: {subject}\r\n"))
+                {
+                    fs::remove_file(&path).unwrap();
+                    let (headers, body) = message.split_once("\r\n\r\n").unwrap();
+                    return if headers.contains("Content-Transfer-Encoding: quoted-printable") {
+                        decode_quoted_printable(body)
+                    } else {
+                        body.to_owned()
+                    };
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("no email to {to} about {subject:?}");
+    }
+}
+
+fn decode_quoted_printable(encoded: &str) -> String {
+    let encoded = encoded.replace("=\r\n", "");
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'=' {
+            let hex = [bytes.next().unwrap(), bytes.next().unwrap()];
+            let hex = std::str::from_utf8(&hex).unwrap();
+            decoded.push(u8::from_str_radix(hex, 16).expect("quoted-printable escape"));
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).unwrap()
+}
+
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    /// The body as JSON, or `Null` when it isn't.
+    pub body: Value,
+    pub text: String,
+}
+
+impl TestResponse {
+    /// The value of the cookie `name` set by the response, if it wasn't removed.
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.headers
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|value| Cookie::parse(value.to_str().ok()?.to_owned()).ok())
+            .find(|cookie| cookie.name() == name)
+            .map(|cookie| cookie.value().to_owned())
+            .filter(|value| !value.is_empty())
+    }
+}
+
+/// A request with a JSON body and extra `headers`.
+pub fn json_request(
+    method: Method,
+    uri: &str,
+    body: Value,
+    headers: &[(&str, &str)],
+) -> Request<Body> {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, "application/json");
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request.body(Body::from(body.to_string())).unwrap()
+}
+
+/// A request without a body.
+pub fn empty_request(method: Method, uri: &str, headers: &[(&str, &str)]) -> Request<Body> {
+    let mut request = Request::builder().method(method).uri(uri);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request.body(Body::empty()).unwrap()
+}
+
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn serves_the_app_on_a_migrated_database() {
+        let app = TestApp::start(|_| {}).await;
+        let health = app
+            .call(Request::get("/health").body(Body::empty()).unwrap())
+            .await;
+        assert_eq!(health.status, StatusCode::OK);
+        assert_eq!(health.text, "hello world");
+        assert!(!app.sign_up("ada@example.com").await.is_empty());
+    }
+}
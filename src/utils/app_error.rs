@@ -22,6 +22,13 @@ impl AppError {
     }
 }
 
+/// For handlers that still report errors as a bare status and message.
+impl From<AppError> for (StatusCode, String) {
+    fn from(error: AppError) -> Self {
+        (error.code, error.message)
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
         (
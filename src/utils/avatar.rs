@@ -0,0 +1,65 @@
+use std::io::Cursor;
+
+use http::StatusCode;
+use image::{imageops::FilterType, ImageFormat, ImageReader, Limits};
+
+use super::app_error::AppError;
+
+/// Edge lengths, in pixels, of the square renditions stored for every avatar.
+pub const AVATAR_SIZES: [u32; 3] = [64, 128, 256];
+pub const DEFAULT_AVATAR_SIZE: u32 = 128;
+
+#[derive(Clone, Debug)]
+pub struct AvatarConfig {
+    pub max_upload_bytes: usize,
+    /// Uploads wider or taller than this are refused before being decoded.
+    pub max_dimension: u32,
+}
+
+pub fn avatar_key(user_id: i32, size: u32) -> String {
+    format!("avatars/{user_id}/{size}.png")
+}
+
+/// Decodes an uploaded image and renders it as a PNG at every size in `AVATAR_SIZES`,
+/// center-cropped to a square. CPU-bound: call it from a blocking task.
+pub fn render_avatars(bytes: &[u8], max_dimension: u32) -> Result<Vec<(u32, Vec<u8>)>, AppError> {
+    let unsupported = || {
+        AppError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Avatars must be PNG, JPEG, WebP or GIF images.",
+        )
+    };
+    let mut reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|_| unsupported())?;
+    if !matches!(
+        reader.format(),
+        Some(ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP | ImageFormat::Gif)
+    ) {
+        return Err(unsupported());
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(max_dimension);
+    limits.max_image_height = Some(max_dimension);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|error| match error {
+        image::ImageError::Limits(_) => AppError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Avatars can be at most {max_dimension}x{max_dimension} pixels."),
+        ),
+        _ => AppError::new(StatusCode::BAD_REQUEST, "The image could not be read."),
+    })?;
+    AVATAR_SIZES
+        .iter()
+        .map(|&size| {
+            let mut png = Vec::new();
+            image
+                .resize_to_fill(size, size, FilterType::Lanczos3)
+                .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+                .map_err(|error| {
+                    AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+                })?;
+            Ok((size, png))
+        })
+        .collect()
+}
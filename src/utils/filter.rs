@@ -0,0 +1,754 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, NaiveDate};
+use http::StatusCode;
+use sea_orm::{
+    sea_query::{Expr, Func, LikeExpr},
+    ColumnTrait, Condition, Value,
+};
+
+use super::app_error::AppError;
+
+pub const MAX_FILTER_LENGTH: usize = 1000;
+const MAX_NESTING: usize = 16;
+
+/// A parsed `filter=` expression such as
+/// `(priority in (high, urgent) or completed = false) and not title ~ 'draft'`.
+///
+/// Comparisons are `field op value` with `=`, `!=`, `<`, `<=`, `>`, `>=` and `~`
+/// (case-insensitive contains), `field [not] in (a, b)` and `field is [not] null`. They
+/// combine with `and`, `or`, `not` and parentheses; `and` binds tighter than `or`. Values
+/// are bare words or single- or double-quoted strings.
+#[derive(Clone, Debug)]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Test(Comparison),
+}
+
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    pub field: Spanned,
+    pub test: Test,
+}
+
+#[derive(Clone, Debug)]
+pub enum Test {
+    Compare(Operator, Spanned),
+    In(Vec<Spanned>),
+    IsNull,
+    IsNotNull,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+/// A word of the expression with its byte offset, so errors can point at it.
+#[derive(Clone, Debug)]
+pub struct Spanned {
+    pub text: String,
+    pub at: usize,
+}
+
+/// How to read the values a field is compared with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueKind {
+    Int,
+    Text,
+    Bool,
+    Time,
+}
+
+/// A 400 pointing at the character where the problem is.
+pub fn filter_error(at: usize, message: impl Display) -> AppError {
+    AppError::new(
+        StatusCode::BAD_REQUEST,
+        format!("Invalid filter at character {}: {message}", at + 1),
+    )
+}
+
+pub fn parse_filter(input: &str) -> Result<Filter, AppError> {
+    if input.len() > MAX_FILTER_LENGTH {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Filters can be at most {MAX_FILTER_LENGTH} characters."),
+        ));
+    }
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        position: 0,
+        end: input.len(),
+    };
+    let filter = parser.or(0)?;
+    match parser.peek() {
+        None => Ok(filter),
+        Some(token) => Err(filter_error(
+            token.at,
+            format!("unexpected {}", token.kind.describe()),
+        )),
+    }
+}
+
+impl Filter {
+    /// Translates the expression, turning each comparison into a condition with `resolve`.
+    pub fn to_condition(
+        &self,
+        resolve: &impl Fn(&Comparison) -> Result<Condition, AppError>,
+    ) -> Result<Condition, AppError> {
+        match self {
+            Filter::And(filters) => filters.iter().try_fold(Condition::all(), |all, filter| {
+                Ok(all.add(filter.to_condition(resolve)?))
+            }),
+            Filter::Or(filters) => filters.iter().try_fold(Condition::any(), |any, filter| {
+                Ok(any.add(filter.to_condition(resolve)?))
+            }),
+            Filter::Not(filter) => Ok(filter.to_condition(resolve)?.not()),
+            Filter::Test(comparison) => resolve(comparison),
+        }
+    }
+
+    /// Whether any comparison is on one of `fields`.
+    pub fn mentions(&self, fields: &[&str]) -> bool {
+        match self {
+            Filter::And(filters) | Filter::Or(filters) => {
+                filters.iter().any(|filter| filter.mentions(fields))
+            }
+            Filter::Not(filter) => filter.mentions(fields),
+            Filter::Test(comparison) => fields.contains(&comparison.field.text.as_str()),
+        }
+    }
+}
+
+impl Comparison {
+    /// Tests `column` itself, reading the values as `kind`.
+    pub fn on_column<C: ColumnTrait>(
+        &self,
+        column: C,
+        kind: ValueKind,
+    ) -> Result<Condition, AppError> {
+        let condition = Condition::all();
+        match &self.test {
+            Test::IsNull => Ok(condition.add(column.is_null())),
+            Test::IsNotNull => Ok(condition.add(column.is_not_null())),
+            Test::In(values) => {
+                let values = values
+                    .iter()
+                    .map(|value| value.parse(kind))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(condition.add(column.is_in(values)))
+            }
+            Test::Compare(Operator::Contains, value) if kind == ValueKind::Text => {
+                let pattern = format!("%{}%", escape_like(&value.text.to_lowercase()));
+                Ok(condition.add(
+                    Expr::expr(Func::lower(Expr::col(column)))
+                        .like(LikeExpr::new(pattern).escape('\\')),
+                ))
+            }
+            Test::Compare(Operator::Contains, value) => Err(filter_error(
+                value.at,
+                format!("`{}` doesn't support `~`", self.field.text),
+            )),
+            Test::Compare(operator, value) => {
+                if kind == ValueKind::Bool && !matches!(operator, Operator::Eq | Operator::Ne) {
+                    return Err(filter_error(
+                        value.at,
+                        format!("`{}` can only be compared with = and !=", self.field.text),
+                    ));
+                }
+                let parsed = value.parse(kind)?;
+                Ok(match operator {
+                    Operator::Eq => condition.add(column.eq(parsed)),
+                    // Missing values count as different from any value.
+                    Operator::Ne => Condition::any()
+                        .add(column.ne(parsed))
+                        .add(column.is_null()),
+                    Operator::Lt => condition.add(column.lt(parsed)),
+                    Operator::Le => condition.add(column.lte(parsed)),
+                    Operator::Gt => condition.add(column.gt(parsed)),
+                    Operator::Ge => condition.add(column.gte(parsed)),
+                    Operator::Contains => unreachable!("handled above"),
+                })
+            }
+        }
+    }
+
+    /// Treats the field as a boolean that's true when `column` is set, as in `completed = true`.
+    pub fn on_presence<C: ColumnTrait>(&self, column: C) -> Result<Condition, AppError> {
+        let (wanted, value) = match &self.test {
+            Test::Compare(Operator::Eq, value) => (true, value),
+            Test::Compare(Operator::Ne, value) => (false, value),
+            _ => {
+                return Err(filter_error(
+                    self.field.at,
+                    format!("`{}` can only be compared with = and !=", self.field.text),
+                ))
+            }
+        };
+        let present = match value.parse(ValueKind::Bool)? {
+            Value::Bool(Some(present)) => present == wanted,
+            _ => unreachable!("booleans parse to Value::Bool"),
+        };
+        Ok(Condition::all().add(match present {
+            true => column.is_not_null(),
+            false => column.is_null(),
+        }))
+    }
+
+    /// Replaces values spelled `alias` with `value`, as in `owner = me`.
+    pub fn with_alias(&self, alias: &str, value: &str) -> Comparison {
+        let replaced = self.map_values(|spanned| {
+            Ok(match spanned.text == alias {
+                true => value.to_owned(),
+                false => spanned.text.clone(),
+            })
+        });
+        replaced.expect("replacing aliases can't fail")
+    }
+
+    /// Rewrites every value with `map`, keeping its position for errors, as when a field
+    /// is written with names but stored as numbers.
+    pub fn map_values(
+        &self,
+        map: impl Fn(&Spanned) -> Result<String, AppError>,
+    ) -> Result<Comparison, AppError> {
+        let replace = |spanned: &Spanned| -> Result<Spanned, AppError> {
+            Ok(Spanned {
+                text: map(spanned)?,
+                at: spanned.at,
+            })
+        };
+        Ok(Comparison {
+            field: self.field.clone(),
+            test: match &self.test {
+                Test::Compare(operator, spanned) => Test::Compare(*operator, replace(spanned)?),
+                Test::In(values) => Test::In(values.iter().map(replace).collect::<Result<_, _>>()?),
+                Test::IsNull => Test::IsNull,
+                Test::IsNotNull => Test::IsNotNull,
+            },
+        })
+    }
+}
+
+impl Spanned {
+    fn parse(&self, kind: ValueKind) -> Result<Value, AppError> {
+        let text = self.text.as_str();
+        match kind {
+            ValueKind::Text => Ok(text.into()),
+            ValueKind::Int => text
+                .parse::<i64>()
+                .map(Into::into)
+                .map_err(|_| filter_error(self.at, format!("expected a number, found `{text}`"))),
+            ValueKind::Bool => match text.to_ascii_lowercase().as_str() {
+                "true" => Ok(true.into()),
+                "false" => Ok(false.into()),
+                _ => Err(filter_error(
+                    self.at,
+                    format!("expected true or false, found `{text}`"),
+                )),
+            },
+            ValueKind::Time => DateTime::parse_from_rfc3339(text)
+                .ok()
+                .or_else(|| {
+                    NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                        .ok()
+                        .and_then(|date| date.and_hms_opt(0, 0, 0))
+                        .map(|midnight| midnight.and_utc().fixed_offset())
+                })
+                .map(Value::from)
+                .ok_or_else(|| {
+                    filter_error(
+                        self.at,
+                        format!(
+                            "expected a date like 2024-01-31 or an RFC 3339 time, found `{text}`"
+                        ),
+                    )
+                }),
+        }
+    }
+}
+
+/// Escapes `LIKE` wildcards so a search term only ever matches literally.
+pub fn escape_like(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+    Word(String),
+    Quoted(String),
+    Operator(Operator),
+    Open,
+    Close,
+    Comma,
+}
+
+impl TokenKind {
+    fn describe(&self) -> String {
+        match self {
+            TokenKind::Word(word) => format!("`{word}`"),
+            TokenKind::Quoted(text) => format!("'{text}'"),
+            TokenKind::Operator(operator) => format!("`{}`", operator.symbol()),
+            TokenKind::Open => "`(`".to_owned(),
+            TokenKind::Close => "`)`".to_owned(),
+            TokenKind::Comma => "`,`".to_owned(),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, TokenKind::Word(word) if word.eq_ignore_ascii_case(keyword))
+    }
+}
+
+impl Operator {
+    fn symbol(self) -> &'static str {
+        match self {
+            Operator::Eq => "=",
+            Operator::Ne => "!=",
+            Operator::Lt => "<",
+            Operator::Le => "<=",
+            Operator::Gt => ">",
+            Operator::Ge => ">=",
+            Operator::Contains => "~",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: TokenKind,
+    at: usize,
+}
+
+const KEYWORDS: [&str; 6] = ["and", "or", "not", "in", "is", "null"];
+
+fn tokenize(input: &str) -> Result<Vec<Token>, AppError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+    while let Some((at, c)) = chars.next() {
+        let kind = match c {
+            c if c.is_whitespace() => continue,
+            '(' => TokenKind::Open,
+            ')' => TokenKind::Close,
+            ',' => TokenKind::Comma,
+            '=' => TokenKind::Operator(Operator::Eq),
+            '~' => TokenKind::Operator(Operator::Contains),
+            '!' | '<' | '>' => {
+                let with_equals = chars.next_if(|&(_, next)| next == '=').is_some();
+                TokenKind::Operator(match (c, with_equals) {
+                    ('!', true) => Operator::Ne,
+                    ('<', false) => Operator::Lt,
+                    ('<', true) => Operator::Le,
+                    ('>', false) => Operator::Gt,
+                    ('>', true) => Operator::Ge,
+                    _ => return Err(filter_error(at, "expected `!=`")),
+                })
+            }
+            '\'' | '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped)) => text.push(escaped),
+                            None => break,
+                        },
+                        Some((_, next)) if next == c => {
+                            tokens.push(Token {
+                                kind: TokenKind::Quoted(text),
+                                at,
+                            });
+                            break;
+                        }
+                        Some((_, next)) => text.push(next),
+                        None => return Err(filter_error(at, "unterminated string")),
+                    }
+                }
+                continue;
+            }
+            c if is_word_char(c) => {
+                let mut word = c.to_string();
+                while let Some((_, next)) = chars.next_if(|&(_, next)| is_word_char(next)) {
+                    word.push(next);
+                }
+                TokenKind::Word(word)
+            }
+            c => return Err(filter_error(at, format!("unexpected `{c}`"))),
+        };
+        tokens.push(Token { kind, at });
+    }
+    Ok(tokens)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | ':' | '.' | '+' | '@')
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    /// Where errors about a missing token point.
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn next_is_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(token) if token.kind.is_keyword(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(
+        &mut self,
+        expected: &str,
+        matches: impl Fn(&TokenKind) -> bool,
+    ) -> Result<Token, AppError> {
+        match self.next() {
+            Some(token) if matches(&token.kind) => Ok(token),
+            Some(token) => Err(filter_error(
+                token.at,
+                format!("expected {expected}, found {}", token.kind.describe()),
+            )),
+            None => Err(filter_error(self.end, format!("expected {expected}"))),
+        }
+    }
+
+    fn or(&mut self, depth: usize) -> Result<Filter, AppError> {
+        let mut filters = vec![self.and(depth)?];
+        while self.next_is_keyword("or") {
+            filters.push(self.and(depth)?);
+        }
+        Ok(match filters.len() {
+            1 => filters.remove(0),
+            _ => Filter::Or(filters),
+        })
+    }
+
+    fn and(&mut self, depth: usize) -> Result<Filter, AppError> {
+        let mut filters = vec![self.unary(depth)?];
+        while self.next_is_keyword("and") {
+            filters.push(self.unary(depth)?);
+        }
+        Ok(match filters.len() {
+            1 => filters.remove(0),
+            _ => Filter::And(filters),
+        })
+    }
+
+    fn unary(&mut self, depth: usize) -> Result<Filter, AppError> {
+        if depth > MAX_NESTING {
+            let at = self.peek().map_or(self.end, |token| token.at);
+            return Err(filter_error(at, "nested too deeply"));
+        }
+        if self.next_is_keyword("not") {
+            return Ok(Filter::Not(Box::new(self.unary(depth + 1)?)));
+        }
+        if matches!(
+            self.peek(),
+            Some(Token {
+                kind: TokenKind::Open,
+                ..
+            })
+        ) {
+            self.position += 1;
+            let filter = self.or(depth + 1)?;
+            self.expect("`)`", |kind| *kind == TokenKind::Close)?;
+            return Ok(filter);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Filter, AppError> {
+        let field = self.expect("a field name", |kind| {
+            matches!(kind, TokenKind::Word(word) if !KEYWORDS.contains(&word.to_ascii_lowercase().as_str()))
+        })?;
+        let TokenKind::Word(name) = field.kind else {
+            unreachable!("matched a word");
+        };
+        let field = Spanned {
+            text: name.to_ascii_lowercase(),
+            at: field.at,
+        };
+        let test = match self.next() {
+            Some(Token {
+                kind: TokenKind::Operator(operator),
+                ..
+            }) => Test::Compare(operator, self.value()?),
+            Some(token) if token.kind.is_keyword("in") => Test::In(self.list()?),
+            Some(token) if token.kind.is_keyword("not") => {
+                self.expect("`in`", |kind| kind.is_keyword("in"))?;
+                return Ok(Filter::Not(Box::new(Filter::Test(Comparison {
+                    field,
+                    test: Test::In(self.list()?),
+                }))));
+            }
+            Some(token) if token.kind.is_keyword("is") => {
+                let negated = self.next_is_keyword("not");
+                self.expect("`null`", |kind| kind.is_keyword("null"))?;
+                match negated {
+                    true => Test::IsNotNull,
+                    false => Test::IsNull,
+                }
+            }
+            Some(token) => {
+                return Err(filter_error(
+                    token.at,
+                    format!(
+                        "expected an operator after `{}`, found {}",
+                        field.text,
+                        token.kind.describe()
+                    ),
+                ))
+            }
+            None => {
+                return Err(filter_error(
+                    self.end,
+                    format!("expected an operator after `{}`", field.text),
+                ))
+            }
+        };
+        Ok(Filter::Test(Comparison { field, test }))
+    }
+
+    fn value(&mut self) -> Result<Spanned, AppError> {
+        let token = self.expect("a value", |kind| {
+            matches!(kind, TokenKind::Word(_) | TokenKind::Quoted(_))
+        })?;
+        match token.kind {
+            TokenKind::Word(text) | TokenKind::Quoted(text) => Ok(Spanned { text, at: token.at }),
+            _ => unreachable!("matched a value"),
+        }
+    }
+
+    fn list(&mut self) -> Result<Vec<Spanned>, AppError> {
+        self.expect("`(`", |kind| *kind == TokenKind::Open)?;
+        let mut values = vec![self.value()?];
+        loop {
+            match self.expect("`,` or `)`", |kind| {
+                matches!(kind, TokenKind::Comma | TokenKind::Close)
+            })? {
+                Token {
+                    kind: TokenKind::Comma,
+                    ..
+                } => values.push(self.value()?),
+                _ => return Ok(values),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tasks;
+
+    /// The parse tree in prefix notation, with lists in brackets.
+    fn show(filter: &Filter) -> String {
+        let all = |name: &str, filters: &[Filter]| {
+            let filters: Vec<_> = filters.iter().map(show).collect();
+            format!("({name} {})", filters.join(" "))
+        };
+        match filter {
+            Filter::And(filters) => all("and", filters),
+            Filter::Or(filters) => all("or", filters),
+            Filter::Not(filter) => format!("(not {})", show(filter)),
+            Filter::Test(Comparison { field, test }) => match test {
+                Test::Compare(operator, value) => {
+                    format!("{} {} {}", field.text, operator.symbol(), value.text)
+                }
+                Test::In(values) => {
+                    let values: Vec<_> = values.iter().map(|value| value.text.as_str()).collect();
+                    format!("{} in [{}]", field.text, values.join(", "))
+                }
+                Test::IsNull => format!("{} is null", field.text),
+                Test::IsNotNull => format!("{} is not null", field.text),
+            },
+        }
+    }
+
+    fn message(error: AppError) -> String {
+        let (status, message) = error.into();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        message
+    }
+
+    #[test]
+    fn parses_valid_expressions() {
+        let cases = [
+            ("priority = high", "priority = high"),
+            ("Title ~ 'dr\\'aft'", "title ~ dr'aft"),
+            (
+                "a != 1 and b < 2 and c <= 3 and d > 4 and e >= 5",
+                "(and a != 1 b < 2 c <= 3 d > 4 e >= 5)",
+            ),
+            ("a = 1 or b = 2 and c = 3", "(or a = 1 (and b = 2 c = 3))"),
+            ("a = 1 and b = 2 or c = 3", "(or (and a = 1 b = 2) c = 3)"),
+            ("(a = 1 or b = 2) and c = 3", "(and (or a = 1 b = 2) c = 3)"),
+            ("not a = 1 and b = 2", "(and (not a = 1) b = 2)"),
+            ("not (a = 1 or b = 2)", "(not (or a = 1 b = 2))"),
+            ("NOT not a = 1", "(not (not a = 1))"),
+            (
+                "priority in (high, \"very urgent\")",
+                "priority in [high, very urgent]",
+            ),
+            ("priority NOT IN (low)", "(not priority in [low])"),
+            (
+                "a in (x) or b not in (y, z) and c = 1",
+                "(or a in [x] (and (not b in [y, z]) c = 1))",
+            ),
+            ("due is null", "due is null"),
+            ("due IS NOT NULL", "due is not null"),
+            (
+                "created >= 2024-01-31T12:00:00+02:00",
+                "created >= 2024-01-31T12:00:00+02:00",
+            ),
+            ("owner = user@example.com", "owner = user@example.com"),
+        ];
+        for (input, expected) in cases {
+            let filter = parse_filter(input).unwrap_or_else(|error| {
+                panic!("{input}: {}", message(error));
+            });
+            assert_eq!(show(&filter), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn keeps_the_position_of_fields_and_values() {
+        let Filter::Test(comparison) = parse_filter("  priority in (high, 'low')").unwrap() else {
+            panic!("expected a comparison");
+        };
+        assert_eq!(comparison.field.at, 2);
+        let Test::In(values) = comparison.test else {
+            panic!("expected a list");
+        };
+        let positions: Vec<_> = values.iter().map(|value| value.at).collect();
+        assert_eq!(positions, [15, 21]);
+    }
+
+    #[test]
+    fn points_at_the_bad_token() {
+        let nested = format!("{}a = 1", "(".repeat(20));
+        let cases = [
+            ("a = 'x", 5, "unterminated string"),
+            ("a ! 1", 3, "expected `!=`"),
+            ("a = 1 # b", 7, "unexpected `#`"),
+            ("a = 1)", 6, "unexpected `)`"),
+            ("a = 1 b = 2", 7, "unexpected `b`"),
+            ("a = 1 and", 10, "expected a field name"),
+            ("and = 1", 1, "expected a field name, found `and`"),
+            ("a = 1 or = 2", 10, "expected a field name, found `=`"),
+            ("a", 2, "expected an operator after `a`"),
+            ("a 1", 3, "expected an operator after `a`, found `1`"),
+            ("a =", 4, "expected a value"),
+            ("a = (", 5, "expected a value, found `(`"),
+            ("(a = 1", 7, "expected `)`"),
+            ("(a = 1 b", 8, "expected `)`, found `b`"),
+            ("a in x", 6, "expected `(`, found `x`"),
+            ("a in ()", 7, "expected a value, found `)`"),
+            ("a in (x y)", 9, "expected `,` or `)`, found `y`"),
+            ("a in (x,", 9, "expected a value"),
+            ("a not x", 7, "expected `in`, found `x`"),
+            ("a is nul", 6, "expected `null`, found `nul`"),
+            ("a is not", 9, "expected `null`"),
+            (nested.as_str(), 18, "nested too deeply"),
+        ];
+        for (input, at, expected) in cases {
+            let error = parse_filter(input).expect_err(input);
+            assert_eq!(
+                message(error),
+                format!("Invalid filter at character {at}: {expected}"),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_overlong_filters() {
+        let input = format!("title = '{}'", "x".repeat(MAX_FILTER_LENGTH));
+        assert_eq!(
+            message(parse_filter(&input).unwrap_err()),
+            "Filters can be at most 1000 characters."
+        );
+    }
+
+    #[test]
+    fn points_at_values_of_the_wrong_kind() {
+        let on_column = |input: &str, kind| {
+            let Filter::Test(comparison) = parse_filter(input).unwrap() else {
+                panic!("expected a comparison");
+            };
+            comparison.on_column(tasks::Column::Title, kind)
+        };
+        let cases = [
+            (
+                "id = 'x'",
+                ValueKind::Int,
+                "Invalid filter at character 6: expected a number, found `x`",
+            ),
+            (
+                "id in (1, two)",
+                ValueKind::Int,
+                "Invalid filter at character 11: expected a number, found `two`",
+            ),
+            (
+                "done = maybe",
+                ValueKind::Bool,
+                "Invalid filter at character 8: expected true or false, found `maybe`",
+            ),
+            (
+                "done < true",
+                ValueKind::Bool,
+                "Invalid filter at character 8: `done` can only be compared with = and !=",
+            ),
+            (
+                "id ~ 1",
+                ValueKind::Int,
+                "Invalid filter at character 6: `id` doesn't support `~`",
+            ),
+            (
+                "due > tomorrow",
+                ValueKind::Time,
+                "Invalid filter at character 7: expected a date like 2024-01-31 or an RFC 3339 \
+                 time, found `tomorrow`",
+            ),
+        ];
+        for (input, kind, expected) in cases {
+            let error = on_column(input, kind).expect_err(input);
+            assert_eq!(message(error), expected, "{input}");
+        }
+        assert!(on_column("due > 2024-01-31", ValueKind::Time).is_ok());
+    }
+
+    #[test]
+    fn escapes_like_wildcards() {
+        assert_eq!(escape_like(r"50%_off\"), r"50\%\_off\\");
+    }
+}
@@ -1,39 +1,156 @@
-use std::env;
+use std::sync::Arc;
 
 use chrono::{Duration, Utc};
 use http::StatusCode;
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
-use serde::{Deserialize, Serialize};
+use jsonwebtoken::{decode, decode_header, encode, errors::ErrorKind, Header, Validation};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use uuid::Uuid;
 
-use super::app_error::AppError;
+use super::{app_error::AppError, keyring::Keyring};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug)]
+pub struct JwtConfig {
+    pub keyring: Arc<Keyring>,
+    pub issuer: String,
+    pub audience: String,
+    pub access_token_ttl: Duration,
+    pub refresh_token_ttl: Duration,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Claims {
-    exp: usize,
-    iat: usize,
+    pub sub: String,
+    /// Session the access token was issued under.
+    pub sid: Uuid,
+    pub jti: String,
+    pub iss: String,
+    pub aud: String,
+    pub exp: usize,
+    pub iat: usize,
+}
+
+impl Claims {
+    pub fn user_id(&self) -> Result<i32, AppError> {
+        self.sub
+            .parse()
+            .map_err(|_| AppError::new(StatusCode::UNAUTHORIZED, "Invalid token.".to_owned()))
+    }
+}
+
+/// Short-lived token proving one step of a multi-step flow, such as a password check
+/// awaiting its second factor. Its audience is tied to `purpose`, so it is never accepted
+/// as an access token or for a different flow.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChallengeClaims {
+    pub sub: String,
+    pub jti: String,
+    pub iss: String,
+    pub aud: String,
+    pub exp: usize,
+    pub iat: usize,
 }
 
-pub fn create_jwt() -> Result<String, AppError> {
+impl ChallengeClaims {
+    pub fn user_id(&self) -> Result<i32, AppError> {
+        self.sub
+            .parse()
+            .map_err(|_| AppError::new(StatusCode::UNAUTHORIZED, "Invalid token.".to_owned()))
+    }
+}
+
+pub fn create_jwt(config: &JwtConfig, user_id: i32, session_id: Uuid) -> Result<String, AppError> {
     let now = Utc::now();
-    let iat = now.timestamp() as usize;
-    let exp = (now + Duration::seconds(30)).timestamp() as usize;
-    let claim = Claims { exp, iat };
-    let secret = env::var("JWT_SECRET").expect("JWT secret not set");
-    let key = EncodingKey::from_secret(secret.as_bytes());
-    encode(&Header::default(), &claim, &key)
+    let claim = Claims {
+        sub: user_id.to_string(),
+        sid: session_id,
+        jti: Uuid::new_v4().to_string(),
+        iss: config.issuer.clone(),
+        aud: config.audience.clone(),
+        exp: (now + config.access_token_ttl).timestamp() as usize,
+        iat: now.timestamp() as usize,
+    };
+    sign(config, &claim)
+}
+
+pub fn decode_claims(config: &JwtConfig, token: &str) -> Result<Claims, AppError> {
+    verify(config, token, &config.audience)
+}
+
+pub fn create_challenge_token(
+    config: &JwtConfig,
+    subject: i32,
+    purpose: &str,
+    jti: Uuid,
+    ttl: Duration,
+) -> Result<String, AppError> {
+    let now = Utc::now();
+    let claim = ChallengeClaims {
+        sub: subject.to_string(),
+        jti: jti.to_string(),
+        iss: config.issuer.clone(),
+        aud: challenge_audience(config, purpose),
+        exp: (now + ttl).timestamp() as usize,
+        iat: now.timestamp() as usize,
+    };
+    sign(config, &claim)
+}
+
+pub fn decode_challenge_token(
+    config: &JwtConfig,
+    token: &str,
+    purpose: &str,
+) -> Result<ChallengeClaims, AppError> {
+    verify(config, token, &challenge_audience(config, purpose))
+}
+
+fn challenge_audience(config: &JwtConfig, purpose: &str) -> String {
+    format!("{}:{purpose}", config.audience)
+}
+
+fn sign<T: Serialize>(config: &JwtConfig, claims: &T) -> Result<String, AppError> {
+    let (kid, algorithm, key) = config.keyring.signing_key();
+    let mut header = Header::new(algorithm);
+    header.kid = Some(kid.to_owned());
+    encode(&header, claims, key)
         .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))
 }
 
-pub fn is_valid(token: &str) -> Result<bool, AppError> {
-    let secret = env::var("JWT_SECRET").expect("JWT secret not set");
-    let key = DecodingKey::from_secret(secret.as_bytes());
-    decode::<Claims>(token, &key, &Validation::new(Algorithm::HS256)).map_err(
-        |error| match error.kind() {
-            jsonwebtoken::errors::ErrorKind::ExpiredSignature => {
-                AppError::new(StatusCode::UNAUTHORIZED, "Invalid token.".to_owned())
-            }
-            _ => AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
-        },
-    )?;
-    Ok(true)
+fn verify<T: DeserializeOwned>(
+    config: &JwtConfig,
+    token: &str,
+    audience: &str,
+) -> Result<T, AppError> {
+    let header = decode_header(token).map_err(map_decode_error)?;
+    let Some((algorithm, key)) = header
+        .kid
+        .as_deref()
+        .and_then(|kid| config.keyring.verifying_key(kid))
+    else {
+        return Err(invalid_token());
+    };
+    let mut validation = Validation::new(algorithm);
+    validation.set_issuer(&[&config.issuer]);
+    validation.set_audience(&[audience]);
+    validation.set_required_spec_claims(&["exp", "sub", "iss", "aud"]);
+    decode::<T>(token, key, &validation)
+        .map(|data| data.claims)
+        .map_err(map_decode_error)
+}
+
+/// Anything wrong with the token itself is the caller's problem; only failures on our side
+/// (a key we can't use) are reported as server errors.
+fn map_decode_error(error: jsonwebtoken::errors::Error) -> AppError {
+    match error.kind() {
+        ErrorKind::InvalidEcdsaKey
+        | ErrorKind::InvalidRsaKey(_)
+        | ErrorKind::InvalidKeyFormat
+        | ErrorKind::Crypto(_) => {
+            AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+        }
+        _ => invalid_token(),
+    }
+}
+
+fn invalid_token() -> AppError {
+    AppError::new(StatusCode::UNAUTHORIZED, "Invalid token.".to_owned())
 }
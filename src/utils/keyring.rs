@@ -0,0 +1,201 @@
+use std::{collections::HashMap, fmt, fs, path::Path};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey,
+};
+use rsa::{
+    pkcs1::DecodeRsaPrivateKey,
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
+};
+
+/// The keys used to sign and verify access tokens, addressed by `kid`.
+///
+/// Exactly one key signs new tokens. Every other key in the ring only verifies, which is
+/// how a retired key keeps accepting the tokens it signed until they expire.
+pub struct Keyring {
+    active_kid: String,
+    signing_algorithm: Algorithm,
+    signing_key: EncodingKey,
+    verifying_keys: HashMap<String, VerifyingKey>,
+}
+
+struct VerifyingKey {
+    algorithm: Algorithm,
+    key: DecodingKey,
+    /// Public form of the key. `None` for shared secrets, which must never be published.
+    jwk: Option<Jwk>,
+}
+
+impl Keyring {
+    /// A single HS256 secret. Tokens can only be verified by holders of the same secret.
+    pub fn from_secret(kid: &str, secret: &[u8]) -> Self {
+        let verifying_key = VerifyingKey {
+            algorithm: Algorithm::HS256,
+            key: DecodingKey::from_secret(secret),
+            jwk: None,
+        };
+        Keyring {
+            active_kid: kid.to_owned(),
+            signing_algorithm: Algorithm::HS256,
+            signing_key: EncodingKey::from_secret(secret),
+            verifying_keys: HashMap::from([(kid.to_owned(), verifying_key)]),
+        }
+    }
+
+    /// Loads every `<kid>.pem` file in `dir`. RSA keys sign with RS256 and Ed25519 keys with
+    /// EdDSA. The `active_kid` file must hold a private key; the others may be public keys.
+    pub fn load_dir(dir: &Path, active_kid: &str) -> Result<Self, String> {
+        let entries = fs::read_dir(dir)
+            .map_err(|error| format!("Could not read key directory {}: {error}", dir.display()))?;
+
+        let mut signing = None;
+        let mut verifying_keys = HashMap::new();
+        for entry in entries {
+            let path = entry.map_err(|error| error.to_string())?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("pem") {
+                continue;
+            }
+            let Some(kid) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let pem = fs::read_to_string(&path)
+                .map_err(|error| format!("Could not read {}: {error}", path.display()))?;
+            let loaded = load_pem(kid, &pem).map_err(|error| format!("{kid}: {error}"))?;
+
+            if kid == active_kid {
+                let Some(encoding_key) = loaded.encoding_key else {
+                    return Err(format!("Active key {kid} is not a private key"));
+                };
+                signing = Some((loaded.algorithm, encoding_key));
+            }
+            verifying_keys.insert(
+                kid.to_owned(),
+                VerifyingKey {
+                    algorithm: loaded.algorithm,
+                    key: DecodingKey::from_jwk(&loaded.jwk).map_err(|error| error.to_string())?,
+                    jwk: Some(loaded.jwk),
+                },
+            );
+        }
+
+        let Some((signing_algorithm, signing_key)) = signing else {
+            return Err(format!(
+                "Active key {active_kid} not found in {}",
+                dir.display()
+            ));
+        };
+        Ok(Keyring {
+            active_kid: active_kid.to_owned(),
+            signing_algorithm,
+            signing_key,
+            verifying_keys,
+        })
+    }
+
+    pub fn signing_key(&self) -> (&str, Algorithm, &EncodingKey) {
+        (&self.active_kid, self.signing_algorithm, &self.signing_key)
+    }
+
+    pub fn verifying_key(&self, kid: &str) -> Option<(Algorithm, &DecodingKey)> {
+        self.verifying_keys
+            .get(kid)
+            .map(|verifying| (verifying.algorithm, &verifying.key))
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .verifying_keys
+                .values()
+                .filter_map(|verifying| verifying.jwk.clone())
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("active_kid", &self.active_kid)
+            .field("kids", &self.verifying_keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+struct LoadedKey {
+    algorithm: Algorithm,
+    encoding_key: Option<EncodingKey>,
+    jwk: Jwk,
+}
+
+fn load_pem(kid: &str, pem: &str) -> Result<LoadedKey, String> {
+    let (key_algorithm, parameters, encoding_key) = if pem.contains("PRIVATE KEY") {
+        if let Ok(key) =
+            RsaPrivateKey::from_pkcs8_pem(pem).or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+        {
+            let encoding_key =
+                EncodingKey::from_rsa_pem(pem.as_bytes()).map_err(|error| error.to_string())?;
+            (
+                KeyAlgorithm::RS256,
+                rsa_parameters(&key.to_public_key()),
+                Some(encoding_key),
+            )
+        } else {
+            let key = ed25519_dalek::SigningKey::from_pkcs8_pem(pem)
+                .map_err(|_| "unsupported private key, expected RSA or Ed25519".to_owned())?;
+            let encoding_key =
+                EncodingKey::from_ed_pem(pem.as_bytes()).map_err(|error| error.to_string())?;
+            (
+                KeyAlgorithm::EdDSA,
+                ed25519_parameters(&key.verifying_key()),
+                Some(encoding_key),
+            )
+        }
+    } else if let Ok(key) = RsaPublicKey::from_public_key_pem(pem) {
+        (KeyAlgorithm::RS256, rsa_parameters(&key), None)
+    } else {
+        let key = ed25519_dalek::VerifyingKey::from_public_key_pem(pem)
+            .map_err(|_| "unsupported public key, expected RSA or Ed25519".to_owned())?;
+        (KeyAlgorithm::EdDSA, ed25519_parameters(&key), None)
+    };
+
+    Ok(LoadedKey {
+        algorithm: match key_algorithm {
+            KeyAlgorithm::RS256 => Algorithm::RS256,
+            _ => Algorithm::EdDSA,
+        },
+        encoding_key,
+        jwk: Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(key_algorithm),
+                key_id: Some(kid.to_owned()),
+                ..Default::default()
+            },
+            algorithm: parameters,
+        },
+    })
+}
+
+fn rsa_parameters(key: &RsaPublicKey) -> AlgorithmParameters {
+    AlgorithmParameters::RSA(RSAKeyParameters {
+        key_type: RSAKeyType::RSA,
+        n: URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+        e: URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+    })
+}
+
+fn ed25519_parameters(key: &ed25519_dalek::VerifyingKey) -> AlgorithmParameters {
+    AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+        key_type: OctetKeyPairType::OctetKeyPair,
+        curve: EllipticCurve::Ed25519,
+        x: URL_SAFE_NO_PAD.encode(key.as_bytes()),
+    })
+}
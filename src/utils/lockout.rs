@@ -0,0 +1,406 @@
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use axum::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use http::StatusCode;
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, sea_query::OnConflict, ActiveModelTrait, ConnectionTrait,
+    DatabaseBackend, DatabaseConnection, EntityTrait, Set, Statement,
+};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use super::app_error::AppError;
+use crate::database::{login_attempts, prelude::LoginAttempts};
+
+/// Upper bound for the delay added to a failed attempt.
+const MAX_DELAY: Duration = Duration::seconds(5);
+
+#[derive(Clone, Debug)]
+pub struct LockoutConfig {
+    pub store: AttemptStoreKind,
+    /// Failures within `window` before an account is locked.
+    pub max_failures: u32,
+    /// Failures within `window` before a source address is locked, across all accounts.
+    pub max_failures_per_ip: u32,
+    pub window: Duration,
+    pub lockout: Duration,
+    /// Delay after the first failure; it doubles with every further one.
+    pub base_delay: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum AttemptStoreKind {
+    Memory,
+    Postgres,
+}
+
+/// Where failed-attempt counters are kept.
+#[async_trait]
+pub trait AttemptStore: Debug + Send + Sync {
+    /// Counts a failure against `key` and returns the count so far. The count restarts if
+    /// the current window began before `window_start`.
+    async fn record_failure(
+        &self,
+        key: &str,
+        window_start: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<u32, AppError>;
+
+    async fn locked_until(&self, key: &str) -> Result<Option<DateTime<Utc>>, AppError>;
+
+    /// Locks `key` until `until` and restarts its count.
+    async fn lock(&self, key: &str, until: DateTime<Utc>) -> Result<(), AppError>;
+
+    async fn clear(&self, key: &str) -> Result<(), AppError>;
+}
+
+#[derive(Debug)]
+struct AttemptRecord {
+    failures: u32,
+    window_started_at: DateTime<Utc>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+/// Keeps counters in process memory. Only suitable for a single instance.
+#[derive(Debug, Default)]
+pub struct MemoryAttemptStore {
+    records: Mutex<HashMap<String, AttemptRecord>>,
+}
+
+#[async_trait]
+impl AttemptStore for MemoryAttemptStore {
+    async fn record_failure(
+        &self,
+        key: &str,
+        window_start: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<u32, AppError> {
+        let mut records = self.records.lock().await;
+        let record = records.entry(key.to_owned()).or_insert(AttemptRecord {
+            failures: 0,
+            window_started_at: now,
+            locked_until: None,
+        });
+        if record.window_started_at <= window_start {
+            record.failures = 0;
+            record.window_started_at = now;
+        }
+        record.failures += 1;
+        Ok(record.failures)
+    }
+
+    async fn locked_until(&self, key: &str) -> Result<Option<DateTime<Utc>>, AppError> {
+        let records = self.records.lock().await;
+        Ok(records.get(key).and_then(|record| record.locked_until))
+    }
+
+    async fn lock(&self, key: &str, until: DateTime<Utc>) -> Result<(), AppError> {
+        let mut records = self.records.lock().await;
+        records.insert(
+            key.to_owned(),
+            AttemptRecord {
+                failures: 0,
+                window_started_at: Utc::now(),
+                locked_until: Some(until),
+            },
+        );
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), AppError> {
+        self.records.lock().await.remove(key);
+        Ok(())
+    }
+}
+
+/// Keeps counters in the `login_attempts` table, shared by every instance.
+#[derive(Debug)]
+pub struct PostgresAttemptStore {
+    database: DatabaseConnection,
+}
+
+impl PostgresAttemptStore {
+    pub fn new(database: DatabaseConnection) -> Self {
+        PostgresAttemptStore { database }
+    }
+}
+
+#[async_trait]
+impl AttemptStore for PostgresAttemptStore {
+    async fn record_failure(
+        &self,
+        key: &str,
+        window_start: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<u32, AppError> {
+        // A single upsert, so concurrent failures can't lose increments.
+        let statement = Statement::from_sql_and_values(
+            DatabaseBackend::Postgres,
+            r#"INSERT INTO login_attempts (key, failures, window_started_at)
+               VALUES ($1, 1, $2)
+               ON CONFLICT (key) DO UPDATE SET
+                   failures = CASE WHEN login_attempts.window_started_at > $3
+                       THEN login_attempts.failures + 1 ELSE 1 END,
+                   window_started_at = CASE WHEN login_attempts.window_started_at > $3
+                       THEN login_attempts.window_started_at ELSE $2 END
+               RETURNING failures"#,
+            [
+                key.into(),
+                DateTimeWithTimeZone::from(now).into(),
+                DateTimeWithTimeZone::from(window_start).into(),
+            ],
+        );
+        let row = self
+            .database
+            .query_one(statement)
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+            .ok_or_else(|| {
+                AppError::new(StatusCode::INTERNAL_SERVER_ERROR, "Upsert returned no row.")
+            })?;
+        let failures: i32 = row
+            .try_get("", "failures")
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        Ok(failures as u32)
+    }
+
+    async fn locked_until(&self, key: &str) -> Result<Option<DateTime<Utc>>, AppError> {
+        let record = LoginAttempts::find_by_id(key)
+            .one(&self.database)
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        Ok(record
+            .and_then(|record| record.locked_until)
+            .map(|until| until.with_timezone(&Utc)))
+    }
+
+    async fn lock(&self, key: &str, until: DateTime<Utc>) -> Result<(), AppError> {
+        let record = login_attempts::ActiveModel {
+            key: Set(key.to_owned()),
+            failures: Set(0),
+            window_started_at: Set(Utc::now().into()),
+            locked_until: Set(Some(until.into())),
+        };
+        LoginAttempts::insert(record)
+            .on_conflict(
+                OnConflict::column(login_attempts::Column::Key)
+                    .update_columns([
+                        login_attempts::Column::Failures,
+                        login_attempts::Column::WindowStartedAt,
+                        login_attempts::Column::LockedUntil,
+                    ])
+                    .to_owned(),
+            )
+            .exec(&self.database)
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        Ok(())
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), AppError> {
+        login_attempts::ActiveModel {
+            key: Set(key.to_owned()),
+            ..Default::default()
+        }
+        .delete(&self.database)
+        .await
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Tracks failed sign-ins per account and per source address, slowing down and then locking
+/// out whoever keeps guessing.
+#[derive(Clone, Debug)]
+pub struct LoginThrottle {
+    store: Arc<dyn AttemptStore>,
+    config: LockoutConfig,
+}
+
+impl LoginThrottle {
+    pub fn new(config: LockoutConfig, database: DatabaseConnection) -> Self {
+        let store: Arc<dyn AttemptStore> = match config.store {
+            AttemptStoreKind::Memory => Arc::new(MemoryAttemptStore::default()),
+            AttemptStoreKind::Postgres => Arc::new(PostgresAttemptStore::new(database)),
+        };
+        LoginThrottle { store, config }
+    }
+
+    /// Fails with 429 while either the account or the source address is locked.
+    pub async fn check(&self, username: &str, ip: Option<&str>) -> Result<(), AppError> {
+        let now = Utc::now();
+        for key in keys(username, ip) {
+            if let Some(until) = self.store.locked_until(&key).await? {
+                if until > now {
+                    return Err(locked_out());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a failed attempt, locking whatever crossed its threshold, then waits out the
+    /// progressive delay before the caller responds.
+    pub async fn record_failure(&self, username: &str, ip: Option<&str>) -> Result<(), AppError> {
+        let now = Utc::now();
+        let window_start = now - self.config.window;
+        let mut most_failures = 0;
+        for key in keys(username, ip) {
+            let failures = self.store.record_failure(&key, window_start, now).await?;
+            let threshold = if key.starts_with("ip:") {
+                self.config.max_failures_per_ip
+            } else {
+                self.config.max_failures
+            };
+            if failures >= threshold {
+                self.store.lock(&key, now + self.config.lockout).await?;
+                info!(key, "locked out after repeated failed sign-ins");
+            }
+            most_failures = most_failures.max(failures);
+        }
+        tokio::time::sleep(self.delay(most_failures)).await;
+        Ok(())
+    }
+
+    /// A successful sign-in clears the account's count. The address keeps its count so one
+    /// valid account can't be used to reset it.
+    pub async fn record_success(&self, username: &str) -> Result<(), AppError> {
+        self.store.clear(&account_key(username)).await
+    }
+
+    pub async fn unlock(&self, username: &str) -> Result<(), AppError> {
+        self.store.clear(&account_key(username)).await
+    }
+
+    fn delay(&self, failures: u32) -> std::time::Duration {
+        let exponent = failures.saturating_sub(1).min(16);
+        (self.config.base_delay * 2_i32.pow(exponent))
+            .min(MAX_DELAY)
+            .to_std()
+            .unwrap_or_default()
+    }
+}
+
+fn keys(username: &str, ip: Option<&str>) -> Vec<String> {
+    let mut keys = vec![account_key(username)];
+    if let Some(ip) = ip {
+        keys.push(format!("ip:{ip}"));
+    }
+    keys
+}
+
+pub fn account_key(username: &str) -> String {
+    format!("account:{}", username.to_lowercase())
+}
+
+fn locked_out() -> AppError {
+    AppError::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        "Too many failed sign-in attempts. Try again later.",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(lockout: Duration) -> LoginThrottle {
+        let config = LockoutConfig {
+            store: AttemptStoreKind::Memory,
+            max_failures: 3,
+            max_failures_per_ip: 5,
+            window: Duration::minutes(15),
+            lockout,
+            base_delay: Duration::milliseconds(1),
+        };
+        LoginThrottle::new(config, DatabaseConnection::Disconnected)
+    }
+
+    async fn fail(throttle: &LoginThrottle, username: &str, ip: &str, times: u32) {
+        for _ in 0..times {
+            throttle.record_failure(username, Some(ip)).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn locks_the_account_at_the_threshold() {
+        let throttle = throttle(Duration::minutes(15));
+        fail(&throttle, "alice@example.com", "10.0.0.1", 2).await;
+        assert!(throttle.check("alice@example.com", None).await.is_ok());
+
+        fail(&throttle, "alice@example.com", "10.0.0.1", 1).await;
+        let (code, _) = throttle
+            .check("Alice@Example.com", None)
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(code, StatusCode::TOO_MANY_REQUESTS);
+        assert!(throttle.check("bob@example.com", None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn locks_the_source_address_across_accounts() {
+        let throttle = throttle(Duration::minutes(15));
+        for username in ["a@example.com", "b@example.com", "c@example.com"] {
+            fail(&throttle, username, "10.0.0.1", 1).await;
+        }
+        fail(&throttle, "d@example.com", "10.0.0.1", 2).await;
+
+        assert!(throttle
+            .check("e@example.com", Some("10.0.0.1"))
+            .await
+            .is_err());
+        assert!(throttle
+            .check("e@example.com", Some("10.0.0.2"))
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn delay_doubles_with_every_failure_up_to_the_cap() {
+        let throttle = throttle(Duration::minutes(15));
+        let millis = |failures| throttle.delay(failures).as_millis();
+        assert_eq!(millis(1), 1);
+        assert_eq!(millis(2), 2);
+        assert_eq!(millis(4), 8);
+        assert_eq!(
+            throttle.delay(40),
+            MAX_DELAY.to_std().unwrap(),
+            "delay is capped"
+        );
+    }
+
+    #[tokio::test]
+    async fn lockout_expires() {
+        let throttle = throttle(Duration::milliseconds(100));
+        fail(&throttle, "alice@example.com", "10.0.0.1", 3).await;
+        assert!(throttle.check("alice@example.com", None).await.is_err());
+
+        tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+        assert!(throttle.check("alice@example.com", None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn unlock_clears_the_account_but_not_the_address() {
+        let throttle = throttle(Duration::minutes(15));
+        fail(&throttle, "alice@example.com", "10.0.0.1", 3).await;
+        fail(&throttle, "bob@example.com", "10.0.0.1", 2).await;
+
+        throttle.unlock("ALICE@example.com").await.unwrap();
+        assert!(throttle.check("alice@example.com", None).await.is_ok());
+        assert!(throttle
+            .check("alice@example.com", Some("10.0.0.1"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn success_restarts_the_account_count() {
+        let throttle = throttle(Duration::minutes(15));
+        fail(&throttle, "alice@example.com", "10.0.0.1", 2).await;
+        throttle.record_success("alice@example.com").await.unwrap();
+        fail(&throttle, "alice@example.com", "10.0.0.2", 2).await;
+        assert!(throttle.check("alice@example.com", None).await.is_ok());
+    }
+}
@@ -0,0 +1,105 @@
+use std::{fmt::Debug, path::PathBuf, sync::Arc};
+
+use axum::async_trait;
+use http::StatusCode;
+use lettre::{message::Mailbox, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tracing::info;
+use uuid::Uuid;
+
+use super::app_error::AppError;
+
+#[derive(Clone, Debug)]
+pub struct MailerConfig {
+    pub from: String,
+    pub transport: MailTransport,
+}
+
+#[derive(Clone, Debug)]
+pub enum MailTransport {
+    /// An `smtp://` or `smtps://` URL, credentials included.
+    Smtp(String),
+    /// Writes every message to a `.eml` file in this directory instead of sending it.
+    File(PathBuf),
+}
+
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Debug + Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), AppError>;
+}
+
+pub fn build_mailer(config: MailerConfig) -> Result<Arc<dyn Mailer>, String> {
+    let from: Mailbox = config
+        .from
+        .parse()
+        .map_err(|error| format!("Invalid sender address: {error}"))?;
+    Ok(match config.transport {
+        MailTransport::Smtp(url) => Arc::new(SmtpMailer {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::from_url(&url)
+                .map_err(|error| error.to_string())?
+                .build(),
+            from,
+        }),
+        MailTransport::File(dir) => Arc::new(FileMailer { dir, from }),
+    })
+}
+
+#[derive(Debug)]
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        let message = build_message(&self.from, email)?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(|error| AppError::new(StatusCode::BAD_GATEWAY, error.to_string()))?;
+        Ok(())
+    }
+}
+
+/// For local development: nothing leaves the machine.
+#[derive(Debug)]
+pub struct FileMailer {
+    dir: PathBuf,
+    from: Mailbox,
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), AppError> {
+        let message = build_message(&self.from, email)?;
+        let path = self.dir.join(format!("{}.eml", Uuid::new_v4()));
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+        tokio::fs::write(&path, message.formatted())
+            .await
+            .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+        info!(path = %path.display(), "email written");
+        Ok(())
+    }
+}
+
+fn build_message(from: &Mailbox, email: Email) -> Result<Message, AppError> {
+    let to: Mailbox = email
+        .to
+        .parse()
+        .map_err(|_| AppError::new(StatusCode::BAD_REQUEST, "Invalid email address."))?;
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(email.subject)
+        .body(email.body)
+        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))
+}
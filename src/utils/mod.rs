@@ -1,3 +1,17 @@
 pub mod app_error;
+pub mod avatar;
+pub mod filter;
 pub mod jwt;
+pub mod keyring;
+pub mod lockout;
+pub mod mailer;
+pub mod oidc;
+pub mod pagination;
+pub mod passkey;
 pub mod password;
+pub mod password_policy;
+pub mod permission;
+pub mod purge;
+pub mod storage;
+pub mod token;
+pub mod totp;
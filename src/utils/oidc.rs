@@ -0,0 +1,258 @@
+use std::{collections::HashMap, sync::Arc};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http::StatusCode;
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::{OnceCell, RwLock};
+use url::Url;
+
+use super::app_error::AppError;
+use super::token::generate_opaque_token;
+
+#[derive(Clone, Debug)]
+pub struct OidcProviderConfig {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    pub scopes: String,
+}
+
+/// Every configured identity provider, keyed by the name used in routes.
+#[derive(Clone, Debug, Default)]
+pub struct OidcProviders(Arc<HashMap<String, OidcClient>>);
+
+impl OidcProviders {
+    pub fn new(configs: Vec<OidcProviderConfig>) -> Self {
+        let http = reqwest::Client::new();
+        OidcProviders(Arc::new(
+            configs
+                .into_iter()
+                .map(|config| {
+                    let client = OidcClient {
+                        http: http.clone(),
+                        discovery: OnceCell::new(),
+                        jwks: RwLock::new(None),
+                        config,
+                    };
+                    (client.config.name.clone(), client)
+                })
+                .collect(),
+        ))
+    }
+
+    pub fn get(&self, name: &str) -> Result<&OidcClient, AppError> {
+        self.0
+            .get(name)
+            .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, "Unknown identity provider."))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    nonce: Option<String>,
+}
+
+/// A PKCE verifier and its S256 challenge.
+pub struct Pkce {
+    pub verifier: String,
+    pub challenge: String,
+}
+
+impl Pkce {
+    pub fn generate() -> Self {
+        let verifier = generate_opaque_token();
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        Pkce {
+            verifier,
+            challenge,
+        }
+    }
+}
+
+/// Client for one provider. Discovery is loaded on first use and the provider's JWKS is
+/// cached until a token arrives signed by a key we haven't seen.
+#[derive(Debug)]
+pub struct OidcClient {
+    config: OidcProviderConfig,
+    http: reqwest::Client,
+    discovery: OnceCell<Discovery>,
+    jwks: RwLock<Option<JwkSet>>,
+}
+
+impl OidcClient {
+    pub async fn authorization_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+    ) -> Result<String, AppError> {
+        let discovery = self.discovery().await?;
+        let mut url = Url::parse(&discovery.authorization_endpoint)
+            .map_err(|error| provider_error(error.to_string()))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_uri)
+            .append_pair("scope", &self.config.scopes)
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", code_challenge)
+            .append_pair("code_challenge_method", "S256");
+        Ok(url.into())
+    }
+
+    /// Redeems an authorization code and returns the validated ID token claims.
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, AppError> {
+        let discovery = self.discovery().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let response = self
+            .http
+            .post(&discovery.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|error| provider_error(error.to_string()))?;
+        if !response.status().is_success() {
+            return Err(AppError::new(
+                StatusCode::UNAUTHORIZED,
+                "Identity provider rejected the authorization code.",
+            ));
+        }
+        let tokens: TokenResponse = response
+            .json()
+            .await
+            .map_err(|error| provider_error(error.to_string()))?;
+        self.validate_id_token(&tokens.id_token, nonce).await
+    }
+
+    async fn validate_id_token(
+        &self,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, AppError> {
+        let discovery = self.discovery().await?;
+        let header = decode_header(id_token).map_err(|_| invalid_id_token())?;
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(invalid_id_token());
+        }
+        let key = self.decoding_key(header.kid.as_deref()).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&discovery.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map_err(|_| invalid_id_token())?
+            .claims;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(invalid_id_token());
+        }
+        Ok(claims)
+    }
+
+    async fn decoding_key(&self, kid: Option<&str>) -> Result<DecodingKey, AppError> {
+        if let Some(key) = self.cached_key(kid).await {
+            return Ok(key);
+        }
+        // The provider may have rotated its keys since we last looked.
+        let jwks: JwkSet = self
+            .http
+            .get(&self.discovery().await?.jwks_uri)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|error| provider_error(error.to_string()))?
+            .json()
+            .await
+            .map_err(|error| provider_error(error.to_string()))?;
+        *self.jwks.write().await = Some(jwks);
+        self.cached_key(kid).await.ok_or_else(invalid_id_token)
+    }
+
+    async fn cached_key(&self, kid: Option<&str>) -> Option<DecodingKey> {
+        let jwks = self.jwks.read().await;
+        let jwks = jwks.as_ref()?;
+        let jwk = match kid {
+            Some(kid) => jwks.find(kid)?,
+            None if jwks.keys.len() == 1 => &jwks.keys[0],
+            None => return None,
+        };
+        DecodingKey::from_jwk(jwk).ok()
+    }
+
+    async fn discovery(&self) -> Result<&Discovery, AppError> {
+        self.discovery
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let discovery: Discovery = self
+                    .http
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|error| provider_error(error.to_string()))?
+                    .json()
+                    .await
+                    .map_err(|error| provider_error(error.to_string()))?;
+                if discovery.issuer.trim_end_matches('/')
+                    != self.config.issuer.trim_end_matches('/')
+                {
+                    return Err(provider_error("discovery issuer mismatch".to_owned()));
+                }
+                Ok(discovery)
+            })
+            .await
+    }
+}
+
+fn provider_error(message: String) -> AppError {
+    AppError::new(
+        StatusCode::BAD_GATEWAY,
+        format!("Identity provider error: {message}"),
+    )
+}
+
+fn invalid_id_token() -> AppError {
+    AppError::new(StatusCode::UNAUTHORIZED, "Invalid ID token.")
+}
@@ -0,0 +1,489 @@
+use axum::http::Uri;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http::StatusCode;
+use sea_orm::{
+    prelude::DateTimeWithTimeZone,
+    sea_query::{NullOrdering, Order},
+    ColumnTrait, Condition, EntityTrait, QueryFilter, QueryOrder, QuerySelect, Select, Value,
+};
+use serde::{Deserialize, Serialize};
+
+use super::app_error::AppError;
+
+pub const DEFAULT_PAGE_SIZE: u64 = 50;
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+/// Query parameters shared by every paginated listing. `after` and `before` take the
+/// cursors of a previous page and can't be combined.
+#[derive(Debug, Default, Deserialize)]
+pub struct PageParams {
+    limit: Option<u64>,
+    #[serde(alias = "cursor")]
+    after: Option<String>,
+    before: Option<String>,
+    /// Also counts every matching row, at the cost of an extra query.
+    #[serde(default)]
+    total: bool,
+}
+
+/// One page of a keyset-paginated listing. Cursors and links are absent at either end.
+#[derive(Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+    pub prev_cursor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    pub links: PageLinks,
+}
+
+#[derive(Serialize)]
+pub struct PageLinks {
+    pub next: Option<String>,
+    pub prev: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    fn reversed(self) -> Self {
+        match self {
+            SortOrder::Asc => SortOrder::Desc,
+            SortOrder::Desc => SortOrder::Asc,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SortKey<C> {
+    pub column: C,
+    pub order: SortOrder,
+}
+
+/// The value of one sort key on the row a cursor points at.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CursorValue {
+    Null,
+    Int(i64),
+    Text(String),
+    Time(DateTimeWithTimeZone),
+}
+
+impl From<i16> for CursorValue {
+    fn from(value: i16) -> Self {
+        CursorValue::Int(value.into())
+    }
+}
+
+impl From<i32> for CursorValue {
+    fn from(value: i32) -> Self {
+        CursorValue::Int(value.into())
+    }
+}
+
+impl From<String> for CursorValue {
+    fn from(value: String) -> Self {
+        CursorValue::Text(value)
+    }
+}
+
+impl<T: Into<CursorValue>> From<Option<T>> for CursorValue {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(CursorValue::Null, Into::into)
+    }
+}
+
+impl From<DateTimeWithTimeZone> for CursorValue {
+    fn from(value: DateTimeWithTimeZone) -> Self {
+        CursorValue::Time(value)
+    }
+}
+
+impl CursorValue {
+    fn into_value(self) -> Option<Value> {
+        match self {
+            CursorValue::Null => None,
+            CursorValue::Int(value) => Some(value.into()),
+            CursorValue::Text(value) => Some(value.into()),
+            CursorValue::Time(value) => Some(value.into()),
+        }
+    }
+}
+
+/// Cursors carry the sort keys of a page's first or last row, as base64url JSON, and the
+/// sort they were made for. Clients treat them as opaque.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    sort: String,
+    keys: Vec<CursorValue>,
+}
+
+/// Keyset pagination over `keys`, whose last key must be unique so that every row has a
+/// distinct position. Nulls sort after every value in both directions.
+pub struct Keyset<C> {
+    keys: Vec<SortKey<C>>,
+    limit: u64,
+    cursor: Option<Vec<CursorValue>>,
+    /// Paging towards the start, from a `before` cursor.
+    backward: bool,
+    total: bool,
+}
+
+impl<C: ColumnTrait> Keyset<C> {
+    pub fn new(params: PageParams, keys: Vec<SortKey<C>>) -> Result<Self, AppError> {
+        let (cursor, backward) = match (params.after, params.before) {
+            (Some(_), Some(_)) => {
+                return Err(AppError::new(
+                    StatusCode::BAD_REQUEST,
+                    "Use either `after` or `before`, not both.",
+                ))
+            }
+            (Some(after), None) => (Some(after), false),
+            (None, Some(before)) => (Some(before), true),
+            (None, None) => (None, false),
+        };
+        let mut keyset = Keyset {
+            keys,
+            limit: params
+                .limit
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .clamp(1, MAX_PAGE_SIZE),
+            cursor: None,
+            backward,
+            total: params.total,
+        };
+        if let Some(cursor) = cursor {
+            keyset.cursor = Some(keyset.decode(&cursor)?);
+        }
+        Ok(keyset)
+    }
+
+    /// Whether the caller asked for the total number of matching rows.
+    pub fn wants_total(&self) -> bool {
+        self.total
+    }
+
+    /// Orders `select`, skips to the cursor and fetches one row more than a page, to tell
+    /// whether another page follows.
+    pub fn apply<E: EntityTrait<Column = C>>(&self, mut select: Select<E>) -> Select<E> {
+        let nulls = match self.backward {
+            false => NullOrdering::Last,
+            true => NullOrdering::First,
+        };
+        for key in &self.keys {
+            let order = match self.order(key) {
+                SortOrder::Asc => Order::Asc,
+                SortOrder::Desc => Order::Desc,
+            };
+            select = select.order_by_with_nulls(key.column, order, nulls);
+        }
+        if let Some(cursor) = &self.cursor {
+            select = select.filter(self.past(cursor));
+        }
+        select.limit(self.limit + 1)
+    }
+
+    /// Builds a page from rows fetched with `apply`. `key` reads the sort keys off a row, in
+    /// order; links keep every other parameter of `uri`.
+    pub fn page<R, T>(
+        &self,
+        mut rows: Vec<R>,
+        key: impl Fn(&R) -> Vec<CursorValue>,
+        item: impl FnMut(R) -> T,
+        total: Option<u64>,
+        uri: &Uri,
+    ) -> Result<Page<T>, AppError> {
+        let has_more = rows.len() as u64 > self.limit;
+        rows.truncate(self.limit as usize);
+        let (more_after, more_before) = match self.backward {
+            false => (has_more, self.cursor.is_some()),
+            true => {
+                rows.reverse();
+                (self.cursor.is_some(), has_more)
+            }
+        };
+        let next_cursor = match rows.last() {
+            Some(last) if more_after => Some(self.encode(key(last))?),
+            _ => None,
+        };
+        let prev_cursor = match rows.first() {
+            Some(first) if more_before => Some(self.encode(key(first))?),
+            _ => None,
+        };
+        Ok(Page {
+            items: rows.into_iter().map(item).collect(),
+            links: PageLinks {
+                next: next_cursor
+                    .as_deref()
+                    .map(|cursor| page_link(uri, "after", cursor)),
+                prev: prev_cursor
+                    .as_deref()
+                    .map(|cursor| page_link(uri, "before", cursor)),
+            },
+            next_cursor,
+            prev_cursor,
+            total,
+        })
+    }
+
+    fn order(&self, key: &SortKey<C>) -> SortOrder {
+        match self.backward {
+            false => key.order,
+            true => key.order.reversed(),
+        }
+    }
+
+    /// Rows strictly past the cursor in traversal order: equal on every key before some key
+    /// and past the cursor on that one.
+    fn past(&self, cursor: &[CursorValue]) -> Condition {
+        let mut past = Condition::any();
+        let mut equal = Condition::all();
+        for (key, value) in self.keys.iter().zip(cursor) {
+            let value = value.clone().into_value();
+            let beyond = match (value.clone(), self.order(key), self.backward) {
+                // Nothing sorts after a null when nulls come last.
+                (None, _, false) => None,
+                (None, _, true) => Some(Condition::all().add(key.column.is_not_null())),
+                (Some(value), SortOrder::Asc, false) => Some(
+                    Condition::any()
+                        .add(key.column.gt(value))
+                        .add(key.column.is_null()),
+                ),
+                (Some(value), SortOrder::Desc, false) => Some(
+                    Condition::any()
+                        .add(key.column.lt(value))
+                        .add(key.column.is_null()),
+                ),
+                (Some(value), SortOrder::Asc, true) => {
+                    Some(Condition::all().add(key.column.gt(value)))
+                }
+                (Some(value), SortOrder::Desc, true) => {
+                    Some(Condition::all().add(key.column.lt(value)))
+                }
+            };
+            if let Some(beyond) = beyond {
+                past = past.add(equal.clone().add(beyond));
+            }
+            equal = match value {
+                Some(value) => equal.add(key.column.eq(value)),
+                None => equal.add(key.column.is_null()),
+            };
+        }
+        past
+    }
+
+    fn signature(&self) -> String {
+        self.keys
+            .iter()
+            .map(|key| match key.order {
+                SortOrder::Asc => key.column.as_str().to_owned(),
+                SortOrder::Desc => format!("-{}", key.column.as_str()),
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn encode(&self, keys: Vec<CursorValue>) -> Result<String, AppError> {
+        let cursor = Cursor {
+            sort: self.signature(),
+            keys,
+        };
+        let json = serde_json::to_vec(&cursor)
+            .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+        Ok(URL_SAFE_NO_PAD.encode(json))
+    }
+
+    fn decode(&self, cursor: &str) -> Result<Vec<CursorValue>, AppError> {
+        let cursor: Cursor = URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| AppError::new(StatusCode::BAD_REQUEST, "Invalid cursor."))?;
+        if cursor.sort != self.signature() || cursor.keys.len() != self.keys.len() {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                "This cursor belongs to a different sort order.",
+            ));
+        }
+        Ok(cursor.keys)
+    }
+}
+
+/// The current request's path and query, with `param` set to `cursor` and the other cursor
+/// parameters dropped.
+fn page_link(uri: &Uri, param: &str, cursor: &str) -> String {
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    for (name, value) in url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()) {
+        if !matches!(name.as_ref(), "after" | "before" | "cursor") {
+            query.append_pair(&name, &value);
+        }
+    }
+    query.append_pair(param, cursor);
+    format!("{}?{}", uri.path(), query.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{DbBackend, QueryTrait};
+
+    use super::*;
+    use crate::database::tasks;
+
+    /// Tasks by priority, which is nullable, then id.
+    fn keyset(order: SortOrder, params: PageParams) -> Result<Keyset<tasks::Column>, AppError> {
+        Keyset::new(
+            params,
+            vec![
+                SortKey {
+                    column: tasks::Column::Priority,
+                    order,
+                },
+                SortKey {
+                    column: tasks::Column::Id,
+                    order,
+                },
+            ],
+        )
+    }
+
+    fn after(cursor: String) -> PageParams {
+        PageParams {
+            after: Some(cursor),
+            ..Default::default()
+        }
+    }
+
+    fn before(cursor: String) -> PageParams {
+        PageParams {
+            before: Some(cursor),
+            ..Default::default()
+        }
+    }
+
+    fn status(result: Result<Keyset<tasks::Column>, AppError>) -> StatusCode {
+        let (status, _) = result.err().expect("expected an error").into();
+        status
+    }
+
+    /// The WHERE clause `past` produces for `cursor`.
+    fn past_sql(keyset: &Keyset<tasks::Column>, cursor: &[CursorValue]) -> String {
+        let sql = tasks::Entity::find()
+            .filter(keyset.past(cursor))
+            .build(DbBackend::Postgres)
+            .to_string();
+        sql.split_once(" WHERE ").unwrap().1.to_owned()
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let first = keyset(SortOrder::Asc, PageParams::default()).unwrap();
+        let keys = vec![CursorValue::Int(2), CursorValue::Int(17)];
+        let cursor = first.encode(keys.clone()).unwrap();
+
+        let next = keyset(SortOrder::Asc, after(cursor)).unwrap();
+        assert_eq!(next.cursor, Some(keys));
+        assert!(!next.backward);
+
+        let nulls = vec![CursorValue::Null, CursorValue::Int(3)];
+        let cursor = first.encode(nulls.clone()).unwrap();
+        let prev = keyset(SortOrder::Asc, before(cursor)).unwrap();
+        assert_eq!(prev.cursor, Some(nulls));
+        assert!(prev.backward);
+    }
+
+    #[test]
+    fn rejects_a_cursor_made_for_another_sort() {
+        let cursor = keyset(SortOrder::Asc, PageParams::default())
+            .unwrap()
+            .encode(vec![CursorValue::Int(2), CursorValue::Int(17)])
+            .unwrap();
+        assert_eq!(
+            status(keyset(SortOrder::Desc, after(cursor))),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn rejects_a_malformed_cursor() {
+        assert_eq!(
+            status(keyset(SortOrder::Asc, after("not a cursor".to_owned()))),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn rejects_after_and_before_together() {
+        let params = PageParams {
+            after: Some("a".to_owned()),
+            before: Some("b".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            status(keyset(SortOrder::Asc, params)),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    /// The WHERE clause for rows past a cursor at `keys`, paging in `order` either way.
+    fn from_cursor(order: SortOrder, keys: [CursorValue; 2], backward: bool) -> String {
+        let cursor = keyset(order, PageParams::default())
+            .unwrap()
+            .encode(keys.to_vec())
+            .unwrap();
+        let params = match backward {
+            false => after(cursor),
+            true => before(cursor),
+        };
+        let keyset = keyset(order, params).unwrap();
+        past_sql(&keyset, keyset.cursor.as_ref().unwrap())
+    }
+
+    #[test]
+    fn paging_forward_puts_nulls_last() {
+        let value = [CursorValue::Int(2), CursorValue::Int(17)];
+        assert_eq!(
+            from_cursor(SortOrder::Asc, value.clone(), false),
+            r#""tasks"."priority" > 2 OR "tasks"."priority" IS NULL OR ("tasks"."priority" = 2 AND ("tasks"."id" > 17 OR "tasks"."id" IS NULL))"#
+        );
+        assert_eq!(
+            from_cursor(SortOrder::Desc, value, false),
+            r#""tasks"."priority" < 2 OR "tasks"."priority" IS NULL OR ("tasks"."priority" = 2 AND ("tasks"."id" < 17 OR "tasks"."id" IS NULL))"#
+        );
+        // Only the other nulls are left after a null.
+        assert_eq!(
+            from_cursor(
+                SortOrder::Asc,
+                [CursorValue::Null, CursorValue::Int(17)],
+                false
+            ),
+            r#""tasks"."priority" IS NULL AND ("tasks"."id" > 17 OR "tasks"."id" IS NULL)"#
+        );
+    }
+
+    #[test]
+    fn paging_backward_puts_nulls_first() {
+        let value = [CursorValue::Int(2), CursorValue::Int(17)];
+        assert_eq!(
+            from_cursor(SortOrder::Asc, value.clone(), true),
+            r#""tasks"."priority" < 2 OR ("tasks"."priority" = 2 AND "tasks"."id" < 17)"#
+        );
+        assert_eq!(
+            from_cursor(SortOrder::Desc, value, true),
+            r#""tasks"."priority" > 2 OR ("tasks"."priority" = 2 AND "tasks"."id" > 17)"#
+        );
+        // Every value comes before a null.
+        assert_eq!(
+            from_cursor(
+                SortOrder::Asc,
+                [CursorValue::Null, CursorValue::Int(17)],
+                true
+            ),
+            r#""tasks"."priority" IS NOT NULL OR ("tasks"."priority" IS NULL AND "tasks"."id" < 17)"#
+        );
+    }
+}
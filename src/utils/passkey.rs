@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use chrono::Duration;
+use http::StatusCode;
+use url::Url;
+use webauthn_rs::prelude::WebauthnError;
+use webauthn_rs::{Webauthn, WebauthnBuilder};
+
+use super::app_error::AppError;
+
+#[derive(Clone, Debug)]
+pub struct PasskeyConfig {
+    /// Domain passkeys are scoped to: the origin's host or one of its parent domains.
+    pub rp_id: String,
+    pub rp_name: String,
+    /// Origin of the frontend that runs the ceremonies.
+    pub rp_origin: String,
+    pub challenge_ttl: Duration,
+}
+
+/// The relying party every passkey ceremony runs against.
+#[derive(Clone, Debug)]
+pub struct Passkeys {
+    pub webauthn: Arc<Webauthn>,
+    pub challenge_ttl: Duration,
+}
+
+impl Passkeys {
+    pub fn new(config: PasskeyConfig) -> Result<Self, String> {
+        let origin = Url::parse(&config.rp_origin).map_err(|error| error.to_string())?;
+        let timeout = config
+            .challenge_ttl
+            .to_std()
+            .map_err(|error| error.to_string())?;
+        let webauthn = WebauthnBuilder::new(&config.rp_id, &origin)
+            .map_err(|error| error.to_string())?
+            .rp_name(&config.rp_name)
+            .timeout(timeout)
+            .build()
+            .map_err(|error| error.to_string())?;
+        Ok(Passkeys {
+            webauthn: Arc::new(webauthn),
+            challenge_ttl: config.challenge_ttl,
+        })
+    }
+}
+
+/// Failed ceremonies are the client's fault unless the library says otherwise.
+pub fn ceremony_error(error: WebauthnError) -> AppError {
+    match error {
+        WebauthnError::Configuration | WebauthnError::OpenSSLError(_) => {
+            AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+        }
+        _ => AppError::new(StatusCode::UNAUTHORIZED, "Passkey could not be verified."),
+    }
+}
@@ -1,55 +1,265 @@
+use std::sync::Arc;
+
+use argon2::{
+    password_hash::SaltString, Algorithm, Argon2, Params, PasswordHash, PasswordHasher as _,
+    PasswordVerifier, Version,
+};
 use axum::http::StatusCode;
-use bcrypt::{hash, verify, DEFAULT_COST};
-use regex::Regex;
-use validator::ValidationError;
+use rand::rngs::OsRng;
+use tokio::sync::{OnceCell, Semaphore};
 
 use super::app_error::AppError;
 
-pub fn validate_password(password: &str) -> Result<(), ValidationError> {
-    if password.len() < 8 {
-        return Err(
-            ValidationError::new("length").with_message(std::borrow::Cow::Borrowed(
-                "Password needs to be at least 8 characters long.",
-            )),
-        );
+#[derive(Clone, Debug)]
+pub struct PasswordHasherConfig {
+    /// Argon2id memory cost in KiB.
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    /// How many hashes may be computed at once.
+    pub workers: usize,
+    /// Cost of the bcrypt hashes left over from before Argon2id. `None` once none remain.
+    pub legacy_bcrypt_cost: Option<u32>,
+}
+
+/// Outcome of checking a password against a stored hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    /// Valid, but stored with a legacy algorithm or weaker parameters than configured.
+    ValidNeedsRehash,
+}
+
+impl Verification {
+    pub fn is_valid(self) -> bool {
+        self != Verification::Invalid
     }
+}
 
-    let special_char = Regex::new(r##"[!@#$%^&*(),.?:{}|<>]"##).unwrap();
-    if !special_char.is_match(password) {
-        return Err(
-            ValidationError::new("special").with_message(std::borrow::Cow::Borrowed(
-                "Password must contain at least one special character.",
-            )),
-        );
-    };
-
-    let numbers = Regex::new(r"\d").unwrap();
-    if !numbers.is_match(password) {
-        return Err(
-            ValidationError::new("number").with_message(std::borrow::Cow::Borrowed(
-                "Password must contain at least one number.",
-            )),
-        );
-    };
-
-    let uppercase = Regex::new(r"[A-Z]").unwrap();
-    if !uppercase.is_match(password) {
-        return Err(
-            ValidationError::new("case").with_message(std::borrow::Cow::Borrowed(
-                "Password must contain at least one uppercase character.",
-            )),
-        );
-    };
+/// Hashes new passwords with Argon2id and verifies both Argon2 and legacy bcrypt hashes,
+/// telling them apart by their PHC prefix. The work runs on the blocking thread pool, at
+/// most `workers` at a time, so it never stalls the async runtime.
+///
+/// While legacy hashes remain, every check computes one hash of each kind, padding with a
+/// throwaway hash of the other kind, so its duration doesn't give away which kind an account
+/// has or whether it exists.
+#[derive(Clone, Debug)]
+pub struct PasswordHasher {
+    params: Params,
+    legacy_bcrypt_cost: Option<u32>,
+    permits: Arc<Semaphore>,
+    dummy_hash: Arc<OnceCell<String>>,
+    dummy_bcrypt_hash: Arc<OnceCell<String>>,
+}
 
-    Ok(())
+impl PasswordHasher {
+    pub fn new(config: PasswordHasherConfig) -> Result<Self, String> {
+        let params = Params::new(
+            config.memory_kib,
+            config.iterations,
+            config.parallelism,
+            None,
+        )
+        .map_err(|error| error.to_string())?;
+        Ok(PasswordHasher {
+            params,
+            legacy_bcrypt_cost: config.legacy_bcrypt_cost,
+            permits: Arc::new(Semaphore::new(config.workers.max(1))),
+            dummy_hash: Arc::new(OnceCell::new()),
+            dummy_bcrypt_hash: Arc::new(OnceCell::new()),
+        })
+    }
+
+    pub async fn hash(&self, password: String) -> Result<String, AppError> {
+        let params = self.params.clone();
+        self.run(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            argon2(params)
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+                .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+        })
+        .await
+    }
+
+    pub async fn verify(&self, password: String, hash: String) -> Result<Verification, AppError> {
+        let is_bcrypt = hash.starts_with("$2");
+        let verification = self.verify_hash(password.clone(), hash).await?;
+        if self.legacy_bcrypt_cost.is_some() {
+            let padding = if is_bcrypt {
+                self.dummy_hash().await?
+            } else {
+                self.dummy_bcrypt_hash().await?
+            };
+            self.verify_hash(password, padding).await?;
+        }
+        Ok(verification)
+    }
+
+    /// Does the same work as `verify` without a real hash, so a sign-in for an unknown
+    /// account or one without a password takes as long as a wrong password.
+    pub async fn verify_dummy(&self, password: String) -> Result<Verification, AppError> {
+        let dummy_hash = self.dummy_hash().await?;
+        self.verify_hash(password.clone(), dummy_hash).await?;
+        if self.legacy_bcrypt_cost.is_some() {
+            let dummy_bcrypt_hash = self.dummy_bcrypt_hash().await?;
+            self.verify_hash(password, dummy_bcrypt_hash).await?;
+        }
+        Ok(Verification::Invalid)
+    }
+
+    async fn verify_hash(&self, password: String, hash: String) -> Result<Verification, AppError> {
+        let params = self.params.clone();
+        self.run(move || {
+            if hash.starts_with("$argon2") {
+                verify_argon2(&params, &password, &hash)
+            } else if hash.starts_with("$2") {
+                let valid = bcrypt::verify(password, &hash).map_err(|err| {
+                    AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+                })?;
+                Ok(if valid {
+                    Verification::ValidNeedsRehash
+                } else {
+                    Verification::Invalid
+                })
+            } else {
+                Err(AppError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Unrecognised password hash format.",
+                ))
+            }
+        })
+        .await
+    }
+
+    async fn dummy_hash(&self) -> Result<String, AppError> {
+        self.dummy_hash
+            .get_or_try_init(|| self.hash("not a real password".to_owned()))
+            .await
+            .cloned()
+    }
+
+    async fn dummy_bcrypt_hash(&self) -> Result<String, AppError> {
+        let cost = self.legacy_bcrypt_cost.unwrap_or(bcrypt::DEFAULT_COST);
+        self.dummy_bcrypt_hash
+            .get_or_try_init(|| {
+                self.run(move || {
+                    bcrypt::hash("not a real password", cost).map_err(|err| {
+                        AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+                    })
+                })
+            })
+            .await
+            .cloned()
+    }
+
+    async fn run<T, F>(&self, work: F) -> Result<T, AppError>
+    where
+        F: FnOnce() -> Result<T, AppError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        tokio::task::spawn_blocking(work)
+            .await
+            .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+    }
 }
 
-pub fn hash_password(password: String) -> Result<String, AppError> {
-    hash(password, DEFAULT_COST)
-        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+fn argon2(params: Params) -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
 }
 
-pub fn verify_password(password: String, hash: &str) -> Result<bool, AppError> {
-    verify(password, hash)
-        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+fn verify_argon2(params: &Params, password: &str, hash: &str) -> Result<Verification, AppError> {
+    let parsed = PasswordHash::new(hash)
+        .map_err(|err| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if argon2(params.clone())
+        .verify_password(password.as_bytes(), &parsed)
+        .is_err()
+    {
+        return Ok(Verification::Invalid);
+    }
+    let current = parsed.algorithm.as_str() == Algorithm::Argon2id.as_str()
+        && Params::try_from(&parsed).is_ok_and(|stored| {
+            stored.m_cost() >= params.m_cost()
+                && stored.t_cost() >= params.t_cost()
+                && stored.p_cost() >= params.p_cost()
+        });
+    Ok(if current {
+        Verification::Valid
+    } else {
+        Verification::ValidNeedsRehash
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    const LEGACY_COST: u32 = 10;
+
+    fn hasher(legacy_bcrypt_cost: Option<u32>) -> PasswordHasher {
+        PasswordHasher::new(PasswordHasherConfig {
+            memory_kib: 1024,
+            iterations: 1,
+            parallelism: 1,
+            workers: 1,
+            legacy_bcrypt_cost,
+        })
+        .unwrap()
+    }
+
+    async fn timed<T>(work: impl std::future::Future<Output = T>) -> (T, Duration) {
+        let start = Instant::now();
+        let result = work.await;
+        (result, start.elapsed())
+    }
+
+    #[tokio::test]
+    async fn verifies_both_kinds_of_hash() {
+        let hasher = hasher(Some(4));
+        let argon2 = hasher.hash("Correct-Horse-9".to_owned()).await.unwrap();
+        let bcrypt = bcrypt::hash("Correct-Horse-9", 4).unwrap();
+
+        for (hash, valid) in [
+            (argon2, Verification::Valid),
+            (bcrypt, Verification::ValidNeedsRehash),
+        ] {
+            let right = hasher.verify("Correct-Horse-9".to_owned(), hash.clone());
+            assert_eq!(right.await.unwrap(), valid);
+            let wrong = hasher.verify("Wrong-Horse-9".to_owned(), hash);
+            assert_eq!(wrong.await.unwrap(), Verification::Invalid);
+        }
+        let dummy = hasher.verify_dummy("Correct-Horse-9".to_owned()).await;
+        assert_eq!(dummy.unwrap(), Verification::Invalid);
+    }
+
+    #[tokio::test]
+    async fn unknown_accounts_and_argon2_accounts_pay_for_bcrypt_too() {
+        let hasher = hasher(Some(LEGACY_COST));
+        let argon2 = hasher.hash("Correct-Horse-9".to_owned()).await.unwrap();
+        let bcrypt = bcrypt::hash("Correct-Horse-9", LEGACY_COST).unwrap();
+        // Warm up the throwaway hashes, which are made on first use.
+        hasher.verify_dummy(String::new()).await.unwrap();
+
+        let (_, bcrypt_time) = timed(hasher.verify("Wrong-Horse-9".to_owned(), bcrypt)).await;
+        let (_, argon2_time) = timed(hasher.verify("Wrong-Horse-9".to_owned(), argon2)).await;
+        let (_, unknown_time) = timed(hasher.verify_dummy("Wrong-Horse-9".to_owned())).await;
+        // Argon2 at these settings is far cheaper than bcrypt, so without the padding both
+        // would finish in a fraction of the bcrypt time.
+        assert!(
+            argon2_time * 2 > bcrypt_time,
+            "{argon2_time:?} vs {bcrypt_time:?}"
+        );
+        assert!(
+            unknown_time * 2 > bcrypt_time,
+            "{unknown_time:?} vs {bcrypt_time:?}"
+        );
+    }
 }
@@ -0,0 +1,360 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use http::StatusCode;
+use sha1::{Digest, Sha1};
+use tracing::warn;
+
+use super::app_error::AppError;
+
+/// Length of the hash prefix breached passwords are bucketed by, as in the k-anonymity range API.
+const PREFIX_LENGTH: usize = 5;
+/// Usernames shorter than this are too likely to appear in a password by chance.
+const MIN_USERNAME_MATCH: usize = 3;
+
+#[derive(Clone, Debug)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_special: bool,
+    /// Minimum of `estimate_entropy_bits`; 0 turns the check off.
+    pub min_entropy_bits: f64,
+    pub reject_username: bool,
+    pub breached: Option<Arc<BreachedPasswords>>,
+}
+
+/// Every rule a password broke.
+#[derive(Debug)]
+pub struct PasswordPolicyError(pub Vec<String>);
+
+impl fmt::Display for PasswordPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.join(" "))
+    }
+}
+
+impl From<PasswordPolicyError> for AppError {
+    fn from(error: PasswordPolicyError) -> Self {
+        AppError::new(StatusCode::BAD_REQUEST, error.to_string())
+    }
+}
+
+impl PasswordPolicy {
+    /// Checks every rule rather than stopping at the first failure.
+    pub fn check(&self, password: &str, username: Option<&str>) -> Result<(), PasswordPolicyError> {
+        let mut errors = Vec::new();
+        let length = password.chars().count();
+        if length < self.min_length {
+            errors.push(format!(
+                "Password needs to be at least {} characters long.",
+                self.min_length
+            ));
+        }
+        if length > self.max_length {
+            errors.push(format!(
+                "Password can be at most {} characters long.",
+                self.max_length
+            ));
+        }
+        if self.require_lowercase && !password.chars().any(|c| c.is_lowercase()) {
+            errors.push("Password must contain at least one lowercase character.".to_owned());
+        }
+        if self.require_uppercase && !password.chars().any(|c| c.is_uppercase()) {
+            errors.push("Password must contain at least one uppercase character.".to_owned());
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            errors.push("Password must contain at least one number.".to_owned());
+        }
+        if self.require_special && !password.chars().any(is_special) {
+            errors.push("Password must contain at least one special character.".to_owned());
+        }
+        if estimate_entropy_bits(password) < self.min_entropy_bits {
+            errors.push("Password is too easy to guess.".to_owned());
+        }
+        if self.reject_username
+            && username.is_some_and(|username| contains_username(password, username))
+        {
+            errors.push("Password must not contain your username.".to_owned());
+        }
+        if self
+            .breached
+            .as_ref()
+            .is_some_and(|breached| breached.contains(password))
+        {
+            errors.push("Password has appeared in a data breach.".to_owned());
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(PasswordPolicyError(errors))
+        }
+    }
+}
+
+/// Rough strength estimate: log2 of the character pool the password draws from, times the
+/// number of distinct characters, so repetition doesn't inflate the score.
+pub fn estimate_entropy_bits(password: &str) -> f64 {
+    let mut pool = 0;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if password
+        .chars()
+        .any(|c| c.is_ascii_punctuation() || c == ' ')
+    {
+        pool += 33;
+    }
+    if !password.is_ascii() {
+        pool += 100;
+    }
+    if pool == 0 {
+        return 0.0;
+    }
+    let distinct = password.chars().collect::<HashSet<_>>().len();
+    distinct as f64 * f64::from(pool).log2()
+}
+
+fn is_special(c: char) -> bool {
+    !c.is_alphanumeric() && !c.is_whitespace()
+}
+
+/// Matches the whole username, and for email addresses the part before the `@`.
+fn contains_username(password: &str, username: &str) -> bool {
+    let password = password.to_lowercase();
+    let username = username.to_lowercase();
+    let local_part = username.split('@').next().unwrap_or_default();
+    let found = [username.as_str(), local_part]
+        .into_iter()
+        .filter(|candidate| candidate.chars().count() >= MIN_USERNAME_MATCH)
+        .any(|candidate| password.contains(candidate));
+    found
+}
+
+/// SHA-1 hashes of known-breached passwords, kept on disk in the layout of the k-anonymity
+/// range API: one `<PREFIX>.txt` file per hash prefix, each line holding the rest of a hash,
+/// optionally followed by `:count`. A lookup only reads the bucket for the password's prefix,
+/// a few kilobytes, so the list never has to fit in memory.
+#[derive(Debug)]
+pub struct BreachedPasswords {
+    dir: PathBuf,
+}
+
+impl BreachedPasswords {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        if !fs::metadata(dir)?.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", dir.display()),
+            ));
+        }
+        Ok(BreachedPasswords {
+            dir: dir.to_owned(),
+        })
+    }
+
+    /// A bucket that can't be read counts as not breached, so a damaged list doesn't block
+    /// sign-ups.
+    pub fn contains(&self, password: &str) -> bool {
+        let hash = Sha1::digest(password.as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<String>();
+        let (prefix, suffix) = hash.split_at(PREFIX_LENGTH);
+        self.bucket_contains(prefix, suffix)
+            .unwrap_or_else(|error| {
+                warn!(?error, prefix, "could not read breached password bucket");
+                false
+            })
+    }
+
+    fn bucket_contains(&self, prefix: &str, suffix: &str) -> io::Result<bool> {
+        let bucket = match File::open(self.dir.join(format!("{prefix}.txt"))) {
+            Ok(bucket) => bucket,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(error) => return Err(error),
+        };
+        for line in BufReader::new(bucket).lines() {
+            let line = line?;
+            let entry = line.split(':').next().unwrap_or_default().trim();
+            if entry.eq_ignore_ascii_case(suffix) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            max_length: 64,
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_special: true,
+            min_entropy_bits: 36.0,
+            reject_username: true,
+            breached: None,
+        }
+    }
+
+    fn failures(policy: &PasswordPolicy, password: &str, username: Option<&str>) -> Vec<String> {
+        match policy.check(password, username) {
+            Ok(()) => Vec::new(),
+            Err(PasswordPolicyError(errors)) => errors,
+        }
+    }
+
+    /// A breached list holding `passwords`, in a fresh directory.
+    fn breached_list(passwords: &[&str]) -> BreachedPasswords {
+        let dir = std::env::temp_dir().join(format!("breached-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        for password in passwords {
+            let hash = Sha1::digest(password.as_bytes())
+                .iter()
+                .map(|byte| format!("{byte:02X}"))
+                .collect::<String>();
+            let (prefix, suffix) = hash.split_at(PREFIX_LENGTH);
+            let bucket = format!("{}:3\n{}:42\n", "0".repeat(35), suffix.to_lowercase());
+            fs::write(dir.join(format!("{prefix}.txt")), bucket).unwrap();
+        }
+        BreachedPasswords::open(&dir).unwrap()
+    }
+
+    #[test]
+    fn accepts_a_password_that_meets_every_rule() {
+        assert!(failures(&policy(), "Tr0ub4dor&3x", Some("alice@example.com")).is_empty());
+    }
+
+    #[test]
+    fn reports_every_failed_rule_at_once() {
+        let policy = PasswordPolicy {
+            breached: Some(Arc::new(breached_list(&["alice"]))),
+            ..policy()
+        };
+        assert_eq!(
+            failures(&policy, "alice", Some("alice@example.com")),
+            [
+                "Password needs to be at least 8 characters long.",
+                "Password must contain at least one uppercase character.",
+                "Password must contain at least one number.",
+                "Password must contain at least one special character.",
+                "Password is too easy to guess.",
+                "Password must not contain your username.",
+                "Password has appeared in a data breach.",
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_each_character_class() {
+        let cases = [
+            (
+                "QWERTY12!@#$",
+                "Password must contain at least one lowercase character.",
+            ),
+            (
+                "qwerty12!@#$",
+                "Password must contain at least one uppercase character.",
+            ),
+            ("Qwerty!@#$%^", "Password must contain at least one number."),
+            (
+                "Qwerty12345x",
+                "Password must contain at least one special character.",
+            ),
+        ];
+        for (password, expected) in cases {
+            assert_eq!(
+                failures(&policy(), password, None),
+                [expected],
+                "{password}"
+            );
+        }
+    }
+
+    #[test]
+    fn limits_the_length() {
+        let password = "Tr0ub4dor&3x".repeat(6);
+        assert_eq!(
+            failures(&policy(), &password, None),
+            ["Password can be at most 64 characters long."]
+        );
+    }
+
+    #[test]
+    fn rejects_the_username_and_its_local_part() {
+        let rejected = ["Password must not contain your username."];
+        assert_eq!(
+            failures(&policy(), "1!Alice@Example.com", Some("alice@example.com")),
+            rejected
+        );
+        assert_eq!(
+            failures(&policy(), "My-ALICE#2024", Some("alice@example.com")),
+            rejected
+        );
+        // Local parts this short are too likely to turn up by chance.
+        assert!(failures(&policy(), "Salad#2024!", Some("al@example.com")).is_empty());
+
+        let policy = PasswordPolicy {
+            reject_username: false,
+            ..policy()
+        };
+        assert!(failures(&policy, "My-ALICE#2024", Some("alice@example.com")).is_empty());
+    }
+
+    #[test]
+    fn estimates_entropy_from_the_pool_and_distinct_characters() {
+        assert_eq!(estimate_entropy_bits(""), 0.0);
+        assert_eq!(estimate_entropy_bits("aaaa"), 26_f64.log2());
+        assert_eq!(estimate_entropy_bits("abcd"), 4.0 * 26_f64.log2());
+        assert_eq!(estimate_entropy_bits("aB3!"), 4.0 * 95_f64.log2());
+
+        // Every class is there, but repetition leaves too few distinct characters.
+        assert_eq!(
+            failures(&policy(), "aaaaaaaaA1!", None),
+            ["Password is too easy to guess."]
+        );
+    }
+
+    #[test]
+    fn looks_up_breached_passwords_by_hash_prefix() {
+        let breached = breached_list(&["Password1!"]);
+        assert!(breached.contains("Password1!"));
+        assert!(!breached.contains("Password2!"));
+
+        let policy = PasswordPolicy {
+            breached: Some(Arc::new(breached)),
+            ..policy()
+        };
+        assert_eq!(
+            failures(&policy, "Password1!", None),
+            ["Password has appeared in a data breach."]
+        );
+    }
+
+    #[test]
+    fn only_opens_a_directory() {
+        let file = std::env::temp_dir().join(format!("breached-{}", uuid::Uuid::new_v4()));
+        fs::write(&file, "").unwrap();
+        assert!(BreachedPasswords::open(&file).is_err());
+    }
+}
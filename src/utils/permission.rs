@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+
+use crate::database::sea_orm_active_enums::{UserRole, WorkspaceRole};
+
+/// Something a route can require of the caller.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    ReadTasks,
+    WriteTasks,
+    ViewUsers,
+    ManageUsers,
+    /// Creating workspaces and running them: settings, members and invites.
+    ManageWorkspaces,
+}
+
+impl Permission {
+    pub fn granted_to(self, role: UserRole) -> bool {
+        match role {
+            UserRole::Admin => true,
+            UserRole::Member => matches!(
+                self,
+                Permission::ReadTasks
+                    | Permission::WriteTasks
+                    | Permission::ViewUsers
+                    | Permission::ManageWorkspaces
+            ),
+            UserRole::ReadOnly => matches!(self, Permission::ReadTasks | Permission::ViewUsers),
+        }
+    }
+
+    /// The scope a personal access token needs to exercise this permission. `None` means
+    /// access tokens can never be used for it.
+    pub fn scope(self) -> Option<Scope> {
+        match self {
+            Permission::ReadTasks => Some(Scope::TasksRead),
+            Permission::WriteTasks => Some(Scope::TasksWrite),
+            Permission::ViewUsers | Permission::ManageUsers | Permission::ManageWorkspaces => None,
+        }
+    }
+}
+
+/// Something a workspace route can require of the caller's membership, on top of the
+/// global `Permission` the route itself requires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkspacePermission {
+    ReadTasks,
+    WriteTasks,
+    /// Changing how tasks are organized, such as the priority levels.
+    ConfigureTasks,
+    ManageMembers,
+    ManageWorkspace,
+}
+
+impl WorkspacePermission {
+    pub fn granted_to(self, role: WorkspaceRole) -> bool {
+        match role {
+            WorkspaceRole::Owner => true,
+            WorkspaceRole::Admin => self != WorkspacePermission::ManageWorkspace,
+            WorkspaceRole::Member => matches!(
+                self,
+                WorkspacePermission::ReadTasks | WorkspacePermission::WriteTasks
+            ),
+            WorkspaceRole::Guest => self == WorkspacePermission::ReadTasks,
+        }
+    }
+}
+
+/// What a personal access token may be used for, on top of its owner's role.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "tasks:read")]
+    TasksRead,
+    #[serde(rename = "tasks:write")]
+    TasksWrite,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::TasksRead => "tasks:read",
+            Scope::TasksWrite => "tasks:write",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "tasks:read" => Some(Scope::TasksRead),
+            "tasks:write" => Some(Scope::TasksWrite),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use chrono::{Duration, Utc};
+use sea_orm::{
+    prelude::DateTimeWithTimeZone, sea_query::Expr, ActiveModelTrait, ColumnTrait, ConnectionTrait,
+    DatabaseConnection, DbErr, EntityTrait, IntoActiveModel, PaginatorTrait, QueryFilter,
+    QueryOrder, Set, TransactionTrait,
+};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{info, warn};
+
+use super::lockout::account_key;
+use crate::database::{
+    login_attempts,
+    prelude::{LoginAttempts, Tasks, Users, WorkspaceMemberships, Workspaces},
+    sea_orm_active_enums::WorkspaceRole,
+    tasks, users, workspace_memberships,
+};
+
+/// Runs `purge_deleted_accounts` every `every`, for as long as the process lives.
+pub fn spawn_account_purge(database: DatabaseConnection, grace_period: Duration, every: Duration) {
+    let period = every
+        .to_std()
+        .unwrap_or(std::time::Duration::from_secs(60 * 60));
+    tokio::spawn(async move {
+        let mut ticks = interval(period);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            if let Err(error) = purge_deleted_accounts(&database, grace_period).await {
+                warn!(?error, "could not purge deleted accounts");
+            }
+        }
+    });
+}
+
+/// Permanently removes accounts soft-deleted more than `grace_period` ago. Workspaces they
+/// were the only member of go with them, tasks included. Shared workspaces get a new owner
+/// if needed and keep the tasks the account created, which no longer name a creator.
+/// Everything else that belongs to an account goes with it through `ON DELETE CASCADE`.
+pub async fn purge_deleted_accounts(
+    database: &DatabaseConnection,
+    grace_period: Duration,
+) -> Result<usize, DbErr> {
+    let cutoff = DateTimeWithTimeZone::from(Utc::now() - grace_period);
+    let expired = Users::find()
+        .filter(users::Column::DeletedAt.lt(cutoff))
+        .all(database)
+        .await?;
+    for user in &expired {
+        let txn = database.begin().await?;
+        release_workspaces(&txn, user.id).await?;
+        // The foreign key would do this too; doing it here keeps it from depending on that.
+        Tasks::update_many()
+            .col_expr(tasks::Column::UserId, Expr::value(Option::<i32>::None))
+            .filter(tasks::Column::UserId.eq(user.id))
+            .exec(&txn)
+            .await?;
+        LoginAttempts::delete_many()
+            .filter(login_attempts::Column::Key.eq(account_key(&user.username)))
+            .exec(&txn)
+            .await?;
+        Users::delete_by_id(user.id).exec(&txn).await?;
+        txn.commit().await?;
+        info!(user_id = user.id, "account purged");
+    }
+    Ok(expired.len())
+}
+
+async fn release_workspaces(txn: &impl ConnectionTrait, user_id: i32) -> Result<(), DbErr> {
+    let memberships = WorkspaceMemberships::find()
+        .filter(workspace_memberships::Column::UserId.eq(user_id))
+        .all(txn)
+        .await?;
+    for membership in memberships {
+        let others = WorkspaceMemberships::find()
+            .filter(workspace_memberships::Column::WorkspaceId.eq(membership.workspace_id))
+            .filter(workspace_memberships::Column::UserId.ne(user_id));
+        if others.clone().count(txn).await? == 0 {
+            Workspaces::delete_by_id(membership.workspace_id)
+                .exec(txn)
+                .await?;
+            continue;
+        }
+        if membership.role != WorkspaceRole::Owner {
+            continue;
+        }
+        let other_owners = others
+            .clone()
+            .filter(workspace_memberships::Column::Role.eq(WorkspaceRole::Owner))
+            .count(txn)
+            .await?;
+        if other_owners > 0 {
+            continue;
+        }
+        // Roles sort owner, admin, member, guest: the longest-standing admin takes over.
+        if let Some(successor) = others
+            .order_by_asc(workspace_memberships::Column::Role)
+            .order_by_asc(workspace_memberships::Column::CreatedAt)
+            .one(txn)
+            .await?
+        {
+            let mut successor = successor.into_active_model();
+            successor.role = Set(WorkspaceRole::Owner);
+            successor.update(txn).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::{json_request, TestApp};
+
+    async fn user_id(database: &DatabaseConnection, username: &str) -> i32 {
+        Users::find()
+            .filter(users::Column::Username.eq(username))
+            .one(database)
+            .await
+            .unwrap()
+            .unwrap()
+            .id
+    }
+
+    async fn create_task(app: &TestApp, bearer: &str, uri: &str, title: &str) -> i32 {
+        let response = app
+            .call(json_request(
+                Method::POST,
+                uri,
+                json!({ "title": title }),
+                &[("authorization", bearer)],
+            ))
+            .await;
+        assert_eq!(response.status, StatusCode::CREATED, "{}", response.text);
+        response.body["id"].as_i64().unwrap() as i32
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn purging_keeps_shared_tasks_without_their_creator() {
+        let app = TestApp::start(|_| {}).await;
+        let ada = format!("Bearer {}", app.sign_up("ada@example.com").await);
+        let grace = format!("Bearer {}", app.sign_up("grace@example.com").await);
+        let ada_id = user_id(&app.database, "ada@example.com").await;
+        let grace_id = user_id(&app.database, "grace@example.com").await;
+        let personal = create_task(&app, &ada, "/tasks", "Book the dentist").await;
+        let personal_workspace = Tasks::find_by_id(personal)
+            .one(&app.database)
+            .await
+            .unwrap()
+            .unwrap()
+            .workspace_id;
+
+        let response = app
+            .call(json_request(
+                Method::POST,
+                "/workspaces",
+                json!({ "name": "Team" }),
+                &[("authorization", &ada)],
+            ))
+            .await;
+        assert_eq!(response.status, StatusCode::CREATED, "{}", response.text);
+        let team = response.body["id"].as_i64().unwrap() as i32;
+        workspace_memberships::ActiveModel {
+            workspace_id: Set(team),
+            user_id: Set(grace_id),
+            role: Set(WorkspaceRole::Member),
+            created_at: Set(Utc::now().into()),
+        }
+        .insert(&app.database)
+        .await
+        .unwrap();
+        let team_tasks = format!("/workspaces/{team}/tasks");
+        let by_ada = create_task(&app, &ada, &team_tasks, "Draft the roadmap").await;
+        let by_grace = create_task(&app, &grace, &team_tasks, "Review the roadmap").await;
+
+        let response = app
+            .call(json_request(
+                Method::DELETE,
+                "/me",
+                json!({ "password": "Correct-Horse-9" }),
+                &[("authorization", &ada)],
+            ))
+            .await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.text);
+        let mut user = Users::find_by_id(ada_id)
+            .one(&app.database)
+            .await
+            .unwrap()
+            .unwrap()
+            .into_active_model();
+        user.deleted_at = Set(Some((Utc::now() - Duration::days(31)).into()));
+        user.update(&app.database).await.unwrap();
+        assert_eq!(
+            purge_deleted_accounts(&app.database, Duration::days(30))
+                .await
+                .unwrap(),
+            1
+        );
+
+        assert!(Users::find_by_id(ada_id)
+            .one(&app.database)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(Workspaces::find_by_id(personal_workspace)
+            .one(&app.database)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(Tasks::find_by_id(personal)
+            .one(&app.database)
+            .await
+            .unwrap()
+            .is_none());
+
+        let shared = Tasks::find()
+            .filter(tasks::Column::WorkspaceId.eq(team))
+            .order_by_asc(tasks::Column::Id)
+            .all(&app.database)
+            .await
+            .unwrap();
+        let survivors: Vec<_> = shared
+            .iter()
+            .map(|task| (task.id, task.title.as_str(), task.user_id))
+            .collect();
+        assert_eq!(
+            survivors,
+            [
+                (by_ada, "Draft the roadmap", None),
+                (by_grace, "Review the roadmap", Some(grace_id)),
+            ]
+        );
+        let members = WorkspaceMemberships::find()
+            .filter(workspace_memberships::Column::WorkspaceId.eq(team))
+            .all(&app.database)
+            .await
+            .unwrap();
+        assert_eq!(members.len(), 1);
+        assert_eq!(
+            (members[0].user_id, members[0].role),
+            (grace_id, WorkspaceRole::Owner)
+        );
+        assert!(Workspaces::find_by_id(team)
+            .one(&app.database)
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
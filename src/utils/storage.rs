@@ -0,0 +1,117 @@
+use std::{collections::HashMap, fmt::Debug, io::ErrorKind, path::PathBuf, sync::Arc};
+
+use axum::async_trait;
+use http::StatusCode;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use super::app_error::AppError;
+
+#[derive(Clone, Debug)]
+pub enum StorageBackend {
+    /// Objects are files below this directory, one per key.
+    Filesystem(PathBuf),
+    /// Objects live in process memory and are lost on restart.
+    Memory,
+}
+
+/// A flat key-value store for uploaded files. Keys are `/`-separated paths built by the
+/// application, never taken verbatim from a request.
+#[async_trait]
+pub trait Storage: Debug + Send + Sync {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), AppError>;
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, AppError>;
+    /// Deleting a key that doesn't exist is not an error.
+    async fn delete(&self, key: &str) -> Result<(), AppError>;
+}
+
+pub fn build_storage(backend: StorageBackend) -> Arc<dyn Storage> {
+    match backend {
+        StorageBackend::Filesystem(root) => Arc::new(FileStorage { root }),
+        StorageBackend::Memory => Arc::new(MemoryStorage::default()),
+    }
+}
+
+#[derive(Debug)]
+pub struct FileStorage {
+    root: PathBuf,
+}
+
+impl FileStorage {
+    fn path(&self, key: &str) -> Result<PathBuf, AppError> {
+        if key
+            .split('/')
+            .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+        {
+            return Err(AppError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Invalid storage key {key}"),
+            ));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl Storage for FileStorage {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), AppError> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(|error| {
+                AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+            })?;
+        }
+        // Written aside and renamed into place, so readers never see half a file.
+        let partial = path.with_extension(format!("{}.partial", Uuid::new_v4()));
+        tokio::fs::write(&partial, bytes)
+            .await
+            .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+        tokio::fs::rename(&partial, &path)
+            .await
+            .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, AppError> {
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(AppError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                error.to_string(),
+            )),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(AppError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                error.to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// For development and single-process setups that don't need uploads to survive a restart.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    objects: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), AppError> {
+        self.objects.lock().await.insert(key.to_owned(), bytes);
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, AppError> {
+        Ok(self.objects.lock().await.get(key).cloned())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        self.objects.lock().await.remove(key);
+        Ok(())
+    }
+}
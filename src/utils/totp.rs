@@ -0,0 +1,86 @@
+use chrono::Duration;
+use http::StatusCode;
+use rand::{rngs::OsRng, Rng, RngCore};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use super::app_error::AppError;
+
+#[derive(Clone, Debug)]
+pub struct TotpConfig {
+    /// Shown next to the account in authenticator apps.
+    pub issuer: String,
+    /// How long the password step of a two-step login stays valid.
+    pub challenge_ttl: Duration,
+}
+
+const DIGITS: usize = 6;
+const STEP_SECONDS: u64 = 30;
+/// Codes from one step either side of now are accepted to allow for clock drift.
+const SKEW_STEPS: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+/// Unambiguous characters only, so codes survive being read off paper.
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// A new base32-encoded 160-bit secret.
+pub fn generate_secret() -> String {
+    let mut bytes = vec![0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+    match Secret::Raw(bytes).to_encoded() {
+        Secret::Encoded(secret) => secret,
+        Secret::Raw(_) => unreachable!(),
+    }
+}
+
+/// The `otpauth://` URI authenticator apps enroll from.
+pub fn provisioning_uri(secret: &str, issuer: &str, account: &str) -> Result<String, AppError> {
+    Ok(totp(secret, Some(issuer), account)?.get_url())
+}
+
+/// Checks `code` against the steps around `now` and returns the step it matched, so callers
+/// can refuse to accept the same code twice.
+pub fn verify_code(secret: &str, code: &str, now: i64) -> Result<Option<i64>, AppError> {
+    let totp = totp(secret, None, "")?;
+    let code = code.trim();
+    let current = now / STEP_SECONDS as i64;
+    Ok((current - SKEW_STEPS..=current + SKEW_STEPS)
+        .find(|step| totp.generate((*step as u64) * STEP_SECONDS) == code))
+}
+
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let chars: Vec<u8> = (0..16)
+                .map(|_| RECOVERY_CODE_ALPHABET[OsRng.gen_range(0..RECOVERY_CODE_ALPHABET.len())])
+                .collect();
+            chars
+                .chunks(4)
+                .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect()
+}
+
+/// Recovery codes are compared without dashes, whitespace or case.
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn totp(secret: &str, issuer: Option<&str>, account: &str) -> Result<TOTP, AppError> {
+    let secret = Secret::Encoded(secret.to_owned())
+        .to_bytes()
+        .map_err(|error| AppError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+    TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        0,
+        STEP_SECONDS,
+        secret,
+        issuer.map(str::to_owned),
+        account.to_owned(),
+    )
+    .map_err(|error| AppError::new(StatusCode::BAD_REQUEST, error.to_string()))
+}